@@ -130,11 +130,8 @@ impl BlockSupport for FileSystem {
     }
 
     fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
-        let dev = self
-            .device
-            .as_mut()
-            .ok_or_else(|| FileSystemError::DeviceNotSet())?;
-        set_bitmapbit(&self.superblock, dev, i, false)?;
+        let sb = self.superblock;
+        set_bitmapbit(self, &sb, i, false)?;
         Ok(())
     }
 
@@ -149,44 +146,15 @@ impl BlockSupport for FileSystem {
     }
 
     fn b_alloc(&mut self) -> Result<u64, Self::Error> {
-        let nbitmapblocks = get_nbitmapblocks(&self.superblock);
-        let mut bmstart_index = self.superblock.bmapstart; // get the index
-        let mut block; // get the first block
-        let mut byte_array; // create an empty data buffer
-        let mut byteindex; //block index
-
-        for blockindex in 0..nbitmapblocks {
-            block = self.b_get(bmstart_index + blockindex)?; //next block
-                                                             //byte_array = block.contents_as_ref(); //get the block's array
-            byte_array = block.contents_as_ref();
-            byteindex = get_bytesarray_free_index(byte_array);
-            if byteindex.is_err() {
-                // HERE WE ARE LOOKING FOR THE NEXT BLOCK
-                bmstart_index += 1; //next block index
-            } else {
-                // The current bm_block has a free spot
-                let byteindex = byteindex.unwrap(); //get the index of the byte that has a free spot
-                let byte = byte_array.get(usize::from(byteindex)).unwrap();
-                let bitindex = 8 - 1 - byte.trailing_ones(); //moves the 1 to the correct position
-                let mutator = 0b00000001u8 << byte.trailing_ones(); //moves the 1 to the correct position
-
-                let to_write_byte = &[(*byte | mutator)];
-
-                block.write_data(to_write_byte, byteindex as u64)?;
-
-                let byteindex: u64 = u64::from(byteindex);
-                let datablockindex = blockindex * self.superblock.block_size * 8
-                    + (byteindex) * 8
-                    + u64::from(7 - bitindex);
-
-                if datablockindex < self.superblock.ndatablocks {
-                    self.b_zero(datablockindex)?;
-                    self.b_put(&block)?;
-                    return Ok(datablockindex);
-                }
+        let sb = self.superblock;
+        match find_free_data_index(self, &sb, 0, sb.ndatablocks)? {
+            Some(datablockindex) => {
+                self.b_zero(datablockindex)?;
+                set_bitmapbit(self, &sb, datablockindex, true)?;
+                Ok(datablockindex)
             }
+            None => Err(FileSystemError::AllocationError()),
         }
-        Err(FileSystemError::AllocationError())
     }
 
     fn sup_get(&self) -> Result<SuperBlock, Self::Error> {
@@ -0,0 +1,358 @@
+//! In-memory copy of the block allocation bitmap
+//!
+//! Instead of reading and rewriting a bitmap block through the device on every `b_alloc` and
+//! `b_free`, a mounted `FileSystem` keeps the whole bitmap region in an `AllocBitmap`.
+//! Allocation decisions are made on this copy only. The bitmap blocks whose bits changed are
+//! remembered as dirty and are written back according to the `BitmapWritePolicy` of the mount:
+//! right away, or only when the file system is synced or unmounted.
+//!
+//! Free blocks are picked according to the `AllocatorStrategy` of the mount. The buddy allocator
+//! is rebuilt from the bitmap at mount time, and kept up to date with every allocation and free.
+//!
+//! Writing dirty bitmap blocks back is left to the file system, which sends them through its
+//! buffer cache (see `take_dirty` and `fill_block`).
+//!
+//! Blocks of the bitmap region that are read or written with `b_get`/`b_put` stay consistent with
+//! the in-memory copy: reads see the pending changes and writes replace them.
+
+use std::collections::BTreeSet;
+
+use cplfs_api::controller::Device;
+use cplfs_api::types::{Block, SuperBlock};
+
+use crate::bitmap::Bitmap;
+use crate::buddy::BuddyAllocator;
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::{get_bit_blockindex, get_nbitmapblocks, read_block};
+use crate::options::{AllocatorStrategy, BitmapWritePolicy, MountOptions};
+
+#[derive(Debug, Clone)]
+/// The allocation bitmap of a mounted file system, one bit per data block
+pub struct AllocBitmap {
+    /// Allocation state of every data block
+    bits: Bitmap,
+    /// Number of bits that are not set
+    nfree: u64,
+    /// Block numbers of the bitmap blocks that differ from their copy on disk
+    dirty: BTreeSet<u64>,
+    /// When dirty bitmap blocks are written back
+    policy: BitmapWritePolicy,
+    /// Index of the free blocks, if the buddy allocator strategy is used
+    buddy: Option<BuddyAllocator>,
+}
+
+impl AllocBitmap {
+    /// Reads the bitmap region of the file system described by `sb` from `dev`
+    pub fn load(
+        dev: &Device,
+        sb: &SuperBlock,
+        options: &MountOptions,
+    ) -> Result<AllocBitmap, FileSystemError> {
+        let mut bits = Bitmap::new(sb.ndatablocks);
+        for k in 0..get_nbitmapblocks(sb) {
+            let block = read_block(dev, sb.bmapstart + k)?;
+            bits.read_bytes_at(k * sb.block_size * 8, block.contents_as_ref());
+        }
+        let buddy = match options.allocator {
+            AllocatorStrategy::FirstFit => None,
+            AllocatorStrategy::Buddy => Some(BuddyAllocator::from_bitmap(&bits)),
+        };
+        Ok(AllocBitmap {
+            nfree: bits.len() - bits.count_ones(),
+            bits,
+            dirty: BTreeSet::new(),
+            policy: options.bitmap_policy,
+            buddy,
+        })
+    }
+
+    /// The write policy this bitmap was loaded with
+    pub fn policy(&self) -> BitmapWritePolicy {
+        self.policy
+    }
+
+    /// The allocator strategy this bitmap was loaded with
+    pub fn strategy(&self) -> AllocatorStrategy {
+        match self.buddy {
+            Some(_) => AllocatorStrategy::Buddy,
+            None => AllocatorStrategy::FirstFit,
+        }
+    }
+
+    /// Whether data block `index` is allocated
+    pub fn get(&self, index: u64) -> bool {
+        self.bits.get(index)
+    }
+
+    /// First free data block in [start, end), if any
+    pub fn find_free(&self, start: u64, end: u64) -> Option<u64> {
+        self.bits.find_first_zero(start, end)
+    }
+
+    /// Number of free data blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.nfree
+    }
+
+    /// Number of free data blocks in [start, end)
+    pub fn count_free(&self, start: u64, end: u64) -> u64 {
+        let end = end.min(self.bits.len());
+        if start >= end {
+            return 0;
+        }
+        (end - start) - self.bits.count_ones_in(start, end)
+    }
+
+    /// Length of the longest run of free data blocks, as a measure of fragmentation
+    pub fn largest_free_run(&self) -> u64 {
+        let n = self.bits.len();
+        let mut longest = 0;
+        let mut pos = 0;
+        while let Some(start) = self.bits.find_first_zero(pos, n) {
+            let end = self.bits.find_first_one(start, n).unwrap_or(n);
+            longest = longest.max(end - start);
+            pos = end;
+        }
+        longest
+    }
+
+    /// Allocates a free data block and marks it as used
+    /// First fit looks in [start, end) only, the buddy allocator takes the lowest block of the
+    /// smallest free run anywhere.
+    pub fn allocate(&mut self, sb: &SuperBlock, start: u64, end: u64) -> Option<u64> {
+        let index = match self.buddy.as_mut() {
+            Some(buddy) => buddy.alloc(0)?,
+            None => self.bits.find_first_zero(start, end)?,
+        };
+        self.mark(sb, index, index + 1, true);
+        Some(index)
+    }
+
+    /// Allocates `2^order` contiguous free data blocks, marks them as used and returns the first one
+    /// The buddy allocator aligns the run on a multiple of its length.
+    pub fn allocate_run(&mut self, sb: &SuperBlock, order: usize) -> Option<u64> {
+        let len = 1u64.checked_shl(order as u32)?;
+        let start = match self.buddy.as_mut() {
+            Some(buddy) => buddy.alloc(order)?,
+            None => self.bits.find_zero_run(len, 0, self.bits.len())?,
+        };
+        self.mark(sb, start, start + len, true);
+        Some(start)
+    }
+
+    /// Marks the used data block `index` as free
+    pub fn release(&mut self, sb: &SuperBlock, index: u64) {
+        if let Some(buddy) = self.buddy.as_mut() {
+            buddy.free(index, 0);
+        }
+        self.mark(sb, index, index + 1, false);
+    }
+
+    /// Whether there are changes that have not been written to disk yet
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Whether there are dirty bitmap blocks that the policy wants written right away
+    pub fn needs_write_through(&self) -> bool {
+        self.policy == BitmapWritePolicy::WriteThrough && self.is_dirty()
+    }
+
+    /// Takes the block numbers of all dirty bitmap blocks, which are considered clean afterwards.
+    /// The caller has to write them back, after filling them in with `fill_block`.
+    pub fn take_dirty(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.dirty).into_iter().collect()
+    }
+
+    /// Puts the pending changes of this bitmap into `block`, if it is a dirty bitmap block
+    pub fn overlay(&self, sb: &SuperBlock, block: &mut Block) -> Result<(), FileSystemError> {
+        if self.dirty.contains(&block.block_no) {
+            self.fill_block(sb, block)?;
+        }
+        Ok(())
+    }
+
+    /// Takes over the contents of `block`, which was just written to disk, if it is a bitmap block
+    pub fn absorb(&mut self, sb: &SuperBlock, block: &Block) {
+        if let Some(first_bit) = bitmap_block_first_bit(sb, block.block_no) {
+            self.bits.read_bytes_at(first_bit, block.contents_as_ref());
+            self.nfree = self.bits.len() - self.bits.count_ones();
+            self.dirty.remove(&block.block_no);
+            if self.buddy.is_some() {
+                self.buddy = Some(BuddyAllocator::from_bitmap(&self.bits));
+            }
+        }
+    }
+
+    /// Sets the bits of the data blocks in [start, end) to `used` and marks their bitmap blocks dirty
+    fn mark(&mut self, sb: &SuperBlock, start: u64, end: u64, used: bool) {
+        let used_before = self.bits.count_ones_in(start, end);
+        if used {
+            self.bits.set_range(start, end);
+        } else {
+            self.bits.clear_range(start, end);
+        }
+        self.nfree = self.nfree + used_before - self.bits.count_ones_in(start, end);
+        let first = get_bit_blockindex(sb, start).0;
+        let last = get_bit_blockindex(sb, end - 1).0;
+        self.dirty.extend(first..=last);
+    }
+
+    /// Copies the bits described by bitmap block `block` into it
+    pub fn fill_block(&self, sb: &SuperBlock, block: &mut Block) -> Result<(), FileSystemError> {
+        if let Some(first_bit) = bitmap_block_first_bit(sb, block.block_no) {
+            let mut bytes = block.contents_as_ref().to_vec();
+            self.bits.write_bytes_at(first_bit, &mut bytes);
+            block.write_data(&bytes, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Index of the first data block described by block `block_no`, if that block lies in the bitmap region
+fn bitmap_block_first_bit(sb: &SuperBlock, block_no: u64) -> Option<u64> {
+    if block_no >= sb.bmapstart && block_no < sb.bmapstart + get_nbitmapblocks(sb) {
+        Some((block_no - sb.bmapstart) * sb.block_size * 8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::b_inode_support::FileSystem;
+    use crate::options::{AllocatorStrategy, BitmapWritePolicy, MountOptions};
+    use cplfs_api::fs::{BlockSupport, FileSysSupport};
+    use cplfs_api::types::{Block, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn write_back_options() -> MountOptions {
+        MountOptions {
+            bitmap_policy: BitmapWritePolicy::WriteBack,
+            ..MountOptions::default()
+        }
+    }
+
+    fn buddy_options() -> MountOptions {
+        MountOptions {
+            allocator: AllocatorStrategy::Buddy,
+            ..MountOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-alloc-bitmap-".to_string() + name), "img")
+    }
+
+    /// First byte of the bitmap block, as it is stored on the device
+    fn bitmap_on_disk(fs: &FileSystem) -> u8 {
+        let dev = fs.device.as_ref().unwrap();
+        dev.read_block(SUPERBLOCK_GOOD.bmapstart)
+            .unwrap()
+            .contents_as_ref()[0]
+    }
+
+    #[test]
+    fn write_through() {
+        let path = disk_prep_path("write_through");
+        let mut my_fs = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+        assert_eq!(bitmap_on_disk(&my_fs), 0b11);
+        my_fs.b_free(0).unwrap();
+        assert_eq!(bitmap_on_disk(&my_fs), 0b10);
+        assert!(my_fs.b_free(0).is_err());
+
+        //Writing a bitmap block directly replaces the in-memory bitmap
+        let mut b = Block::new_zero(SUPERBLOCK_GOOD.bmapstart, BLOCK_SIZE);
+        b.write_data(&[0xff], 0).unwrap();
+        my_fs.b_put(&b).unwrap();
+        assert_eq!(my_fs.b_alloc().unwrap(), 8);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn write_back() {
+        let path = disk_prep_path("write_back");
+        let my_fs = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &write_back_options()).unwrap();
+
+        for i in 0..3 {
+            assert_eq!(my_fs.b_alloc().unwrap(), i);
+        }
+        my_fs.b_free(1).unwrap();
+        //Nothing reached the disk yet, but reads through the file system see the changes
+        assert_eq!(bitmap_on_disk(&my_fs), 0);
+        let b = my_fs.b_get(SUPERBLOCK_GOOD.bmapstart).unwrap();
+        assert_eq!(b.contents_as_ref()[0], 0b101);
+
+        my_fs.sync().unwrap();
+        assert_eq!(bitmap_on_disk(&my_fs), 0b101);
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+
+        //Unmounting flushes as well
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(bitmap_on_disk(&my_fs), 0b111);
+        assert_eq!(my_fs.b_alloc().unwrap(), 3);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn allocator_strategies() {
+        let path = disk_prep_path("allocator_strategies");
+        let mut my_fs = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        //First fit puts a run right behind the first block
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        assert_eq!(my_fs.b_alloc_run(2).unwrap(), 1);
+        for i in 0..5 {
+            my_fs.b_free(i).unwrap();
+        }
+
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &buddy_options()).unwrap();
+        //The buddy allocator aligns runs to their size
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        assert_eq!(my_fs.b_alloc_run(2).unwrap(), 4);
+        assert_eq!(my_fs.b_alloc_run(3).unwrap(), 8);
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+        assert_eq!(my_fs.b_alloc_run(1).unwrap(), 2);
+        assert!(my_fs.b_alloc_run(5).is_err());
+        let bitmap = my_fs.alloc_bitmap.as_ref().unwrap();
+        assert_eq!(bitmap.largest_free_run(), 16);
+        for i in 4..8 {
+            my_fs.b_free(i).unwrap();
+        }
+        assert!(my_fs.b_free(4).is_err());
+
+        //The buddy allocator is rebuilt from the bitmap when mounting
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &buddy_options()).unwrap();
+        assert_eq!(my_fs.b_alloc_run(4).unwrap(), 16);
+        assert_eq!(my_fs.b_alloc_run(2).unwrap(), 4);
+        assert!(my_fs.b_alloc().is_err());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
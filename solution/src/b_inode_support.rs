@@ -25,12 +25,14 @@
 
 //use crate::a_block_support::FileSystem;
 use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeSupport};
-use cplfs_api::types::{DInode, FType, Inode, InodeLike, DINODE_SIZE};
+use cplfs_api::types::{DInode, FType, Inode, InodeLike, DINODE_SIZE, ROOT_INUM};
 
 use crate::filesystem_errors::FileSystemError;
 use crate::helpers::{get_inode_block, trunc};
 
 use std::borrow::BorrowMut;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 
 use cplfs_api::controller::Device;
 use cplfs_api::types::{Block, SuperBlock};
@@ -38,6 +40,31 @@ use std::path::Path;
 
 use crate::helpers::*;
 
+use crate::alloc_bitmap::AllocBitmap;
+use crate::block_groups::{
+    fill_group_descs, find_group_dir, find_group_other, group_block_range, group_inode_range,
+    group_of_block, group_of_inode, load_group_descs, new_groups_ext, recount_group_descs,
+    store_group_descs, GroupDesc,
+};
+use crate::buffer_cache::{BufferCache, CacheStats};
+use crate::checksum::{checksum_table_blocks, format_checksums};
+use crate::clock::{Clock, SystemClock};
+use crate::inode_ext::{
+    format_inode_ext, inode_ext_blocks, inode_ext_location, InodeExt, INODE_FLAG_INLINE,
+};
+use crate::options::{JournalMode, MkfsOptions, MountOptions, SecureDelete};
+use crate::permissions::{default_mode, Credentials};
+use crate::readahead::ReadAhead;
+use crate::reflink::{format_refcounts, refcount_blocks};
+use crate::secure_delete::ScrubQueue;
+use crate::space::{available_blocks, reserved_blocks, AllocContext, StatFs};
+use crate::superblock_ext::{
+    read_sb_ext, sb_ext_valid, SuperBlockExt, FEATURE_BLOCK_GROUPS, FEATURE_CHECKSUMS,
+    FEATURE_DATA_CHECKSUMS, FEATURE_EXTENTS, FEATURE_INLINE_DATA, FEATURE_INODE_EXT, FEATURE_LOG,
+    FEATURE_REFLINK,
+};
+use crate::wal::{clear_log, recover, Log};
+
 /// You are free to choose the name for your file system. As we will use
 /// automated tests when grading your assignment, indicate here the name of
 /// your file system data type so we can just use `FSName` instead of
@@ -45,6 +72,24 @@ use crate::helpers::*;
 /// *
 pub type FSName = FileSystem;
 
+#[derive(Debug, Clone)]
+/// The in-memory state of a file system that is restored when its running transaction is thrown
+/// away, see `FileSystem::start_op`
+pub(crate) struct Snapshot {
+    /// Copy of `FileSystem::alloc_bitmap`
+    alloc_bitmap: Option<AllocBitmap>,
+    /// Copy of `FileSystem::groups`
+    groups: Vec<GroupDesc>,
+    /// Copy of `FileSystem::groups_dirty`
+    groups_dirty: bool,
+    /// Copy of `FileSystem::scrub`
+    scrub: ScrubQueue,
+    /// Copy of `FileSystem::readahead`
+    readahead: ReadAhead,
+    /// Copy of `FileSystem::atimes`
+    atimes: BTreeMap<u64, u64>,
+}
+
 #[derive(Debug)]
 /// This is the filesystem structure that wa are going to use in the whole project
 pub struct FileSystem {
@@ -52,147 +97,911 @@ pub struct FileSystem {
     pub superblock: SuperBlock,
     /// This is the device we work on, it is optional at the start and can be filled in later
     pub device: Option<Device>,
+    /// The extra layout information that is stored behind the superblock
+    pub sb_ext: SuperBlockExt,
+    /// In-memory copy of the block group descriptors, empty if the layout is flat
+    pub groups: Vec<GroupDesc>,
+    /// Whether `groups` has changes that were not written to block 0 yet
+    pub groups_dirty: bool,
+    /// In-memory copy of the allocation bitmap, loaded when the file system is mounted
+    pub alloc_bitmap: Option<AllocBitmap>,
+    /// Freed data blocks that still have to be scrubbed, see `MountOptions::secure_delete`
+    pub scrub: ScrubQueue,
+    /// On whose behalf data blocks are allocated, see `as_privileged`
+    pub alloc_context: AllocContext,
+    /// Buffer cache between the block methods and the device.
+    /// It sits in a `RefCell` because reading a block (`b_get(&self)`) updates the cache as well.
+    pub cache: RefCell<BufferCache>,
+    /// Sequential access detection for reads of inodes, in a `RefCell` for the same reason
+    pub readahead: RefCell<ReadAhead>,
+    /// Write-ahead log holding the running transaction, if the file system has a log region
+    pub log: Option<Log>,
+    /// Source of the timestamps of inodes
+    pub clock: Box<dyn Clock>,
+    /// Whether reads leave the access time of inodes alone, see `MountOptions::noatime`
+    pub noatime: bool,
+    /// Whether shrinking truncates move contents back into the inode, see `MountOptions::reinline`
+    pub reinline: bool,
+    /// Access times that have not been written to the inode extension table yet, by inode.
+    /// Reads only have shared access to the file system, hence the `RefCell`.
+    pub atimes: RefCell<BTreeMap<u64, u64>>,
+    /// The user on whose behalf path operations run, see `crate::permissions`
+    pub creds: Credentials,
 }
 
 impl FileSystem {
     /// This function creates a filesystem struct given a superblock and a optional device
     pub fn create_filesystem(superblock: SuperBlock, device: Option<Device>) -> FileSystem {
-        FileSystem { superblock, device }
+        FileSystem {
+            superblock,
+            device,
+            sb_ext: SuperBlockExt::default(),
+            groups: vec![],
+            groups_dirty: false,
+            alloc_bitmap: None,
+            scrub: ScrubQueue::new(SecureDelete::Off),
+            alloc_context: AllocContext::User,
+            cache: RefCell::new(BufferCache::new(0)),
+            readahead: RefCell::new(ReadAhead::new(0)),
+            log: None,
+            clock: Box::new(SystemClock),
+            noatime: false,
+            reinline: false,
+            atimes: RefCell::new(BTreeMap::new()),
+            creds: Credentials::root(),
+        }
     }
-}
 
-impl FileSysSupport for FileSystem {
-    type Error = FileSystemError;
+    /// Same as `mkfs`, but lets the caller pick some layout options
+    pub fn mkfs_with_options<P: AsRef<Path>>(
+        path: P,
+        sb: &SuperBlock,
+        options: &MkfsOptions,
+    ) -> Result<Self, FileSystemError> {
+        if !FSName::sb_valid(sb) {
+            return Err(FileSystemError::InvalidSuperBlock());
+        }
+        let mut ext = match options.blocks_per_group {
+            Some(blocks_per_group) => new_groups_ext(sb, blocks_per_group)?,
+            None => SuperBlockExt::new(),
+        };
+        ext.reserved_blocks = reserved_blocks(sb.ndatablocks, options.reserved_percent)
+            .ok_or_else(FileSystemError::InvalidSuperBlock)?;
+        let mut next_region = sb.datastart + sb.ndatablocks;
+        if options.log_blocks > 0 {
+            ext.features |= FEATURE_LOG;
+            ext.logstart = next_region;
+            ext.nlog = options.log_blocks;
+            next_region += ext.nlog;
+        }
+        if options.extended_inodes {
+            ext.features |= FEATURE_INODE_EXT;
+            ext.iextstart = next_region;
+            ext.niextblocks = inode_ext_blocks(sb);
+            next_region += ext.niextblocks;
+        }
+        if options.reflink {
+            ext.features |= FEATURE_REFLINK;
+            ext.refstart = next_region;
+            ext.nrefblocks = refcount_blocks(sb);
+            next_region += ext.nrefblocks;
+        }
+        if options.checksums {
+            ext.features |= FEATURE_CHECKSUMS;
+            ext.csumstart = next_region;
+            ext.ncsumblocks = checksum_table_blocks(sb);
+        }
+        if options.data_checksums {
+            if !options.checksums {
+                return Err(FileSystemError::InvalidSuperBlock());
+            }
+            ext.features |= FEATURE_DATA_CHECKSUMS;
+        }
+        if options.extents {
+            ext.features |= FEATURE_EXTENTS;
+        }
+        if options.inline_data {
+            if !options.extended_inodes {
+                return Err(FileSystemError::InvalidSuperBlock());
+            }
+            ext.features |= FEATURE_INLINE_DATA;
+        }
+        if !ext.is_used() {
+            // Block 0 then only holds the superblock, so it does not need room for the extension
+            ext = SuperBlockExt::default();
+        }
+        if !sb_ext_valid(sb, &ext) {
+            return Err(FileSystemError::InvalidSuperBlock());
+        }
 
-    fn sb_valid(sb: &SuperBlock) -> bool {
-        return sb_valid(sb);
+        let mut device = Device::new(path, sb.block_size, sb.nblocks)?;
+        //place superblock at index 0
+        write_sb(sb, &mut device)?;
+        if ext.is_used() {
+            write_sb_ext_to_device(&ext, &mut device)?;
+        }
+        allocate_inoderegionblocks(sb, &mut device)?;
+        allocate_bitmapregion(sb, &mut device)?;
+        allocate_dataregion(sb, &mut device)?;
+        if ext.has_feature(FEATURE_LOG) {
+            clear_log(&mut device, &ext)?;
+        }
+        if ext.has_feature(FEATURE_INODE_EXT) {
+            format_inode_ext(&mut device, sb, &ext)?;
+        }
+        if ext.has_feature(FEATURE_REFLINK) {
+            format_refcounts(&mut device, sb, &ext)?;
+        }
+        if ext.has_feature(FEATURE_CHECKSUMS) {
+            format_checksums(&mut device, sb, &ext)?;
+        }
+        let mut fs = FileSystem::mountfs(device)?;
+
+        allocate_inodes(&mut fs)?;
+        fs.refresh_groups()?;
+        fs.rebuild_block_checksums()?;
+        Ok(fs)
     }
 
-    fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
-        if !FSName::sb_valid(sb) {
-            Err(FileSystemError::InvalidSuperBlock())
+    /// Same as `mountfs`, but lets the caller pick some mount options
+    pub fn mountfs_with_options(
+        mut dev: Device,
+        options: &MountOptions,
+    ) -> Result<Self, FileSystemError> {
+        let block = read_block(&dev, 0)?;
+        let sb = block.deserialize_from::<SuperBlock>(0)?;
+        let ext = read_sb_ext(&block)?;
+        if !(FSName::sb_valid(&sb)
+            && sb_ext_valid(&sb, &ext)
+            && dev.block_size == sb.block_size
+            && dev.nblocks == sb.nblocks)
+        {
+            return Err(FileSystemError::InvalidSuperBlock());
+        }
+
+        // Install the last committed transaction before anything is read from the regions
+        recover(&mut dev, &ext)?;
+        let alloc_bitmap = AllocBitmap::load(&dev, &sb, options)?;
+        let mut fs = FileSystem::create_filesystem(sb, Some(dev));
+        fs.sb_ext = ext;
+        fs.alloc_bitmap = Some(alloc_bitmap);
+        fs.scrub = ScrubQueue::new(options.secure_delete);
+        fs.cache = RefCell::new(BufferCache::new(options.cache_blocks));
+        fs.readahead = RefCell::new(ReadAhead::new(options.readahead_blocks));
+        fs.noatime = options.noatime;
+        fs.reinline = options.reinline;
+        if ext.has_feature(FEATURE_LOG) {
+            fs.log = Some(Log::new(&sb, &ext, options.journal_mode));
+        }
+        // The bitmap was loaded straight from the device, so its checksums are checked here
+        fs.verify_bitmap()?;
+        if fs.is_grouped() {
+            fs.groups = load_group_descs(&fs)?;
+        }
+        Ok(fs)
+    }
+
+    /// Writes all pending changes to the device: first the freed blocks that are still queued for
+    /// scrubbing are zeroed, then the group descriptors, the in-memory allocation bitmap and the
+    /// buffer cache are flushed
+    pub fn sync(&mut self) -> Result<(), FileSystemError> {
+        self.flush_atimes()?;
+        self.scrub_freed(usize::MAX)?;
+        self.flush_groups()?;
+        self.flush_bitmap()?;
+        let dev = self
+            .device
+            .as_mut()
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        self.cache.get_mut().flush(dev)
+    }
+
+    /// Same as `unmountfs`, but reports it when the pending changes could not be written back
+    /// The device is handed back either way, next to the error if `sync` failed. `unmountfs`
+    /// drops that error silently, so this is the way to find out about it.
+    pub fn unmountfs_checked(mut self) -> Result<Device, (FileSystemError, Device)> {
+        let synced = self.sync();
+        let device = self.device.take().unwrap();
+        match synced {
+            Ok(()) => Ok(device),
+            Err(e) => Err((e, device)),
+        }
+    }
+
+    /// Replaces the clock that timestamps inodes, e.g. by a `ManualClock` in tests
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Whether this file system was created with an inode extension table
+    pub fn has_inode_ext(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_INODE_EXT)
+    }
+
+    /// Reads the extension record of inode `inum`, see `crate::inode_ext`
+    pub fn i_ext_get(&self, inum: u64) -> Result<InodeExt, FileSystemError> {
+        if inum >= self.superblock.ninodes {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        if !self.has_inode_ext() {
+            return Ok(InodeExt::default());
+        }
+        let (block_no, offset) = inode_ext_location(&self.superblock, &self.sb_ext, inum);
+        let mut ext = self.b_get(block_no)?.deserialize_from::<InodeExt>(offset)?;
+        if let Some(&atime) = self.atimes.borrow().get(&inum) {
+            ext.atime = atime;
+        }
+        Ok(ext)
+    }
+
+    /// Writes the extension record of inode `inum`, see `crate::inode_ext`
+    /// Does nothing if the file system has no inode extension table.
+    pub fn i_ext_put(&mut self, inum: u64, ext: &InodeExt) -> Result<(), FileSystemError> {
+        if inum >= self.superblock.ninodes {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        if !self.has_inode_ext() {
+            return Ok(());
+        }
+        self.atimes.get_mut().remove(&inum);
+        let (block_no, offset) = inode_ext_location(&self.superblock, &self.sb_ext, inum);
+        let mut block = self.b_get(block_no)?;
+        block.serialize_into(ext, offset)?;
+        self.b_put(&block)
+    }
+
+    /// Gives the freshly allocated inode `inum` of type `ft` a new extension record, with all
+    /// timestamps set to now, owned by the current user and with the default mode for `ft`
+    pub fn touch_created(&mut self, inum: u64, ft: FType) -> Result<(), FileSystemError> {
+        let now = self.clock.now();
+        let ext = InodeExt {
+            atime: now,
+            mtime: now,
+            ctime: now,
+            crtime: now,
+            uid: self.creds.uid,
+            gid: self.creds.gid,
+            mode: default_mode(ft),
+            flags: if self.has_inline_data() {
+                INODE_FLAG_INLINE
+            } else {
+                0
+            },
+            xattr_block: 0,
+        };
+        self.i_ext_put(inum, &ext)
+    }
+
+    /// Checks whether the current user may access inode `inum` in the ways in `want`, see
+    /// `Credentials::permits`. Always succeeds without an inode extension table.
+    pub fn check_access(&self, inum: u64, want: u32) -> Result<(), FileSystemError> {
+        if !self.has_inode_ext() || self.creds.is_root() {
+            return Ok(());
+        }
+        if self.creds.permits(&self.i_ext_get(inum)?, want) {
+            Ok(())
         } else {
-            let device_result = Device::new(path, sb.block_size, sb.nblocks);
+            Err(FileSystemError::PermissionDenied())
+        }
+    }
 
-            match device_result {
-                Ok(mut device) => {
-                    //place superblock at index 0
+    /// Sets the modification and change time of inode `inum` to now
+    pub fn touch_modified(&mut self, inum: u64) -> Result<(), FileSystemError> {
+        let now = self.clock.now();
+        let mut ext = self.i_ext_get(inum)?;
+        ext.mtime = now;
+        ext.ctime = now;
+        self.i_ext_put(inum, &ext)
+    }
 
-                    write_sb(sb, &mut device)?;
-                    allocate_inoderegionblocks(&sb, &mut device)?;
-                    allocate_bitmapregion(&sb, &mut device)?;
-                    allocate_dataregion(&sb, &mut device)?;
-                    let mut fs = FileSystem::mountfs(device)?;
+    /// Sets the change time of inode `inum` to now
+    pub fn touch_changed(&mut self, inum: u64) -> Result<(), FileSystemError> {
+        let now = self.clock.now();
+        let mut ext = self.i_ext_get(inum)?;
+        ext.ctime = now;
+        self.i_ext_put(inum, &ext)
+    }
 
-                    allocate_inodes(&mut fs)?;
-                    Ok(fs)
-                }
-                Err(e) => Err(FileSystemError::DeviceAPIError(e)),
-            }
+    /// Sets the access time of inode `inum` to now, unless the file system is mounted `noatime`
+    /// Reads only have shared access, so the new access time is kept in memory until the next
+    /// `sync`.
+    pub fn touch_accessed(&self, inum: u64) {
+        if !self.noatime && self.has_inode_ext() {
+            self.atimes.borrow_mut().insert(inum, self.clock.now());
         }
     }
 
-    fn mountfs(dev: Device) -> Result<Self, Self::Error> {
-        match dev.read_block(0) {
-            Ok(block) => {
-                let sb = &block.deserialize_from::<SuperBlock>(0)?;
-                if FSName::sb_valid(sb)
-                    && dev.block_size == sb.block_size
-                    && dev.nblocks == sb.nblocks
-                {
-                    let fs = FileSystem::create_filesystem(*sb, Some(dev));
-                    Ok(fs)
-                } else {
-                    Err(FileSystemError::InvalidSuperBlock())
-                }
-            }
-            Err(e) => Err(FileSystemError::DeviceAPIError(e)),
+    /// Writes the access times that were kept in memory to the inode extension table
+    fn flush_atimes(&mut self) -> Result<(), FileSystemError> {
+        let atimes = std::mem::take(self.atimes.get_mut());
+        for (inum, atime) in atimes {
+            let mut ext = self.i_ext_get(inum)?;
+            ext.atime = atime;
+            self.i_ext_put(inum, &ext)?;
         }
+        Ok(())
     }
 
-    fn unmountfs(mut self) -> Device {
-        let deviceoption = self.device.take();
-        let device = deviceoption.unwrap();
-        return device;
+    /// Hit and miss counters of the buffer cache
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
     }
-}
 
-impl BlockSupport for FileSystem {
-    fn b_get(&self, i: u64) -> Result<Block, Self::Error> {
+    /// Reads block `i` through the buffer cache
+    /// Blocks written by the running transaction are read from the log instead.
+    pub(crate) fn cached_read(&self, i: u64) -> Result<Block, FileSystemError> {
+        if let Some(block) = self.log.as_ref().and_then(|log| log.read(i)) {
+            return Ok(block);
+        }
         let dev = self
             .device
             .as_ref()
-            .ok_or_else(|| FileSystemError::DeviceNotSet())?;
-        return Ok(read_block(dev, i)?);
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        self.cache.borrow_mut().read(dev, i)
     }
 
-    fn b_put(&mut self, b: &Block) -> Result<(), Self::Error> {
+    /// Brings block `i` into the buffer cache ahead of time, see `BufferCache::prefetch`
+    pub fn prefetch(&self, i: u64) -> Result<(), FileSystemError> {
+        let dev = self
+            .device
+            .as_ref()
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        self.cache.borrow_mut().prefetch(dev, i)
+    }
+
+    /// Writes block `b` through the buffer cache
+    /// Inside an operation, valid blocks are added to the running transaction instead.
+    pub(crate) fn cached_write(&mut self, b: &Block) -> Result<(), FileSystemError> {
         let dev = self
             .device
             .as_mut()
-            .ok_or_else(|| FileSystemError::DeviceNotSet())?;
-        return Ok(write_block(dev, b)?);
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        if let Some(log) = self.log.as_mut().filter(|log| log.is_active()) {
+            if b.len() == dev.block_size && b.block_no < dev.nblocks {
+                return log.write(b);
+            }
+        }
+        self.cache.get_mut().write(dev, b)
     }
 
-    fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
+    /// Starts a file system operation; the blocks it writes are committed atomically by the
+    /// matching `end_op`. Operations can be nested, they then all join the same transaction.
+    /// Does nothing if the file system has no log.
+    pub fn begin_op(&mut self) {
+        if let Some(log) = self.log.as_mut() {
+            log.begin_op();
+        }
+    }
+
+    /// Ends a file system operation, and commits the transaction if it was the outermost one
+    /// If committing fails, the transaction is thrown away, so the log never holds a half-filled
+    /// one. Going back to the in-memory state from before the transaction is up to the caller, see
+    /// `in_op`.
+    pub fn end_op(&mut self) -> Result<(), FileSystemError> {
+        if !self.log.as_ref().map_or(false, |log| log.is_outermost()) {
+            if let Some(log) = self.log.as_mut() {
+                log.end_op();
+            }
+            return Ok(());
+        }
+        let committed = self.commit_op();
+        if committed.is_err() {
+            if let Some(log) = self.log.as_mut() {
+                log.abort();
+            }
+        }
+        committed
+    }
+
+    /// Ends the outermost operation and commits its transaction
+    fn commit_op(&mut self) -> Result<(), FileSystemError> {
+        // Bitmap changes that are held back by a write-back policy belong to the transaction too,
+        // like the changed group descriptors, so they are written while it is still running
+        self.flush_groups()?;
+        self.flush_bitmap()?;
         let dev = self
             .device
             .as_mut()
-            .ok_or_else(|| FileSystemError::DeviceNotSet())?;
-        set_bitmapbit(&self.superblock, dev, i, false)?;
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        if let Some(log) = self.log.as_mut() {
+            if log.overflowed() {
+                return Err(FileSystemError::LogFull());
+            }
+            log.end_op();
+            let cache = self.cache.get_mut();
+            let ordered: Vec<Block> = log
+                .take_ordered()
+                .into_iter()
+                .filter_map(|i| cache.take_dirty(i))
+                .collect();
+            let installed = log.commit(dev, &ordered)?;
+            for b in &installed {
+                cache.install(b);
+            }
+        }
         Ok(())
     }
 
-    fn b_zero(&mut self, i: u64) -> Result<(), Self::Error> {
+    /// Writes block `b`, holding the contents of a file
+    /// Inside an operation, whether it goes through the log depends on the `JournalMode`. Its
+    /// checksum is only kept with `FEATURE_DATA_CHECKSUMS`, see `crate::checksum`.
+    pub fn data_put(&mut self, b: &Block) -> Result<(), FileSystemError> {
+        let dev = self
+            .device
+            .as_mut()
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        match self.log.as_mut() {
+            Some(log) if log.is_active() && log.mode() != JournalMode::Journal => {
+                log.write_unlogged(b.block_no);
+                self.cache.get_mut().write(dev, b)?;
+            }
+            _ => self.cached_write(b)?,
+        }
+        self.put_block_checksum(b, self.has_data_checksums())
+    }
+
+    /// Zeroes bytes `[start, end)` of block `block_no`, which holds the contents of a file
+    pub fn zero_data(
+        &mut self,
+        block_no: u64,
+        start: u64,
+        end: u64,
+    ) -> Result<(), FileSystemError> {
+        let mut block = self.b_get(block_no)?;
+        block.write_data(&vec![0; (end - start) as usize], start)?;
+        self.data_put(&block)
+    }
+
+    /// Runs `f` as a single file system operation, see `begin_op`
+    /// If `f` fails, or writes more blocks than fit in the log, the operation is all or nothing:
+    /// the outermost operation then throws its transaction away instead of committing it.
+    pub fn in_op<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, FileSystemError>,
+    ) -> Result<R, FileSystemError> {
+        let snapshot = self.start_op();
+        let result = f(self);
+        self.finish_op(snapshot, result)
+    }
+
+    /// Starts a file system operation like `begin_op`
+    /// For the outermost operation on a file system with a log, also returns the in-memory state
+    /// to go back to if the operation fails, which `finish_op` needs.
+    pub(crate) fn start_op(&mut self) -> Option<Snapshot> {
+        let snapshot = match self.log.as_ref() {
+            Some(log) if !log.is_active() => Some(self.snapshot()),
+            _ => None,
+        };
+        self.begin_op();
+        snapshot
+    }
+
+    /// Ends an operation that was started by `start_op` and had `result`
+    /// The transaction is committed, unless this is the outermost operation and it failed,
+    /// overflowed the log or could not be committed. Then the transaction is aborted and
+    /// `snapshot` restored instead.
+    pub(crate) fn finish_op<R>(
+        &mut self,
+        snapshot: Option<Snapshot>,
+        result: Result<R, FileSystemError>,
+    ) -> Result<R, FileSystemError> {
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                let ended = self.end_op();
+                let result = result?;
+                ended?;
+                return Ok(result);
+            }
+        };
+        let overflowed = self.log.as_ref().map_or(false, |log| log.overflowed());
+        if result.is_err() || overflowed {
+            self.abort_op(snapshot);
+            return result.and(Err(FileSystemError::LogFull()));
+        }
+        if let Err(e) = self.end_op() {
+            self.abort_op(snapshot);
+            return Err(e);
+        }
+        result
+    }
+
+    /// Copies the in-memory state that a transaction may change
+    pub(crate) fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            alloc_bitmap: self.alloc_bitmap.clone(),
+            groups: self.groups.clone(),
+            groups_dirty: self.groups_dirty,
+            scrub: self.scrub.clone(),
+            readahead: self.readahead.borrow().clone(),
+            atimes: self.atimes.borrow().clone(),
+        }
+    }
+
+    /// Throws the running transaction away, ending all of its operations, and goes back to the
+    /// in-memory state of `snapshot`
+    pub(crate) fn abort_op(&mut self, snapshot: Snapshot) {
+        if let Some(log) = self.log.as_mut() {
+            log.abort();
+        }
+        self.alloc_bitmap = snapshot.alloc_bitmap;
+        self.groups = snapshot.groups;
+        self.groups_dirty = snapshot.groups_dirty;
+        self.scrub = snapshot.scrub;
+        *self.readahead.get_mut() = snapshot.readahead;
+        *self.atimes.get_mut() = snapshot.atimes;
+    }
+
+    /// Writes the in-memory group descriptors to block 0, if they changed since the last time
+    fn flush_groups(&mut self) -> Result<(), FileSystemError> {
+        if self.groups_dirty {
+            store_group_descs(self)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the dirty blocks of the in-memory allocation bitmap to the buffer cache, and returns
+    /// their block numbers
+    fn flush_bitmap(&mut self) -> Result<Vec<u64>, FileSystemError> {
+        let sb = self.superblock;
+        let dirty = match self.alloc_bitmap.as_mut() {
+            Some(bitmap) => bitmap.take_dirty(),
+            None => return Ok(Vec::new()),
+        };
+        for &block_no in &dirty {
+            let mut block = self.cached_read(block_no)?;
+            if let Some(bitmap) = self.alloc_bitmap.as_ref() {
+                bitmap.fill_block(&sb, &mut block)?;
+            }
+            self.put_block_checksum(&block, true)?;
+            self.cached_write(&block)?;
+        }
+        Ok(dirty)
+    }
+
+    /// Zeroes at most `max` of the freed data blocks that are queued for scrubbing and returns how
+    /// many were zeroed. Meant to be called in the background with a small `max`.
+    pub fn scrub_freed(&mut self, max: usize) -> Result<usize, FileSystemError> {
+        let batch = self.scrub.take_batch(max);
+        for &i in &batch {
+            self.fill_block(i, 0)?;
+        }
+        Ok(batch.len())
+    }
+
+    /// Overwrites every byte of data block `i` with `byte`
+    fn fill_block(&mut self, i: u64, byte: u8) -> Result<(), FileSystemError> {
         let datablock_index = i + self.superblock.datastart;
-        let newzeroblock = Block::new(
+        let newblock = Block::new(
             datablock_index,
-            vec![0; self.superblock.block_size as usize].into_boxed_slice(),
+            vec![byte; self.superblock.block_size as usize].into_boxed_slice(),
         );
-        self.b_put(&newzeroblock)?;
+        self.b_put(&newblock)
+    }
+
+    /// Writes the changes of the allocation bitmap to the device, if the bitmap write policy of the
+    /// mount asks for it
+    fn write_bitmap_through(&mut self) -> Result<(), FileSystemError> {
+        let write_through = self
+            .alloc_bitmap
+            .as_ref()
+            .map_or(false, |bitmap| bitmap.needs_write_through());
+        if !write_through {
+            return Ok(());
+        }
+        let written = self.flush_bitmap()?;
+        // Outside an operation, the blocks must reach the device and not just the buffer cache.
+        // Inside one, they are part of the transaction and reach the device when it commits.
+        if self.log.as_ref().map_or(false, |log| log.is_active()) {
+            return Ok(());
+        }
+        let dev = self
+            .device
+            .as_mut()
+            .ok_or_else(FileSystemError::DeviceNotSet)?;
+        let cache = self.cache.get_mut();
+        for b in written.into_iter().filter_map(|i| cache.take_dirty(i)) {
+            write_block(dev, &b)?;
+        }
         Ok(())
     }
 
-    fn b_alloc(&mut self) -> Result<u64, Self::Error> {
-        let nbitmapblocks = get_nbitmapblocks(&self.superblock);
-        let mut bmstart_index = self.superblock.bmapstart; // get the index
-        let mut block; // get the first block
-        let mut byte_array; // create an empty data buffer
-        let mut byteindex; //block index
-
-        for blockindex in 0..nbitmapblocks {
-            block = self.b_get(bmstart_index + blockindex)?; //next block
-                                                             //byte_array = block.contents_as_ref(); //get the block's array
-            byte_array = block.contents_as_ref();
-            byteindex = get_bytesarray_free_index(byte_array);
-            if byteindex.is_err() {
-                // HERE WE ARE LOOKING FOR THE NEXT BLOCK
-                bmstart_index += 1; //next block index
+    /// Updates the free block counts of the groups holding the data blocks in [start, end)
+    /// Does nothing for a flat layout
+    fn account_blocks(&mut self, start: u64, end: u64, freed: bool) -> Result<(), FileSystemError> {
+        if self.is_grouped() {
+            for i in start..end {
+                let g = group_of_block(&self.sb_ext, i);
+                if freed {
+                    self.groups[g].free_blocks += 1;
+                } else {
+                    self.groups[g].free_blocks -= 1;
+                }
+            }
+            self.groups_dirty = true;
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with allocations in the privileged context, so they can use the reserved blocks
+    pub fn as_privileged<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let old = self.alloc_context;
+        self.alloc_context = AllocContext::Privileged;
+        let result = f(self);
+        self.alloc_context = old;
+        result
+    }
+
+    /// Reports the used and free space of this file system
+    pub fn statfs(&self) -> Result<StatFs, FileSystemError> {
+        let mut free_inodes = 0;
+        for i in 1..self.superblock.ninodes {
+            if self.i_get(i)?.get_ft() == FType::TFree {
+                free_inodes += 1;
+            }
+        }
+        self.statfs_with(free_inodes)
+    }
+
+    /// Like `statfs`, with the free inodes counted by the caller, for file systems that store
+    /// inodes in a format of their own
+    pub(crate) fn statfs_with(&self, free_inodes: u64) -> Result<StatFs, FileSystemError> {
+        let free_blocks = self
+            .alloc_bitmap
+            .as_ref()
+            .ok_or_else(FileSystemError::DeviceNotSet)?
+            .free_blocks();
+        let reserved = self.sb_ext.reserved_blocks;
+        Ok(StatFs {
+            block_size: self.superblock.block_size,
+            data_blocks: self.superblock.ndatablocks,
+            free_blocks,
+            reserved_blocks: reserved,
+            available_blocks: available_blocks(free_blocks, reserved, AllocContext::User),
+            inodes: self.superblock.ninodes,
+            free_inodes,
+        })
+    }
+
+    /// Checks whether `n` more data blocks may be allocated in the current allocation context
+    fn check_headroom(&self, n: u64) -> Result<(), FileSystemError> {
+        let free = self
+            .alloc_bitmap
+            .as_ref()
+            .ok_or_else(FileSystemError::DeviceNotSet)?
+            .free_blocks();
+        if available_blocks(free, self.sb_ext.reserved_blocks, self.alloc_context) < n {
+            return Err(FileSystemError::AllocationError());
+        }
+        Ok(())
+    }
+
+    /// Allocates `2^order` contiguous data blocks and returns the index of the first one
+    /// All blocks of the run are zeroed, and can be freed one by one with `b_free`.
+    pub fn b_alloc_run(&mut self, order: usize) -> Result<u64, FileSystemError> {
+        let len = 1u64
+            .checked_shl(order as u32)
+            .ok_or_else(FileSystemError::AllocationError)?;
+        let sb = self.superblock;
+        self.in_op(|fs| {
+            fs.check_headroom(len)?;
+            let start = fs
+                .alloc_bitmap
+                .as_mut()
+                .ok_or_else(FileSystemError::DeviceNotSet)?
+                .allocate_run(&sb, order)
+                .ok_or_else(FileSystemError::AllocationError)?;
+            let end = start + len;
+            for i in start..end {
+                fs.b_zero(i)?;
+            }
+            fs.write_bitmap_through()?;
+            fs.account_blocks(start, end, false)?;
+            Ok(start)
+        })
+    }
+
+    /// Whether this file system was created with block groups
+    pub fn is_grouped(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_BLOCK_GROUPS)
+    }
+
+    /// Recounts the group descriptors from the bitmap and inode region and persists them
+    /// Does nothing for a flat layout
+    pub fn refresh_groups(&mut self) -> Result<(), FileSystemError> {
+        if self.is_grouped() {
+            self.groups = recount_group_descs(self)?;
+            store_group_descs(self)?;
+        }
+        Ok(())
+    }
+
+    /// Allocates a data block, preferring the block group that holds inode `inum`
+    /// For a flat layout this is the same as `b_alloc`
+    pub fn b_alloc_near(&mut self, inum: u64) -> Result<u64, FileSystemError> {
+        let goal = if self.is_grouped() {
+            group_of_inode(&self.sb_ext, inum)
+        } else {
+            0
+        };
+        self.in_op(|fs| fs.b_alloc_from_group(goal))
+    }
+
+    /// Allocation of a data block, trying the groups in order starting from `goal`
+    fn b_alloc_from_group(&mut self, goal: usize) -> Result<u64, FileSystemError> {
+        self.check_headroom(1)?;
+        let sb = self.superblock;
+        let ngroups = self.groups.len().max(1);
+        for k in 0..ngroups {
+            let g = (goal + k) % ngroups;
+            let (start, end) = if self.is_grouped() {
+                if self.groups[g].free_blocks == 0 {
+                    continue;
+                }
+                group_block_range(&self.superblock, &self.sb_ext, g)
             } else {
-                // The current bm_block has a free spot
-                let byteindex = byteindex.unwrap(); //get the index of the byte that has a free spot
-                let byte = byte_array.get(usize::from(byteindex)).unwrap();
-                let bitindex = 8 - 1 - byte.trailing_ones(); //moves the 1 to the correct position
-                let mutator = 0b00000001u8 << byte.trailing_ones(); //moves the 1 to the correct position
+                (0, self.superblock.ndatablocks)
+            };
 
-                let to_write_byte = &[(*byte | mutator)];
+            let allocated = self
+                .alloc_bitmap
+                .as_mut()
+                .ok_or_else(FileSystemError::DeviceNotSet)?
+                .allocate(&sb, start, end);
+            if let Some(datablockindex) = allocated {
+                self.b_zero(datablockindex)?;
+                self.write_bitmap_through()?;
+                self.account_blocks(datablockindex, datablockindex + 1, false)?;
+                return Ok(datablockindex);
+            }
+        }
+        Err(FileSystemError::AllocationError())
+    }
 
-                block.write_data(to_write_byte, byteindex as u64)?;
+    /// Allocates an inode of type `ft` for an entry of directory `parent`
+    /// With block groups, directories are spread over the groups and other files are kept in the
+    /// group of their parent. For a flat layout this is the same as `i_alloc`, which takes the root
+    /// directory as the parent.
+    pub fn i_alloc_near(&mut self, ft: FType, parent: u64) -> Result<u64, FileSystemError> {
+        self.in_op(|fs| {
+            let inum = fs.alloc_inode_near(ft, parent)?;
+            fs.touch_created(inum, ft)?;
+            Ok(inum)
+        })
+    }
+
+    /// Body of `i_alloc_near`, which runs inside an operation
+    fn alloc_inode_near(&mut self, ft: FType, parent: u64) -> Result<u64, FileSystemError> {
+        if !self.is_grouped() {
+            let inode_alloc_start = 1;
+            for i in inode_alloc_start..self.superblock.ninodes {
+                let mut ino = self.i_get(i)?;
+                if ino.get_ft() == FType::TFree {
+                    ino.disk_node.ft = ft;
+                    self.i_put(&ino)?;
+                    return Ok(i);
+                }
+            }
+            return Err(FileSystemError::AllocationError());
+        }
 
-                let byteindex: u64 = u64::from(byteindex);
-                let datablockindex = blockindex * self.superblock.block_size * 8
-                    + (byteindex) * 8
-                    + u64::from(7 - bitindex);
+        let goal = if ft == FType::TDir {
+            find_group_dir(&self.groups)
+        } else {
+            find_group_other(&self.groups, group_of_inode(&self.sb_ext, parent))
+        }
+        .ok_or_else(FileSystemError::AllocationError)?;
 
-                if datablockindex < self.superblock.ndatablocks {
-                    self.b_zero(datablockindex)?;
-                    self.b_put(&block)?;
-                    return Ok(datablockindex);
+        let ngroups = self.groups.len();
+        for k in 0..ngroups {
+            let g = (goal + k) % ngroups;
+            if self.groups[g].free_inodes == 0 {
+                continue;
+            }
+            let (start, end) = group_inode_range(&self.superblock, &self.sb_ext, g);
+            for i in start.max(1)..end {
+                let mut ino = self.i_get(i)?;
+                if ino.get_ft() == FType::TFree {
+                    ino.disk_node.ft = ft;
+                    self.i_put(&ino)?;
+                    self.groups[g].free_inodes -= 1;
+                    if ft == FType::TDir {
+                        self.groups[g].used_dirs += 1;
+                    }
+                    self.groups_dirty = true;
+                    return Ok(i);
                 }
             }
         }
         Err(FileSystemError::AllocationError())
     }
+}
+
+impl FileSysSupport for FileSystem {
+    type Error = FileSystemError;
+
+    fn sb_valid(sb: &SuperBlock) -> bool {
+        return sb_valid(sb);
+    }
+
+    fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
+        FileSystem::mkfs_with_options(path, sb, &MkfsOptions::default())
+    }
+
+    fn mountfs(dev: Device) -> Result<Self, Self::Error> {
+        FileSystem::mountfs_with_options(dev, &MountOptions::default())
+    }
+
+    fn unmountfs(self) -> Device {
+        // Errors while writing back are dropped, `unmountfs_checked` is the way to see them
+        match self.unmountfs_checked() {
+            Ok(device) => device,
+            Err((_, device)) => device,
+        }
+    }
+}
+
+impl BlockSupport for FileSystem {
+    fn b_get(&self, i: u64) -> Result<Block, Self::Error> {
+        let mut block = self.cached_read(i)?;
+        self.verify_block(&block)?;
+        if let Some(bitmap) = self.alloc_bitmap.as_ref() {
+            bitmap.overlay(&self.superblock, &mut block)?;
+        }
+        if i == 0 && self.groups_dirty {
+            fill_group_descs(&self.groups, &mut block)?;
+        }
+        Ok(block)
+    }
+
+    fn b_put(&mut self, b: &Block) -> Result<(), Self::Error> {
+        self.put_block_checksum(b, true)?;
+        self.cached_write(b)?;
+        if let Some(bitmap) = self.alloc_bitmap.as_mut() {
+            bitmap.absorb(&self.superblock, b);
+        }
+        Ok(())
+    }
+
+    fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        if i >= self.superblock.ndatablocks {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        let sb = self.superblock;
+        let allocated = self
+            .alloc_bitmap
+            .as_ref()
+            .ok_or_else(FileSystemError::DeviceNotSet)?
+            .get(i);
+        if !allocated {
+            return Err(FileSystemError::AllreadyFreeError());
+        }
+        self.in_op(|fs| {
+            // A block that is shared by several files stays allocated for the others
+            if fs.drop_ref(i)? {
+                return Ok(());
+            }
+            fs.alloc_bitmap
+                .as_mut()
+                .ok_or_else(FileSystemError::DeviceNotSet)?
+                .release(&sb, i);
+            if let Some(byte) = fs.scrub.freed(i) {
+                fs.fill_block(i, byte)?;
+            }
+            fs.write_bitmap_through()?;
+            fs.account_blocks(i, i + 1, true)
+        })
+    }
+
+    fn b_zero(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fill_block(i, 0)?;
+        self.scrub.zeroed(i);
+        Ok(())
+    }
+
+    fn b_alloc(&mut self) -> Result<u64, Self::Error> {
+        self.in_op(|fs| fs.b_alloc_from_group(0))
+    }
 
     fn sup_get(&self) -> Result<SuperBlock, Self::Error> {
         let block = self.b_get(0)?;
@@ -216,6 +1025,7 @@ impl InodeSupport for FileSystem {
         let block = get_inode_block(self, i, inodes_per_block)?;
         let block_inode_offset = i % inodes_per_block * *DINODE_SIZE;
 
+        self.verify_inode(i, &block, block_inode_offset, *DINODE_SIZE)?;
         let disk_node = block.deserialize_from::<DInode>(block_inode_offset)?;
 
         return Ok(Inode::new(i, disk_node));
@@ -228,6 +1038,7 @@ impl InodeSupport for FileSystem {
         let block_inode_offset = ino.inum % inodes_per_block * *DINODE_SIZE;
 
         block.serialize_into(&ino.disk_node, block_inode_offset)?;
+        self.put_inode_checksum(ino.inum, &block, block_inode_offset, *DINODE_SIZE)?;
 
         self.b_put(&block)?;
 
@@ -235,41 +1046,53 @@ impl InodeSupport for FileSystem {
     }
 
     fn i_free(&mut self, i: u64) -> Result<(), Self::Error> {
-        let mut ino = self.i_get(i)?;
+        self.in_op(|fs| {
+            let mut ino = fs.i_get(i)?;
 
-        if ino.disk_node.nlink == 0 && ino.inum > 0 {
-            trunc(self, ino.borrow_mut())?;
-            ino.disk_node.ft = FType::TFree;
-            self.i_put(&ino)?;
-            Ok(())
-        } else {
-            Err(FileSystemError::INodeNotFreeable())
-        }
+            if ino.disk_node.nlink == 0 && ino.inum > 0 {
+                let old_ft = ino.get_ft();
+                trunc(fs, ino.borrow_mut())?;
+                fs.free_xattrs(i)?;
+                fs.readahead.get_mut().forget(i);
+                fs.atimes.get_mut().remove(&i);
+                ino.disk_node.ft = FType::TFree;
+                fs.i_put(&ino)?;
+                if fs.is_grouped() && old_ft != FType::TFree {
+                    let g = group_of_inode(&fs.sb_ext, i);
+                    fs.groups[g].free_inodes += 1;
+                    if old_ft == FType::TDir {
+                        fs.groups[g].used_dirs -= 1;
+                    }
+                    fs.groups_dirty = true;
+                }
+                Ok(())
+            } else {
+                Err(FileSystemError::INodeNotFreeable())
+            }
+        })
     }
 
     fn i_alloc(&mut self, ft: FType) -> Result<u64, Self::Error> {
-        let inode_alloc_start = 1;
-        for i in inode_alloc_start..self.superblock.ninodes {
-            let mut ino = self.i_get(i)?;
-            if ino.get_ft() == FType::TFree {
-                ino.disk_node.ft = ft;
-                self.i_put(&ino)?;
-                return Ok(i);
-            }
-        }
-        return Err(FileSystemError::AllocationError());
+        // The trait does not tell which directory the inode is for, so with block groups it is
+        // placed as if it were an entry of the root directory. Callers that know the parent
+        // directory, like `mkdir` and `symlink`, use `i_alloc_near` instead.
+        self.i_alloc_near(ft, ROOT_INUM)
     }
 
     fn i_trunc(&mut self, inode: &mut Self::Inode) -> Result<(), Self::Error> {
-        let ino = self.i_get(inode.inum)?;
+        self.in_op(|fs| {
+            let ino = fs.i_get(inode.inum)?;
 
-        if &ino == inode {
-            trunc(self, inode)?;
-            self.i_put(&inode)?;
-        } else {
-        }
+            if &ino == inode {
+                trunc(fs, inode)?;
+                fs.reinline(inode)?;
+                fs.i_put(&inode)?;
+                fs.touch_modified(inode.inum)?;
+            } else {
+            }
 
-        Ok(())
+            Ok(())
+        })
     }
 }
 
@@ -0,0 +1,292 @@
+//! Word-at-a-time bitmap
+//!
+//! A `Bitmap` holds a sequence of bits in 64-bit words, so that searching for free bits or
+//! counting allocated ones handles 64 bits at a time instead of a single byte.
+//! The bit order matches the on-disk bitmap: bit *i* is bit `i % 8` of byte `i / 8`, which is
+//! exactly bit `i % 64` of the little-endian word `i / 64`.
+//! A bitmap can be built from (and written back into) the contents of a bitmap block, or from the
+//! concatenated contents of the whole bitmap region.
+
+const WORD_BITS: u64 = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A fixed-length sequence of bits
+pub struct Bitmap {
+    /// The bits, 64 at a time; bits past `nbits` in the last word are always 0
+    words: Vec<u64>,
+    /// Number of bits in this bitmap
+    nbits: u64,
+}
+
+impl Bitmap {
+    /// Creates a bitmap of `nbits` zero bits
+    pub fn new(nbits: u64) -> Bitmap {
+        Bitmap {
+            words: vec![0; ((nbits + WORD_BITS - 1) / WORD_BITS) as usize],
+            nbits,
+        }
+    }
+
+    /// Creates a bitmap of `nbits` bits from the first bytes of `bytes`
+    /// Bits past the end of `bytes` are 0
+    pub fn from_bytes(bytes: &[u8], nbits: u64) -> Bitmap {
+        let mut bitmap = Bitmap::new(nbits);
+        bitmap.read_bytes_at(0, bytes);
+        bitmap
+    }
+
+    /// Overwrites the bits starting at `first_bit` with the contents of `bytes`
+    /// `first_bit` has to be a multiple of 8. Bytes that fall past the end of the bitmap are ignored.
+    pub fn read_bytes_at(&mut self, first_bit: u64, bytes: &[u8]) {
+        debug_assert!(first_bit % 8 == 0);
+        for (i, &byte) in bytes.iter().enumerate() {
+            let bit = first_bit + i as u64 * 8;
+            if bit >= self.nbits {
+                break;
+            }
+            let w = (bit / WORD_BITS) as usize;
+            let shift = bit % WORD_BITS;
+            self.words[w] = (self.words[w] & !(0xff << shift)) | (u64::from(byte) << shift);
+        }
+        self.clear_tail();
+    }
+
+    /// Writes the bits of this bitmap into the first bytes of `bytes`
+    /// Bytes that are not covered by the bitmap are left alone, and so are the bits of the last
+    /// covered byte that lie past the end of the bitmap.
+    pub fn write_bytes(&self, bytes: &mut [u8]) {
+        self.write_bytes_at(0, bytes)
+    }
+
+    /// Same as `write_bytes`, but writes the bits starting at `first_bit`, which has to be a
+    /// multiple of 8
+    pub fn write_bytes_at(&self, first_bit: u64, bytes: &mut [u8]) {
+        debug_assert!(first_bit % 8 == 0);
+        let nbytes = ((self.nbits.saturating_sub(first_bit) + 7) / 8) as usize;
+        let nbytes = nbytes.min(bytes.len());
+        for (i, byte) in bytes[..nbytes].iter_mut().enumerate() {
+            let bit_start = first_bit + i as u64 * 8;
+            let word = self.words[(bit_start / WORD_BITS) as usize].to_le_bytes();
+            let value = word[(bit_start % WORD_BITS / 8) as usize];
+            if bit_start + 8 <= self.nbits {
+                *byte = value;
+            } else {
+                let keep = 0xffu8 << (self.nbits - bit_start);
+                *byte = (*byte & keep) | (value & !keep);
+            }
+        }
+    }
+
+    /// The bits of this bitmap as bytes, padded with zeroes to a whole byte
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; ((self.nbits + 7) / 8) as usize];
+        self.write_bytes(&mut bytes);
+        bytes
+    }
+
+    /// Number of bits in this bitmap
+    pub fn len(&self) -> u64 {
+        self.nbits
+    }
+
+    /// Whether this bitmap has no bits at all
+    pub fn is_empty(&self) -> bool {
+        self.nbits == 0
+    }
+
+    /// Value of bit `i`
+    pub fn get(&self, i: u64) -> bool {
+        debug_assert!(i < self.nbits);
+        self.words[(i / WORD_BITS) as usize] >> (i % WORD_BITS) & 1 == 1
+    }
+
+    /// Sets bit `i` to 1
+    pub fn set(&mut self, i: u64) {
+        debug_assert!(i < self.nbits);
+        self.words[(i / WORD_BITS) as usize] |= 1 << (i % WORD_BITS);
+    }
+
+    /// Sets bit `i` to 0
+    pub fn clear(&mut self, i: u64) {
+        debug_assert!(i < self.nbits);
+        self.words[(i / WORD_BITS) as usize] &= !(1 << (i % WORD_BITS));
+    }
+
+    /// Sets all bits in `[start, end)` to 1
+    pub fn set_range(&mut self, start: u64, end: u64) {
+        for (w, mask) in self.word_masks(start, end) {
+            self.words[w] |= mask;
+        }
+    }
+
+    /// Sets all bits in `[start, end)` to 0
+    pub fn clear_range(&mut self, start: u64, end: u64) {
+        for (w, mask) in self.word_masks(start, end) {
+            self.words[w] &= !mask;
+        }
+    }
+
+    /// Number of bits that are set
+    pub fn count_ones(&self) -> u64 {
+        self.words.iter().map(|w| u64::from(w.count_ones())).sum()
+    }
+
+    /// Number of bits that are set in `[start, end)`
+    pub fn count_ones_in(&self, start: u64, end: u64) -> u64 {
+        self.word_masks(start, end)
+            .map(|(w, mask)| u64::from((self.words[w] & mask).count_ones()))
+            .sum()
+    }
+
+    /// Index of the first 0 bit in `[start, end)`, if any
+    pub fn find_first_zero(&self, start: u64, end: u64) -> Option<u64> {
+        self.find_first(false, start, end)
+    }
+
+    /// Index of the first 1 bit in `[start, end)`, if any
+    pub fn find_first_one(&self, start: u64, end: u64) -> Option<u64> {
+        self.find_first(true, start, end)
+    }
+
+    /// Start of the first run of `n` consecutive 0 bits that lies entirely in `[start, end)`, if any
+    pub fn find_zero_run(&self, n: u64, start: u64, end: u64) -> Option<u64> {
+        let end = end.min(self.nbits);
+        let mut pos = start;
+        while pos + n <= end {
+            let run_start = self.find_first_zero(pos, end)?;
+            if run_start + n > end {
+                return None;
+            }
+            match self.find_first_one(run_start, run_start + n) {
+                None => return Some(run_start),
+                Some(one) => pos = one + 1,
+            }
+        }
+        None
+    }
+
+    /// Iterates over the indices of all bits that are set, in increasing order
+    pub fn iter_ones(&self) -> impl Iterator<Item = u64> + '_ {
+        self.words.iter().enumerate().flat_map(|(w, &word)| {
+            let mut rest = word;
+            std::iter::from_fn(move || {
+                if rest == 0 {
+                    return None;
+                }
+                let bit = u64::from(rest.trailing_zeros());
+                rest &= rest - 1;
+                Some(w as u64 * WORD_BITS + bit)
+            })
+        })
+    }
+
+    /// Shared implementation of `find_first_zero` and `find_first_one`
+    fn find_first(&self, value: bool, start: u64, end: u64) -> Option<u64> {
+        for (w, mask) in self.word_masks(start, end) {
+            let word = if value { self.words[w] } else { !self.words[w] };
+            let candidates = word & mask;
+            if candidates != 0 {
+                return Some(w as u64 * WORD_BITS + u64::from(candidates.trailing_zeros()));
+            }
+        }
+        None
+    }
+
+    /// Iterates over the words that overlap `[start, end)` (clipped to the length of the bitmap),
+    /// together with the mask of the bits of that word that lie in the range
+    fn word_masks(&self, start: u64, end: u64) -> impl Iterator<Item = (usize, u64)> {
+        let end = end.min(self.nbits);
+        let (first, last) = if start < end {
+            (
+                (start / WORD_BITS) as usize,
+                ((end - 1) / WORD_BITS) as usize + 1,
+            )
+        } else {
+            (0, 0)
+        };
+        (first..last).map(move |w| (w, range_mask(w, start, end)))
+    }
+
+    /// Makes sure the unused bits of the last word are 0
+    fn clear_tail(&mut self) {
+        let used = self.nbits % WORD_BITS;
+        if used != 0 {
+            if let Some(last) = self.words.last_mut() {
+                *last &= (1 << used) - 1;
+            }
+        }
+    }
+}
+
+/// Mask of the bits of word `w` that lie in `[start, end)`
+fn range_mask(w: usize, start: u64, end: u64) -> u64 {
+    let word_start = w as u64 * WORD_BITS;
+    let lo = start.saturating_sub(word_start);
+    let hi = (end - word_start).min(WORD_BITS);
+    let upper = if hi == WORD_BITS {
+        u64::MAX
+    } else {
+        (1u64 << hi) - 1
+    };
+    upper & !((1u64 << lo) - 1)
+}
+
+#[cfg(test)]
+mod bitmap_tests {
+    use super::Bitmap;
+
+    #[test]
+    fn bytes_roundtrip() {
+        let bytes = [0b0000_0101u8, 0xff, 0, 0, 0, 0, 0, 0, 0b1000_0000, 0xff];
+        let bitmap = Bitmap::from_bytes(&bytes, 75);
+        assert!(bitmap.get(0) && !bitmap.get(1) && bitmap.get(2));
+        assert!(bitmap.get(8) && bitmap.get(15) && !bitmap.get(16));
+        assert!(bitmap.get(71) && bitmap.get(72) && bitmap.get(74));
+        //bits past nbits are dropped
+        assert_eq!(bitmap.count_ones(), 2 + 8 + 1 + 3);
+
+        let mut out = vec![0xaa; 10];
+        bitmap.write_bytes(&mut out);
+        assert_eq!(&out[..9], &bytes[..9]);
+        assert_eq!(out[9], 0b1010_1111); //bits past the end are left alone
+
+        let mut bitmap = Bitmap::new(75);
+        bitmap.read_bytes_at(64, &bytes[8..]);
+        assert_eq!(bitmap.iter_ones().collect::<Vec<_>>(), vec![71, 72, 73, 74]);
+        let mut out = vec![0u8; 2];
+        bitmap.write_bytes_at(64, &mut out);
+        assert_eq!(out, vec![0b1000_0000, 0b0000_0111]);
+    }
+
+    #[test]
+    fn searching() {
+        let mut bitmap = Bitmap::new(200);
+        assert_eq!(bitmap.find_first_zero(0, 200), Some(0));
+        bitmap.set_range(0, 130);
+        assert_eq!(bitmap.find_first_zero(0, 200), Some(130));
+        assert_eq!(bitmap.find_first_zero(0, 130), None);
+        assert_eq!(bitmap.find_first_one(130, 200), None);
+        bitmap.set(140);
+        assert_eq!(bitmap.find_zero_run(10, 0, 200), Some(130));
+        assert_eq!(bitmap.find_zero_run(11, 0, 200), Some(141));
+        assert_eq!(bitmap.find_zero_run(11, 0, 150), None);
+        assert_eq!(bitmap.find_zero_run(9, 135, 150), Some(141));
+        bitmap.clear_range(60, 70);
+        assert_eq!(bitmap.find_first_zero(0, 200), Some(60));
+        assert_eq!(bitmap.count_ones(), 131 - 10);
+        assert_eq!(bitmap.count_ones_in(50, 141), 10 + 60 + 1);
+    }
+
+    #[test]
+    fn iterating() {
+        let mut bitmap = Bitmap::new(130);
+        for &i in &[0, 3, 63, 64, 127, 129] {
+            bitmap.set(i);
+        }
+        bitmap.clear(3);
+        assert_eq!(
+            bitmap.iter_ones().collect::<Vec<_>>(),
+            vec![0, 63, 64, 127, 129]
+        );
+    }
+}
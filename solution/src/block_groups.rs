@@ -0,0 +1,348 @@
+//! Optional ext2-style block groups
+//!
+//! When a file system is created with `MkfsOptions::blocks_per_group` set, the data region and the
+//! inode region are split up into `ngroups` consecutive slices, called block groups.
+//! Group *g* owns the data blocks `[g * blocks_per_group, (g + 1) * blocks_per_group)` (and hence
+//! the bits of the bitmap describing them) and the inodes `[g * inodes_per_group, (g + 1) * inodes_per_group)`.
+//! With `blocks_per_group` equal to `8 * block_size`, every group has a bitmap block of its own.
+//!
+//! Every group has a `GroupDesc` that keeps track of its free blocks, free inodes and directories.
+//! These descriptors are stored in block 0, right after the superblock and its extension.
+//! A mounted file system keeps them in memory. Allocations and frees only mark them dirty; they are
+//! written back to block 0 at the end of every operation, on `sync` and when unmounting. Without a
+//! log, a crash can thus leave stale counts behind, which `FileSystem::refresh_groups` recomputes.
+//! They are used to keep related data close together:
+//! - new directories are spread over the groups (`find_group_dir`)
+//! - new files are placed in the group of their parent directory (`find_group_other`)
+//! - data blocks are allocated in the group that holds the inode they belong to
+
+use cplfs_api::fs::{BlockSupport, InodeSupport};
+use cplfs_api::types::{Block, FType, InodeLike, SuperBlock, SUPERBLOCK_SIZE};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::b_inode_support::FileSystem;
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::count_free_data_blocks;
+use crate::superblock_ext::{SuperBlockExt, FEATURE_BLOCK_GROUPS, SB_EXT_SIZE};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Descriptor of a single block group
+pub struct GroupDesc {
+    /// Number of unallocated data blocks in this group
+    pub free_blocks: u64,
+    /// Number of free inodes in this group
+    pub free_inodes: u64,
+    /// Number of directory inodes in this group
+    pub used_dirs: u64,
+}
+
+lazy_static! {
+    /// Size of a group descriptor on disk, in bytes.
+    pub static ref GROUP_DESC_SIZE : u64 = bincode::serialize(&GroupDesc::default()).unwrap().len() as u64;
+}
+
+/// Computes the superblock extension describing a grouped layout with `blocks_per_group` data blocks per group
+pub fn new_groups_ext(
+    sb: &SuperBlock,
+    blocks_per_group: u64,
+) -> Result<SuperBlockExt, FileSystemError> {
+    if blocks_per_group == 0 {
+        return Err(FileSystemError::InvalidSuperBlock());
+    }
+    let ngroups = (sb.ndatablocks + blocks_per_group - 1) / blocks_per_group;
+    let ngroups = ngroups.max(1);
+    let inodes_per_group = (sb.ninodes + ngroups - 1) / ngroups;
+
+    let mut ext = SuperBlockExt::new();
+    ext.features |= FEATURE_BLOCK_GROUPS;
+    ext.blocks_per_group = blocks_per_group;
+    ext.inodes_per_group = inodes_per_group;
+    ext.ngroups = ngroups;
+    if groups_valid(sb, &ext) {
+        Ok(ext)
+    } else {
+        Err(FileSystemError::InvalidSuperBlock())
+    }
+}
+
+/// Checks whether the group geometry in the extension is consistent with the superblock
+pub fn groups_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    if ext.blocks_per_group == 0 || ext.inodes_per_group == 0 || ext.ngroups == 0 {
+        return false;
+    }
+    if ext.ngroups * ext.blocks_per_group < sb.ndatablocks
+        || (ext.ngroups - 1) * ext.blocks_per_group >= sb.ndatablocks.max(1)
+    {
+        return false;
+    }
+    if ext.ngroups * ext.inodes_per_group < sb.ninodes {
+        return false;
+    }
+    // All descriptors have to fit in block 0
+    group_desc_offset(ext.ngroups) <= sb.block_size
+}
+
+/// Offset in block 0 of the descriptor of group `g`
+pub fn group_desc_offset(g: u64) -> u64 {
+    *SUPERBLOCK_SIZE + *SB_EXT_SIZE + g * *GROUP_DESC_SIZE
+}
+
+/// Group that the data block with (relative) index `index` belongs to
+pub fn group_of_block(ext: &SuperBlockExt, index: u64) -> usize {
+    ((index / ext.blocks_per_group).min(ext.ngroups - 1)) as usize
+}
+
+/// Group that inode `inum` belongs to
+pub fn group_of_inode(ext: &SuperBlockExt, inum: u64) -> usize {
+    ((inum / ext.inodes_per_group).min(ext.ngroups - 1)) as usize
+}
+
+/// Range of (relative) data block indices owned by group `g`
+pub fn group_block_range(sb: &SuperBlock, ext: &SuperBlockExt, g: usize) -> (u64, u64) {
+    let start = g as u64 * ext.blocks_per_group;
+    let end = (start + ext.blocks_per_group).min(sb.ndatablocks);
+    (start, end)
+}
+
+/// Range of inode numbers owned by group `g`
+pub fn group_inode_range(sb: &SuperBlock, ext: &SuperBlockExt, g: usize) -> (u64, u64) {
+    let start = g as u64 * ext.inodes_per_group;
+    let end = (start + ext.inodes_per_group).min(sb.ninodes);
+    (start, end)
+}
+
+/// Picks a group for a new directory: among the groups with at least an average number of free
+/// inodes, the one with the most free blocks. This spreads directories over the disk.
+pub fn find_group_dir(groups: &[GroupDesc]) -> Option<usize> {
+    if groups.is_empty() {
+        return None;
+    }
+    let total_free: u64 = groups.iter().map(|d| d.free_inodes).sum();
+    let avg = total_free / groups.len() as u64;
+
+    let mut best: Option<usize> = None;
+    for (g, desc) in groups.iter().enumerate() {
+        if desc.free_inodes == 0 || desc.free_inodes < avg {
+            continue;
+        }
+        match best {
+            Some(b) if groups[b].free_blocks >= desc.free_blocks => {}
+            _ => best = Some(g),
+        }
+    }
+    best.or_else(|| groups.iter().position(|d| d.free_inodes > 0))
+}
+
+/// Picks a group for a new non-directory inode: the group of its parent if that has room,
+/// otherwise a quadratic probe followed by a linear search starting from the parent group.
+pub fn find_group_other(groups: &[GroupDesc], parent_group: usize) -> Option<usize> {
+    let n = groups.len();
+    if n == 0 {
+        return None;
+    }
+    let has_room = |g: usize| groups[g].free_inodes > 0 && groups[g].free_blocks > 0;
+    let parent_group = parent_group % n;
+    if has_room(parent_group) {
+        return Some(parent_group);
+    }
+
+    let mut step = 1;
+    while step < n {
+        let g = (parent_group + step) % n;
+        if has_room(g) {
+            return Some(g);
+        }
+        step <<= 1;
+    }
+
+    (0..n)
+        .map(|k| (parent_group + k) % n)
+        .find(|&g| groups[g].free_inodes > 0)
+}
+
+/// Reads all group descriptors from block 0
+pub fn load_group_descs(fs: &FileSystem) -> Result<Vec<GroupDesc>, FileSystemError> {
+    let block = fs.b_get(0)?;
+    let mut groups = Vec::with_capacity(fs.sb_ext.ngroups as usize);
+    for g in 0..fs.sb_ext.ngroups {
+        groups.push(block.deserialize_from::<GroupDesc>(group_desc_offset(g))?);
+    }
+    Ok(groups)
+}
+
+/// Puts the group descriptors `groups` into `block`, which has to be block 0
+pub fn fill_group_descs(groups: &[GroupDesc], block: &mut Block) -> Result<(), FileSystemError> {
+    for (g, desc) in groups.iter().enumerate() {
+        block.serialize_into(desc, group_desc_offset(g as u64))?;
+    }
+    Ok(())
+}
+
+/// Writes the in-memory group descriptors of the file system back into block 0
+pub fn store_group_descs(fs: &mut FileSystem) -> Result<(), FileSystemError> {
+    let mut block = fs.b_get(0)?;
+    fill_group_descs(&fs.groups, &mut block)?;
+    fs.b_put(&block)?;
+    fs.groups_dirty = false;
+    Ok(())
+}
+
+/// Recomputes the group descriptors from the bitmap and the inode region
+pub fn recount_group_descs(fs: &FileSystem) -> Result<Vec<GroupDesc>, FileSystemError> {
+    let sb = fs.superblock;
+    let ext = fs.sb_ext;
+    let mut groups = vec![GroupDesc::default(); ext.ngroups as usize];
+
+    for (g, desc) in groups.iter_mut().enumerate() {
+        let (start, end) = group_block_range(&sb, &ext, g);
+        desc.free_blocks = count_free_data_blocks(fs, &sb, start, end)?;
+        let (start, end) = group_inode_range(&sb, &ext, g);
+        // Inode 0 is never handed out, so it does not count as free
+        for inum in start.max(1)..end {
+            match fs.i_get(inum)?.get_ft() {
+                FType::TFree => desc.free_inodes += 1,
+                FType::TDir => desc.used_dirs += 1,
+                FType::TFile => {}
+            }
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::{find_group_dir, find_group_other, group_desc_offset, GroupDesc};
+    use crate::b_inode_support::FileSystem;
+    use crate::options::{MkfsOptions, MountOptions};
+    use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeSupport};
+    use cplfs_api::types::{FType, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn grouped() -> MkfsOptions {
+        MkfsOptions {
+            blocks_per_group: Some(8),
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-groups-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn group_choice() {
+        let desc = |free_blocks, free_inodes| GroupDesc {
+            free_blocks,
+            free_inodes,
+            used_dirs: 0,
+        };
+        let groups = [desc(1, 4), desc(8, 4), desc(8, 1), desc(0, 4)];
+        assert_eq!(find_group_dir(&groups), Some(1));
+        assert_eq!(find_group_other(&groups, 0), Some(0));
+        assert_eq!(find_group_other(&groups, 3), Some(0)); //3 is full, quadratic probe wraps to 0
+        assert_eq!(find_group_other(&[desc(0, 0), desc(0, 1)], 0), Some(1));
+        assert_eq!(find_group_other(&[desc(3, 0)], 0), None);
+    }
+
+    #[test]
+    fn locality() {
+        let path = disk_prep_path("locality");
+        let mut my_fs = FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &grouped()).unwrap();
+        assert_eq!(my_fs.groups.len(), 4);
+        assert_eq!(my_fs.sb_ext.inodes_per_group, 4);
+
+        //Directories get spread, files stay with their parent
+        let d1 = my_fs.i_alloc(FType::TDir).unwrap();
+        let d2 = my_fs.i_alloc(FType::TDir).unwrap();
+        assert_ne!(d1 / 4, d2 / 4);
+        let f1 = my_fs.i_alloc_near(FType::TFile, d2).unwrap();
+        assert_eq!(f1 / 4, d2 / 4);
+
+        //Data blocks go to the group of their inode
+        let b1 = my_fs.b_alloc_near(f1).unwrap();
+        assert_eq!(b1 / 8, f1 / 4);
+        let g = (f1 / 4) as usize;
+        assert_eq!(my_fs.groups[g].free_blocks, 7);
+        assert_eq!(my_fs.groups[g].used_dirs, 1);
+
+        //Descriptors survive a remount
+        let groups = my_fs.groups.clone();
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(my_fs.groups, groups);
+
+        my_fs.b_free(b1).unwrap();
+        my_fs.i_free(f1).unwrap();
+        assert_eq!(my_fs.groups[g].free_blocks, 8);
+        assert_eq!(my_fs.groups[g].free_inodes, groups[g].free_inodes + 1);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn lazy_descriptors() {
+        let path = disk_prep_path("lazy_descriptors");
+        let my_fs = FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &grouped()).unwrap();
+        let dev = my_fs.unmountfs();
+        let options = MountOptions {
+            cache_blocks: 0,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+        let desc_on_disk = |fs: &FileSystem, g: u64| {
+            let dev = fs.device.as_ref().unwrap();
+            let block = dev.read_block(0).unwrap();
+            block
+                .deserialize_from::<GroupDesc>(group_desc_offset(g))
+                .unwrap()
+        };
+
+        //Allocations leave block 0 alone, but reads of it see the new counts
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+        assert_eq!(desc_on_disk(&my_fs, 0).free_blocks, 8);
+        let block = my_fs.b_get(0).unwrap();
+        let desc = block
+            .deserialize_from::<GroupDesc>(group_desc_offset(0))
+            .unwrap();
+        assert_eq!(desc.free_blocks, 6);
+
+        my_fs.sync().unwrap();
+        assert_eq!(desc_on_disk(&my_fs, 0), my_fs.groups[0]);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn full_group_spills() {
+        let path = disk_prep_path("spill");
+        let mut my_fs = FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &grouped()).unwrap();
+        for i in 0..8 {
+            assert_eq!(my_fs.b_alloc_near(1).unwrap(), i);
+        }
+        assert_eq!(my_fs.b_alloc_near(1).unwrap(), 8);
+        assert_eq!(my_fs.groups[0].free_blocks, 0);
+        assert_eq!(my_fs.groups[1].free_blocks, 7);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
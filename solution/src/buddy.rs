@@ -0,0 +1,159 @@
+//! Buddy allocator for the data region
+//!
+//! The free data blocks are kept as aligned runs of `2^order` blocks, one free list per order.
+//! An allocation of order *k* takes the lowest run of the smallest order >= *k* and splits it in
+//! halves until a run of order *k* remains. When a run is freed and its buddy (the other half of
+//! the run of order *k + 1* that contains it) is free as well, both are merged, and so on upwards.
+//! Freeing the blocks of a run one by one merges them back into the original run as well.
+//!
+//! The allocator only indexes free space: the allocation bitmap stays the state that is stored on
+//! disk, and the allocator is rebuilt from it when the file system is mounted.
+
+use std::collections::BTreeSet;
+
+use crate::bitmap::Bitmap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Free lists of a buddy allocator over `nblocks` blocks
+pub struct BuddyAllocator {
+    /// Number of blocks that are managed
+    nblocks: u64,
+    /// `free[k]` holds the first block of every free run of `2^k` blocks
+    free: Vec<BTreeSet<u64>>,
+}
+
+impl BuddyAllocator {
+    /// Builds the free lists from an allocation bitmap, in which a set bit marks a used block
+    pub fn from_bitmap(bits: &Bitmap) -> BuddyAllocator {
+        let nblocks = bits.len();
+        let max_order = if nblocks == 0 {
+            0
+        } else {
+            63 - nblocks.leading_zeros() as usize
+        };
+        let mut buddy = BuddyAllocator {
+            nblocks,
+            free: vec![BTreeSet::new(); max_order + 1],
+        };
+
+        let mut pos = 0;
+        while let Some(start) = bits.find_first_zero(pos, nblocks) {
+            let end = bits.find_first_one(start, nblocks).unwrap_or(nblocks);
+            let mut run = start;
+            while run < end {
+                let order = buddy.largest_order_at(run, end);
+                buddy.free[order].insert(run);
+                run += 1 << order;
+            }
+            pos = end;
+        }
+        buddy
+    }
+
+    /// Largest order that can be allocated at all
+    pub fn max_order(&self) -> usize {
+        self.free.len() - 1
+    }
+
+    /// Allocates a run of `2^order` blocks and returns its first block
+    pub fn alloc(&mut self, order: usize) -> Option<u64> {
+        let mut k = (order..self.free.len()).find(|&k| !self.free[k].is_empty())?;
+        let start = *self.free[k].iter().next()?;
+        self.free[k].remove(&start);
+        while k > order {
+            k -= 1;
+            self.free[k].insert(start + (1 << k));
+        }
+        Some(start)
+    }
+
+    /// Frees the run of `2^order` blocks starting at `start`, merging it with its free buddies
+    pub fn free(&mut self, start: u64, order: usize) {
+        debug_assert!(start + (1 << order) <= self.nblocks);
+        let mut start = start;
+        let mut order = order;
+        while order < self.max_order() {
+            let buddy = start ^ (1 << order);
+            if !self.free[order].remove(&buddy) {
+                break;
+            }
+            start = start.min(buddy);
+            order += 1;
+        }
+        self.free[order].insert(start);
+    }
+
+    /// Total number of free blocks
+    pub fn free_blocks(&self) -> u64 {
+        self.free
+            .iter()
+            .enumerate()
+            .map(|(k, list)| (list.len() as u64) << k)
+            .sum()
+    }
+
+    /// Order of the largest free run, if any block is free
+    pub fn largest_free_order(&self) -> Option<usize> {
+        self.free.iter().rposition(|list| !list.is_empty())
+    }
+
+    /// Largest order of an aligned run that starts at `run` and ends before `end`
+    fn largest_order_at(&self, run: u64, end: u64) -> usize {
+        let aligned = if run == 0 {
+            self.max_order()
+        } else {
+            (run.trailing_zeros() as usize).min(self.max_order())
+        };
+        (0..=aligned)
+            .rev()
+            .find(|&k| run + (1 << k) <= end)
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod buddy_tests {
+    use super::BuddyAllocator;
+    use crate::bitmap::Bitmap;
+
+    #[test]
+    fn split_and_merge() {
+        let mut buddy = BuddyAllocator::from_bitmap(&Bitmap::new(16));
+        assert_eq!(buddy.max_order(), 4);
+        assert_eq!(buddy.alloc(0), Some(0));
+        assert_eq!(buddy.alloc(2), Some(4));
+        assert_eq!(buddy.alloc(1), Some(2));
+        assert_eq!(buddy.alloc(0), Some(1));
+        assert_eq!(buddy.alloc(3), Some(8));
+        assert_eq!(buddy.alloc(0), None);
+        assert_eq!(buddy.free_blocks(), 0);
+
+        //Freeing the blocks one by one merges everything back together
+        for i in 0..16 {
+            buddy.free(i, 0);
+        }
+        assert_eq!(buddy.largest_free_order(), Some(4));
+        assert_eq!(buddy.free_blocks(), 16);
+    }
+
+    #[test]
+    fn from_bitmap() {
+        let mut bits = Bitmap::new(21);
+        bits.set_range(0, 3);
+        bits.set(12);
+        let mut buddy = BuddyAllocator::from_bitmap(&bits);
+        assert_eq!(buddy.free_blocks(), 21 - 4);
+        //free runs: [3,4) [4,8) [8,12) [13,14) [14,16) [16,20) [20,21)
+        assert_eq!(buddy.largest_free_order(), Some(2));
+        assert_eq!(buddy.alloc(2), Some(4));
+        assert_eq!(buddy.alloc(1), Some(14));
+        assert_eq!(buddy.alloc(0), Some(3));
+        assert_eq!(buddy.alloc(3), None);
+
+        //A run is only merged with a buddy that is free
+        buddy.free(14, 1);
+        assert_eq!(buddy.alloc(2), Some(8));
+        assert_eq!(buddy.alloc(2), Some(16));
+        assert_eq!(buddy.alloc(2), None);
+    }
+}
@@ -0,0 +1,358 @@
+//! Write-back buffer cache
+//!
+//! A `BufferCache` sits between the `BlockSupport` methods of a `FileSystem` and its `Device`.
+//! It keeps up to `capacity` blocks in memory, so hot blocks (block 0, inode blocks, directory
+//! blocks) do not have to be copied out of the device on every access.
+//! - Writes only update the cached copy and mark it dirty. Dirty blocks reach the device when
+//!   they are evicted, and on `flush` (called by `sync` and when unmounting).
+//! - When the cache is full, the least recently used block is evicted.
+//!   Reads only have shared access to the device, so they cannot write a dirty block back: a read
+//!   miss evicts the least recently used *clean* block instead, or is not cached at all if every
+//!   cached block is dirty.
+//!
+//! A capacity of 0 turns the cache off: every access goes straight to the device.
+
+use std::collections::{BTreeMap, HashMap};
+
+use cplfs_api::controller::Device;
+use cplfs_api::types::Block;
+
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::{read_block, write_block};
+
+/// Number of blocks that are cached when the mount options do not say otherwise
+pub const DEFAULT_CACHE_BLOCKS: usize = 64;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Counters describing how well the cache is doing
+pub struct CacheStats {
+    /// Reads and writes of a block that was cached
+    pub hits: u64,
+    /// Reads and writes of a block that was not cached
+    pub misses: u64,
+    /// Blocks that were dropped from the cache to make room for another one
+    pub evictions: u64,
+    /// Dirty blocks that were written to the device
+    pub writebacks: u64,
+    /// Blocks that were read into the cache ahead of time
+    pub prefetches: u64,
+}
+
+#[derive(Debug, Clone)]
+/// A cached block
+struct CacheEntry {
+    /// Contents of the block
+    data: Box<[u8]>,
+    /// Whether the contents differ from the block on the device
+    dirty: bool,
+    /// Time of the last access, the key of this block in `BufferCache::lru`
+    stamp: u64,
+}
+
+#[derive(Debug, Clone)]
+/// Fixed-capacity, write-back cache of device blocks with LRU eviction
+pub struct BufferCache {
+    /// Maximum number of cached blocks
+    capacity: usize,
+    /// The cached blocks, by block number
+    entries: HashMap<u64, CacheEntry>,
+    /// Block numbers of the cached blocks, by time of last access
+    lru: BTreeMap<u64, u64>,
+    /// Logical clock used to stamp accesses
+    clock: u64,
+    /// Hit and miss counters
+    stats: CacheStats,
+}
+
+impl BufferCache {
+    /// Creates an empty cache that holds at most `capacity` blocks
+    pub fn new(capacity: usize) -> BufferCache {
+        BufferCache {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Maximum number of cached blocks
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of cached blocks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Whether no blocks are cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of cached blocks that have not been written to the device yet
+    pub fn dirty_len(&self) -> usize {
+        self.entries.values().filter(|e| e.dirty).count()
+    }
+
+    /// Hit and miss counters since the cache was created
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// Reads block `i`, from the cache if possible
+    pub fn read(&mut self, dev: &Device, i: u64) -> Result<Block, FileSystemError> {
+        if let Some(entry) = self.entries.get(&i) {
+            let data = entry.data.clone();
+            self.stats.hits += 1;
+            self.touch(i);
+            return Ok(Block::new(i, data));
+        }
+
+        self.stats.misses += 1;
+        let block = read_block(dev, i)?;
+        if self.capacity > 0 && self.make_room_clean() {
+            self.insert(i, block.contents_as_ref().into(), false);
+        }
+        Ok(block)
+    }
+
+    /// Reads block `i` into the cache ahead of time, if it is not cached yet and there is room
+    /// for it without writing anything back
+    pub fn prefetch(&mut self, dev: &Device, i: u64) -> Result<(), FileSystemError> {
+        if self.capacity == 0 || self.entries.contains_key(&i) || !self.make_room_clean() {
+            return Ok(());
+        }
+        let block = read_block(dev, i)?;
+        self.insert(i, block.contents_as_ref().into(), false);
+        self.stats.prefetches += 1;
+        Ok(())
+    }
+
+    /// Writes block `b` into the cache, evicting another block if needed
+    pub fn write(&mut self, dev: &mut Device, b: &Block) -> Result<(), FileSystemError> {
+        // Let the device report blocks it would not accept, instead of failing on write back
+        if self.capacity == 0 || b.len() != dev.block_size || b.block_no >= dev.nblocks {
+            return write_block(dev, b);
+        }
+
+        if let Some(entry) = self.entries.get_mut(&b.block_no) {
+            entry.data.copy_from_slice(b.contents_as_ref());
+            entry.dirty = true;
+            self.stats.hits += 1;
+            self.touch(b.block_no);
+            return Ok(());
+        }
+
+        self.stats.misses += 1;
+        if self.entries.len() >= self.capacity {
+            self.evict_lru(dev)?;
+        }
+        self.insert(b.block_no, b.contents_as_ref().into(), true);
+        Ok(())
+    }
+
+    /// Writes all dirty blocks to the device, in block order. They stay cached.
+    pub fn flush(&mut self, dev: &mut Device) -> Result<(), FileSystemError> {
+        let mut dirty: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.dirty)
+            .map(|(&i, _)| i)
+            .collect();
+        dirty.sort_unstable();
+        for i in dirty {
+            let entry = self.entries.get_mut(&i).unwrap();
+            write_block(dev, &Block::new(i, entry.data.clone()))?;
+            entry.dirty = false;
+            self.stats.writebacks += 1;
+        }
+        Ok(())
+    }
+
+    /// Marks the cached copy of block `i` clean, and returns its contents if it was dirty, so the
+    /// caller can write it to the device itself
+    pub fn take_dirty(&mut self, i: u64) -> Option<Block> {
+        let entry = self.entries.get_mut(&i).filter(|e| e.dirty)?;
+        entry.dirty = false;
+        self.stats.writebacks += 1;
+        Some(Block::new(i, entry.data.clone()))
+    }
+
+    /// Replaces the cached copy of block `b`, if any, by `b`, which was just written to the device
+    /// behind the cache's back. The copy is clean afterwards.
+    pub fn install(&mut self, b: &Block) {
+        if let Some(entry) = self.entries.get_mut(&b.block_no) {
+            entry.data.copy_from_slice(b.contents_as_ref());
+            entry.dirty = false;
+        }
+    }
+
+    /// Marks block `i` as the most recently used one
+    fn touch(&mut self, i: u64) {
+        self.clock += 1;
+        let entry = self.entries.get_mut(&i).unwrap();
+        self.lru.remove(&entry.stamp);
+        entry.stamp = self.clock;
+        self.lru.insert(self.clock, i);
+    }
+
+    /// Adds block `i`, which is not cached yet, as the most recently used one
+    fn insert(&mut self, i: u64, data: Box<[u8]>, dirty: bool) {
+        self.clock += 1;
+        let stamp = self.clock;
+        self.entries.insert(i, CacheEntry { data, dirty, stamp });
+        self.lru.insert(stamp, i);
+    }
+
+    /// Evicts the least recently used block, writing it back first if it is dirty
+    fn evict_lru(&mut self, dev: &mut Device) -> Result<(), FileSystemError> {
+        if let Some((&stamp, &i)) = self.lru.iter().next() {
+            let entry = &self.entries[&i];
+            if entry.dirty {
+                write_block(dev, &Block::new(i, entry.data.clone()))?;
+                self.stats.writebacks += 1;
+            }
+            self.lru.remove(&stamp);
+            self.entries.remove(&i);
+            self.stats.evictions += 1;
+        }
+        Ok(())
+    }
+
+    /// Makes room for one more block without writing anything back
+    /// Returns false if the cache is full of dirty blocks
+    fn make_room_clean(&mut self) -> bool {
+        if self.entries.len() < self.capacity {
+            return true;
+        }
+        let victim = self
+            .lru
+            .iter()
+            .find(|(_, i)| !self.entries[*i].dirty)
+            .map(|(&stamp, &i)| (stamp, i));
+        match victim {
+            Some((stamp, i)) => {
+                self.lru.remove(&stamp);
+                self.entries.remove(&i);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::{BufferCache, CacheStats};
+    use crate::b_inode_support::FileSystem;
+    use crate::options::MountOptions;
+    use cplfs_api::fs::{BlockSupport, FileSysSupport};
+    use cplfs_api::types::SuperBlock;
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-buffer-cache-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn lru_write_back() {
+        let path = disk_prep_path("lru_write_back");
+        let mut dev = utils::disk_setup(&path, BLOCK_SIZE, NBLOCKS);
+        let mut cache = BufferCache::new(2);
+
+        cache
+            .write(&mut dev, &utils::n_block(1, BLOCK_SIZE, 1))
+            .unwrap();
+        cache
+            .write(&mut dev, &utils::n_block(2, BLOCK_SIZE, 2))
+            .unwrap();
+        assert_eq!(dev.read_block(1).unwrap(), utils::zero_block(1, BLOCK_SIZE));
+        assert_eq!(
+            cache.read(&dev, 1).unwrap(),
+            utils::n_block(1, BLOCK_SIZE, 1)
+        );
+        assert_eq!(cache.dirty_len(), 2);
+
+        //Block 2 is the least recently used one, so it is written back and evicted
+        cache
+            .write(&mut dev, &utils::n_block(3, BLOCK_SIZE, 3))
+            .unwrap();
+        assert_eq!(dev.read_block(2).unwrap(), utils::n_block(2, BLOCK_SIZE, 2));
+        assert_eq!(dev.read_block(3).unwrap(), utils::zero_block(3, BLOCK_SIZE));
+
+        //A read miss does not get cached when only dirty blocks could make room
+        assert_eq!(
+            cache.read(&dev, 2).unwrap(),
+            utils::n_block(2, BLOCK_SIZE, 2)
+        );
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 4,
+                evictions: 1,
+                writebacks: 1,
+                prefetches: 0,
+            }
+        );
+
+        cache.flush(&mut dev).unwrap();
+        assert_eq!(cache.dirty_len(), 0);
+        assert_eq!(dev.read_block(1).unwrap(), utils::n_block(1, BLOCK_SIZE, 1));
+        assert_eq!(dev.read_block(3).unwrap(), utils::n_block(3, BLOCK_SIZE, 3));
+        //Now a clean block can make room
+        cache.read(&dev, 4).unwrap();
+        assert_eq!(cache.stats().evictions, 2);
+        assert!(cache.write(&mut dev, &utils::n_block(2, 10, 2)).is_err());
+
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn file_system_cache() {
+        let path = disk_prep_path("file_system_cache");
+        let dev = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD)
+            .unwrap()
+            .unmountfs();
+        let options = MountOptions {
+            cache_blocks: 4,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+        my_fs.sup_get().unwrap();
+        let stats = my_fs.cache_stats();
+        my_fs.sup_get().unwrap();
+        assert_eq!(my_fs.cache_stats().hits, stats.hits + 1);
+        assert_eq!(my_fs.cache_stats().misses, stats.misses);
+
+        let b = utils::n_block(SUPERBLOCK_GOOD.datastart, BLOCK_SIZE, 6);
+        my_fs.b_put(&b).unwrap();
+        assert_eq!(my_fs.b_get(SUPERBLOCK_GOOD.datastart).unwrap(), b);
+        my_fs.sync().unwrap();
+        assert_eq!(my_fs.cache.borrow().dirty_len(), 0);
+
+        let dev = my_fs.unmountfs();
+        assert_eq!(dev.read_block(SUPERBLOCK_GOOD.datastart).unwrap(), b);
+        utils::disk_destruct(dev);
+    }
+}
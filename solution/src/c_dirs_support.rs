@@ -27,12 +27,17 @@
 
 use crate::b_inode_support::FileSystem;
 use crate::helpers::{
-    allocate_bitmapregion, allocate_dataregion, allocate_inoderegionblocks, allocate_inodes,
     allocate_rootdirectory, get_direntries, is_valid_dirname, sb_valid, to_char_array, write_dir,
-    write_sb,
 };
+use crate::options::{MkfsOptions, MountOptions};
+use crate::buffer_cache::CacheStats;
+use crate::clock::Clock;
+use crate::inode_ext::InodeExt;
+use crate::space::{AllocContext, StatFs};
 use cplfs_api::fs::{BlockSupport, DirectorySupport, FileSysSupport, InodeSupport};
-use cplfs_api::types::{Block, DirEntry, FType, Inode, InodeLike, SuperBlock, DIRNAME_SIZE};
+use cplfs_api::types::{
+    Block, DirEntry, FType, Inode, InodeLike, SuperBlock, DIRNAME_SIZE, ROOT_INUM,
+};
 
 use crate::filesystem_errors::FileSystemError;
 
@@ -61,6 +66,109 @@ impl FileSystemC {
     pub fn create_filesystem(fs: FileSystem) -> FileSystemC {
         FileSystemC { fs }
     }
+
+    /// Same as `mkfs`, but lets the caller pick some layout options
+    pub fn mkfs_with_options<P: AsRef<Path>>(
+        path: P,
+        sb: &SuperBlock,
+        options: &MkfsOptions,
+    ) -> Result<Self, FileSystemError> {
+        let fs = FileSystem::mkfs_with_options(path, sb, options)?;
+        let mut fs_c = FileSystemC::create_filesystem(fs);
+        allocate_rootdirectory(&mut fs_c.fs)?;
+        fs_c.fs.touch_created(ROOT_INUM, FType::TDir)?;
+        fs_c.fs.refresh_groups()?;
+        Ok(fs_c)
+    }
+
+    /// Same as `mountfs`, but lets the caller pick some mount options
+    pub fn mountfs_with_options(
+        dev: Device,
+        options: &MountOptions,
+    ) -> Result<Self, FileSystemError> {
+        let fs = FileSystem::mountfs_with_options(dev, options)?;
+        Ok(FileSystemC::create_filesystem(fs))
+    }
+
+    /// Writes all pending changes to the device, see `FileSystem::sync`
+    pub fn sync(&mut self) -> Result<(), FileSystemError> {
+        self.fs.sync()
+    }
+
+    /// Unmounts the file system, reporting write errors, see `FileSystem::unmountfs_checked`
+    pub fn unmountfs_checked(self) -> Result<Device, (FileSystemError, Device)> {
+        self.fs.unmountfs_checked()
+    }
+
+    /// Zeroes at most `max` freed data blocks that are queued for scrubbing, see
+    /// `FileSystem::scrub_freed`
+    pub fn scrub_freed(&mut self, max: usize) -> Result<usize, FileSystemError> {
+        self.fs.scrub_freed(max)
+    }
+
+    /// Runs `f` with allocations in the privileged context, see `FileSystem::as_privileged`
+    pub fn as_privileged<R>(&mut self, f: impl FnOnce(&mut Self) -> R) -> R {
+        let old = self.fs.alloc_context;
+        self.fs.alloc_context = AllocContext::Privileged;
+        let result = f(self);
+        self.fs.alloc_context = old;
+        result
+    }
+
+    /// Hit and miss counters of the buffer cache, see `FileSystem::cache_stats`
+    pub fn cache_stats(&self) -> CacheStats {
+        self.fs.cache_stats()
+    }
+
+    /// Reports the used and free space of this file system, see `FileSystem::statfs`
+    pub fn statfs(&self) -> Result<StatFs, FileSystemError> {
+        self.fs.statfs()
+    }
+
+    /// Allocates a data block close to inode `inum`, see `FileSystem::b_alloc_near`
+    pub fn b_alloc_near(&mut self, inum: u64) -> Result<u64, FileSystemError> {
+        self.fs.b_alloc_near(inum)
+    }
+
+    /// Allocates a run of `2^order` contiguous data blocks, see `FileSystem::b_alloc_run`
+    pub fn b_alloc_run(&mut self, order: usize) -> Result<u64, FileSystemError> {
+        self.fs.b_alloc_run(order)
+    }
+
+    /// Allocates an inode close to its parent directory, see `FileSystem::i_alloc_near`
+    pub fn i_alloc_near(&mut self, ft: FType, parent: u64) -> Result<u64, FileSystemError> {
+        self.fs.i_alloc_near(ft, parent)
+    }
+
+    /// Replaces the clock that timestamps inodes, see `FileSystem::set_clock`
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.fs.set_clock(clock)
+    }
+
+    /// Reads the extension record of inode `inum`, see `FileSystem::i_ext_get`
+    pub fn i_ext_get(&self, inum: u64) -> Result<InodeExt, FileSystemError> {
+        self.fs.i_ext_get(inum)
+    }
+
+    /// Starts a file system operation, see `FileSystem::begin_op`
+    pub fn begin_op(&mut self) {
+        self.fs.begin_op()
+    }
+
+    /// Ends a file system operation, see `FileSystem::end_op`
+    pub fn end_op(&mut self) -> Result<(), FileSystemError> {
+        self.fs.end_op()
+    }
+
+    /// Runs `f` as a single file system operation, see `FileSystem::in_op`
+    pub fn in_op<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, FileSystemError>,
+    ) -> Result<R, FileSystemError> {
+        let snapshot = self.fs.start_op();
+        let result = f(self);
+        self.fs.finish_op(snapshot, result)
+    }
 }
 
 impl FileSysSupport for FileSystemC {
@@ -71,47 +179,11 @@ impl FileSysSupport for FileSystemC {
     }
 
     fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
-        if !FSName::sb_valid(sb) {
-            Err(FileSystemError::InvalidSuperBlock())
-        } else {
-            let device_result = Device::new(path, sb.block_size, sb.nblocks);
-
-            match device_result {
-                Ok(mut device) => {
-                    //place superblock at index 0
-
-                    write_sb(sb, &mut device)?;
-                    allocate_inoderegionblocks(&sb, &mut device)?;
-                    allocate_bitmapregion(&sb, &mut device)?;
-                    allocate_dataregion(&sb, &mut device)?;
-                    let mut fs_c = FSName::mountfs(device)?;
-
-                    allocate_inodes(&mut fs_c.fs)?;
-                    allocate_rootdirectory(&mut fs_c.fs)?;
-                    Ok(fs_c)
-                }
-                Err(e) => Err(FileSystemError::DeviceAPIError(e)),
-            }
-        }
+        FileSystemC::mkfs_with_options(path, sb, &MkfsOptions::default())
     }
 
     fn mountfs(dev: Device) -> Result<Self, Self::Error> {
-        match dev.read_block(0) {
-            Ok(block) => {
-                let sb = &block.deserialize_from::<SuperBlock>(0)?;
-                if FSName::sb_valid(sb)
-                    && dev.block_size == sb.block_size
-                    && dev.nblocks == sb.nblocks
-                {
-                    let fs = FileSystem::create_filesystem(*sb, Some(dev));
-                    let fs_c = FileSystemC::create_filesystem(fs);
-                    Ok(fs_c)
-                } else {
-                    Err(FileSystemError::InvalidSuperBlock())
-                }
-            }
-            Err(e) => Err(FileSystemError::DeviceAPIError(e)),
-        }
+        FileSystemC::mountfs_with_options(dev, &MountOptions::default())
     }
 
     fn unmountfs(self) -> Device {
@@ -256,17 +328,25 @@ impl DirectorySupport for FileSystemC {
             return Err(FileSystemError::INodeNotADirectory());
         }
 
-        if inum != inode.inum {
-            let mut d_inode = self.i_get(inum)?;
-            if d_inode.get_ft() == FType::TFree {
-                return Err(FileSystemError::INodeNotADirectory());
+        self.in_op(|fs| {
+            if inum != inode.inum {
+                let mut d_inode = fs.i_get(inum)?;
+                if d_inode.get_ft() == FType::TFree {
+                    return Err(FileSystemError::INodeNotADirectory());
+                }
+                d_inode.disk_node.nlink += 1;
+                fs.i_put(&d_inode)?;
             }
-            d_inode.disk_node.nlink += 1;
-            self.i_put(&d_inode)?;
-        }
-        let dir = &FSName::new_de(inum, name).unwrap();
-        let offset = write_dir(&mut self.fs, inode, dir)?;
-        return Ok(offset);
+            let dir = &FSName::new_de(inum, name).unwrap();
+            let offset = write_dir(&mut fs.fs, inode, dir)?;
+            // Persist the grown directory, so callers holding a fresh copy see the new entry
+            fs.i_put(inode)?;
+            fs.fs.touch_modified(inode.inum)?;
+            if inum != inode.inum {
+                fs.fs.touch_changed(inum)?;
+            }
+            Ok(offset)
+        })
     }
 }
 
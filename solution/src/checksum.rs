@@ -0,0 +1,424 @@
+//! Checksums of inodes and blocks
+//!
+//! On a file system created with `MkfsOptions::checksums` (`FEATURE_CHECKSUMS`), `mkfs` puts a
+//! checksum table behind all other regions. It holds an entry for every inode, followed by an
+//! entry for every block of the disk. An entry is either 0, for an inode or block without a
+//! checksum, or `CHECKSUM_PRESENT` combined with the CRC32C of the contents.
+//!
+//! - Inodes are checksummed one by one, so a mismatch can name the inode: `i_put` stores the
+//!   checksum of the inode, and `i_get` returns `FileSystemError::CorruptInode` if it does not
+//!   match. The blocks of the inode region are not checksummed themselves.
+//! - `b_put` stores the checksum of every block it writes, and `b_get` returns
+//!   `FileSystemError::CorruptBlock` if it does not match. This covers the metadata blocks: the
+//!   bitmap, directories, indirect blocks and extent tree nodes, group descriptors and the other
+//!   tables. The bitmap is checked when the file system is mounted, since it is loaded without
+//!   `b_get`.
+//! - The contents of files, written by `data_put`, are only checksummed if the file system was
+//!   created with `MkfsOptions::data_checksums` (`FEATURE_DATA_CHECKSUMS`); otherwise
+//!   `data_put` clears the entry of the block.
+//!
+//! Block 0, the log and the checksum table itself have no checksums. Like the other tables, the
+//! checksum table is written through the buffer cache and the log, so a transaction commits the
+//! checksums together with the blocks they belong to.
+
+use cplfs_api::controller::Device;
+use cplfs_api::types::{Block, SuperBlock};
+
+use crate::b_inode_support::FileSystem;
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::{get_nbitmapblocks, write_block};
+use crate::superblock_ext::{
+    SuperBlockExt, FEATURE_CHECKSUMS, FEATURE_DATA_CHECKSUMS, FEATURE_INODE_EXT, FEATURE_LOG,
+    FEATURE_REFLINK,
+};
+
+/// Size of an entry of the checksum table on disk, in bytes
+const CHECKSUM_SIZE: u64 = 8;
+
+/// Set in the entries that hold a checksum, in the bits above the CRC32C
+pub const CHECKSUM_PRESENT: u64 = 1 << 32;
+
+/// Entry of the checksum table for `data`
+fn checksum_entry(data: &[u8]) -> u64 {
+    CHECKSUM_PRESENT | crc32c::crc32c(data) as u64
+}
+
+/// Number of entries in a block of the table
+fn checksums_per_block(sb: &SuperBlock) -> u64 {
+    sb.block_size / CHECKSUM_SIZE
+}
+
+/// Number of blocks the checksum table needs
+pub fn checksum_table_blocks(sb: &SuperBlock) -> u64 {
+    let per_block = checksums_per_block(sb);
+    (sb.ninodes + sb.nblocks + per_block - 1) / per_block
+}
+
+/// Checks whether the table described by the extension fits on the disk, behind the data region,
+/// the log, the inode extension table and the reference count table
+pub fn checksums_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    let mut first_free = sb.datastart + sb.ndatablocks;
+    if ext.has_feature(FEATURE_LOG) {
+        first_free = first_free.max(ext.logstart + ext.nlog);
+    }
+    if ext.has_feature(FEATURE_INODE_EXT) {
+        first_free = first_free.max(ext.iextstart + ext.niextblocks);
+    }
+    if ext.has_feature(FEATURE_REFLINK) {
+        first_free = first_free.max(ext.refstart + ext.nrefblocks);
+    }
+    ext.csumstart >= first_free
+        && ext.ncsumblocks >= checksum_table_blocks(sb)
+        && ext.csumstart + ext.ncsumblocks <= sb.nblocks
+}
+
+/// Block and byte offset in that block of entry `entry` of the table
+fn checksum_location(sb: &SuperBlock, ext: &SuperBlockExt, entry: u64) -> (u64, u64) {
+    let per_block = checksums_per_block(sb);
+    (
+        ext.csumstart + entry / per_block,
+        entry % per_block * CHECKSUM_SIZE,
+    )
+}
+
+/// Zeroes the checksum table, so nothing has a checksum yet
+pub fn format_checksums(
+    dev: &mut Device,
+    sb: &SuperBlock,
+    ext: &SuperBlockExt,
+) -> Result<(), FileSystemError> {
+    for i in ext.csumstart..ext.csumstart + ext.ncsumblocks {
+        write_block(dev, &Block::new_zero(i, sb.block_size))?;
+    }
+    Ok(())
+}
+
+impl FileSystem {
+    /// Whether this file system was created with checksums
+    pub fn has_checksums(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_CHECKSUMS)
+    }
+
+    /// Whether this file system checksums the contents of files as well
+    pub fn has_data_checksums(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_DATA_CHECKSUMS)
+    }
+
+    /// Whether block `i` has an entry in the checksum table that is kept up to date
+    fn block_checksummed(&self, i: u64) -> bool {
+        let sb = &self.superblock;
+        let ext = &self.sb_ext;
+        let in_log =
+            ext.has_feature(FEATURE_LOG) && (ext.logstart..ext.logstart + ext.nlog).contains(&i);
+        let in_table = (ext.csumstart..ext.csumstart + ext.ncsumblocks).contains(&i);
+        self.has_checksums()
+            && i != 0
+            && i < sb.nblocks
+            && !(sb.inodestart..sb.bmapstart).contains(&i)
+            && !in_log
+            && !in_table
+    }
+
+    /// Reads entry `entry` of the checksum table
+    fn checksum_get(&self, entry: u64) -> Result<u64, FileSystemError> {
+        let (block_no, offset) = checksum_location(&self.superblock, &self.sb_ext, entry);
+        Ok(self
+            .cached_read(block_no)?
+            .deserialize_from::<u64>(offset)?)
+    }
+
+    /// Stores `value` as entry `entry` of the checksum table, unless it is there already
+    fn checksum_put(&mut self, entry: u64, value: u64) -> Result<(), FileSystemError> {
+        let (block_no, offset) = checksum_location(&self.superblock, &self.sb_ext, entry);
+        let mut block = self.cached_read(block_no)?;
+        if block.deserialize_from::<u64>(offset)? != value {
+            block.serialize_into(&value, offset)?;
+            self.cached_write(&block)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the checksum of block `b`, that is about to be written, or clears it if `checksum`
+    /// is false
+    pub(crate) fn put_block_checksum(
+        &mut self,
+        b: &Block,
+        checksum: bool,
+    ) -> Result<(), FileSystemError> {
+        if !self.block_checksummed(b.block_no) || b.len() != self.superblock.block_size {
+            return Ok(());
+        }
+        let value = if checksum {
+            checksum_entry(b.contents_as_ref())
+        } else {
+            0
+        };
+        self.checksum_put(self.superblock.ninodes + b.block_no, value)
+    }
+
+    /// Checks block `b`, as read from the disk, against its checksum
+    pub(crate) fn verify_block(&self, b: &Block) -> Result<(), FileSystemError> {
+        if !self.block_checksummed(b.block_no) {
+            return Ok(());
+        }
+        let value = self.checksum_get(self.superblock.ninodes + b.block_no)?;
+        if value != 0 && value != checksum_entry(b.contents_as_ref()) {
+            return Err(FileSystemError::CorruptBlock(b.block_no));
+        }
+        Ok(())
+    }
+
+    /// Stores the checksum of inode `inum`, which is serialized in the `size` bytes at `offset` in
+    /// `block`
+    pub(crate) fn put_inode_checksum(
+        &mut self,
+        inum: u64,
+        block: &Block,
+        offset: u64,
+        size: u64,
+    ) -> Result<(), FileSystemError> {
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        let bytes = &block.contents_as_ref()[offset as usize..(offset + size) as usize];
+        self.checksum_put(inum, checksum_entry(bytes))
+    }
+
+    /// Checks inode `inum`, which is serialized in the `size` bytes at `offset` in `block`, against
+    /// its checksum
+    pub(crate) fn verify_inode(
+        &self,
+        inum: u64,
+        block: &Block,
+        offset: u64,
+        size: u64,
+    ) -> Result<(), FileSystemError> {
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        let bytes = &block.contents_as_ref()[offset as usize..(offset + size) as usize];
+        let value = self.checksum_get(inum)?;
+        if value != 0 && value != checksum_entry(bytes) {
+            return Err(FileSystemError::CorruptInode(inum));
+        }
+        Ok(())
+    }
+
+    /// Checks the blocks of the bitmap region against their checksums
+    pub(crate) fn verify_bitmap(&self) -> Result<(), FileSystemError> {
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        let bmapstart = self.superblock.bmapstart;
+        for i in bmapstart..bmapstart + get_nbitmapblocks(&self.superblock) {
+            self.verify_block(&self.cached_read(i)?)?;
+        }
+        Ok(())
+    }
+
+    /// Stores the checksums of all blocks as they are now, for `mkfs`
+    /// The inodes get theirs when `mkfs` writes them.
+    pub(crate) fn rebuild_block_checksums(&mut self) -> Result<(), FileSystemError> {
+        if !self.has_checksums() {
+            return Ok(());
+        }
+        for i in 1..self.superblock.nblocks {
+            if self.block_checksummed(i) {
+                let block = self.cached_read(i)?;
+                self.put_block_checksum(&block, true)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::d_path_support::FileSystemD;
+    use crate::f_indirect_inodes::FileSystemF;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::MkfsOptions;
+    use cplfs_api::controller::Device;
+    use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeRWSupport, InodeSupport, PathSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock, DINODE_SIZE, DIRECT_POINTERS};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static SUPERBLOCK_D: SuperBlock = SuperBlock {
+        block_size: 1000,
+        nblocks: 41,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    static SUPERBLOCK_F: SuperBlock = SuperBlock {
+        block_size: 300,
+        nblocks: 80,
+        ninodes: 6,
+        inodestart: 1,
+        ndatablocks: 70,
+        bmapstart: 4,
+        datastart: 5,
+    };
+    fn checksums() -> MkfsOptions {
+        MkfsOptions {
+            checksums: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-checksum-".to_string() + name), "img")
+    }
+
+    /// Flips the bits of byte `offset` of block `block_no`, behind the back of the file system
+    fn flip(dev: &mut Device, block_no: u64, offset: u64) {
+        let mut block = dev.read_block(block_no).unwrap();
+        let mut byte = [0];
+        block.read_data(&mut byte, offset).unwrap();
+        block.write_data(&[!byte[0]], offset).unwrap();
+        dev.write_block(&block).unwrap();
+    }
+
+    #[test]
+    fn needs_checksums() {
+        let path = disk_prep_path("needs_checksums");
+        let options = MkfsOptions {
+            checksums: false,
+            data_checksums: true,
+            ..checksums()
+        };
+        assert!(matches!(
+            FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_D, &options),
+            Err(FileSystemError::InvalidSuperBlock())
+        ));
+    }
+
+    #[test]
+    fn metadata_checksums() {
+        let path = disk_prep_path("metadata_checksums");
+        let mut my_fs = FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_D, &checksums()).unwrap();
+        let dir = my_fs.mkdir("/dir").unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let buf = Buffer::new(vec![7; 50].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, 50).unwrap();
+        let data_block = ino.get_block(0);
+        let root_block = my_fs.i_get(1).unwrap().get_block(0);
+
+        //Untouched images mount and read fine
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemD::mountfs(dev).unwrap();
+        assert_eq!(my_fs.resolve_path("/dir").unwrap().inum, dir.inum);
+
+        //Without data checksums, changed file contents go unnoticed
+        let mut dev = my_fs.unmountfs();
+        flip(&mut dev, data_block, 3);
+        let my_fs = FileSystemD::mountfs(dev).unwrap();
+        let mut read = Buffer::new_zero(50);
+        my_fs
+            .i_read(&my_fs.i_get(inum).unwrap(), &mut read, 0, 50)
+            .unwrap();
+        assert_eq!(read.contents_as_ref()[3], !7);
+
+        //Changed directories and inodes are reported by their number
+        let mut dev = my_fs.unmountfs();
+        flip(&mut dev, root_block, 10);
+        let my_fs = FileSystemD::mountfs(dev).unwrap();
+        assert!(matches!(
+            my_fs.resolve_path("/dir"),
+            Err(FileSystemError::CorruptBlock(b)) if b == root_block
+        ));
+        let mut dev = my_fs.unmountfs();
+        flip(&mut dev, root_block, 10);
+        let per_block = SUPERBLOCK_D.block_size / *DINODE_SIZE;
+        let inode_block = SUPERBLOCK_D.inodestart + dir.inum / per_block;
+        flip(
+            &mut dev,
+            inode_block,
+            dir.inum % per_block * *DINODE_SIZE + 8,
+        );
+        let my_fs = FileSystemD::mountfs(dev).unwrap();
+        assert!(matches!(
+            my_fs.resolve_path("/dir"),
+            Err(FileSystemError::CorruptInode(i)) if i == dir.inum
+        ));
+        assert!(my_fs.i_get(inum).is_ok());
+
+        //A changed bitmap keeps the file system from mounting
+        let mut dev = my_fs.unmountfs();
+        flip(&mut dev, SUPERBLOCK_D.bmapstart, 0);
+        assert!(matches!(
+            FileSystemD::mountfs(dev),
+            Err(FileSystemError::CorruptBlock(b)) if b == SUPERBLOCK_D.bmapstart
+        ));
+
+        utils::disk_unprep_path(&path);
+    }
+
+    #[test]
+    fn data_checksums() {
+        let path = disk_prep_path("data_checksums");
+        let options = MkfsOptions {
+            data_checksums: true,
+            ..checksums()
+        };
+        let mut my_fs = FileSystemF::mkfs_with_options(&path, &SUPERBLOCK_F, &options).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let size = (DIRECT_POINTERS + 2) * 300;
+        let buf = Buffer::new(vec![5; size as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, size).unwrap();
+        let patch = Buffer::new(vec![6; 20].into_boxed_slice());
+        my_fs.i_write(&mut ino, &patch, 310, 20).unwrap();
+        let data_block = my_fs.bmap(&ino, 1).unwrap();
+        let indirect_block = ino.disk_node.indirect_block;
+
+        //Rewritten blocks keep valid checksums
+        let mut dev = my_fs.unmountfs();
+        let my_fs = FileSystemF::mountfs(dev).unwrap();
+        let mut read = Buffer::new_zero(size);
+        my_fs
+            .i_read(&my_fs.i_get(inum).unwrap(), &mut read, 0, size)
+            .unwrap();
+        assert_eq!(read.contents_as_ref()[315], 6);
+
+        //File contents and indirect blocks are both checked
+        dev = my_fs.unmountfs();
+        flip(&mut dev, data_block, 0);
+        let my_fs = FileSystemF::mountfs(dev).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+        assert!(matches!(
+            my_fs.i_read(&ino, &mut read, 0, size),
+            Err(FileSystemError::CorruptBlock(b)) if b == data_block
+        ));
+        assert!(my_fs.b_get(data_block).is_err());
+        dev = my_fs.unmountfs();
+        flip(&mut dev, data_block, 0);
+        flip(&mut dev, indirect_block, 0);
+        let my_fs = FileSystemF::mountfs(dev).unwrap();
+        assert!(matches!(
+            my_fs.i_read(&ino, &mut read, 0, size),
+            Err(FileSystemError::CorruptBlock(b)) if b == indirect_block
+        ));
+        assert_eq!(my_fs.i_read(&ino, &mut read, 0, 600).unwrap(), 600);
+
+        //So are the inodes of assignment f
+        dev = my_fs.unmountfs();
+        flip(&mut dev, SUPERBLOCK_F.inodestart, 0);
+        let my_fs = FileSystemF::mountfs(dev).unwrap();
+        assert!(matches!(
+            my_fs.i_get(0),
+            Err(FileSystemError::CorruptInode(0))
+        ));
+        assert!(my_fs.i_get(inum).is_ok());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
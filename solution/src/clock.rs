@@ -0,0 +1,61 @@
+//! Clocks for the timestamps of inodes
+//!
+//! A file system asks its `Clock` for the time whenever it updates the timestamps in an
+//! `InodeExt`. Mounted file systems use the `SystemClock`; tests can inject a `ManualClock`
+//! with `FileSystem::set_clock` to get deterministic timestamps.
+
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of the current time, in seconds since the Unix epoch
+pub trait Clock: Debug + Send {
+    /// The current time
+    fn now(&self) -> u64;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// The clock of the operating system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+/// A clock that only moves when it is told to
+/// Clones share the same time, so a test can keep one to move the clock of a file system.
+pub struct ManualClock {
+    /// The current time
+    time: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Creates a clock that is stopped at `time`
+    pub fn new(time: u64) -> ManualClock {
+        ManualClock {
+            time: Arc::new(AtomicU64::new(time)),
+        }
+    }
+
+    /// Moves the clock to `time`
+    pub fn set(&self, time: u64) {
+        self.time.store(time, Ordering::SeqCst);
+    }
+
+    /// Moves the clock `secs` seconds forward
+    pub fn advance(&self, secs: u64) {
+        self.time.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.time.load(Ordering::SeqCst)
+    }
+}
@@ -17,21 +17,590 @@
 //! or you want to explain your approach, write it down after the comments
 //! section. If you had no major issues and everything works, there is no need to write any comments.
 //!
-//! COMPLETED: NO
+//! COMPLETED: YES
 //!
 //! COMMENTS:
 //!
-//! ...
+//! `FileSystemD` wraps the `FileSystemC` of the previous assignment and adds a current working
+//! directory. `mkdir` and `unlink` each run as a single file system operation, so they are atomic
+//! on file systems with a log.
 //!
+//! Paths are resolved through symbolic links (see `crate::symlink`). The flag that marks a symbolic
+//! link lives in the inode extension table, so only file systems created with
+//! `MkfsOptions::extended_inodes` have them: elsewhere `symlink` fails with
+//! `FeatureNotSupported()` and no inode is ever followed as a link.
+//! The same holds for permissions (see `crate::permissions`): `walk` and `resolve_parent` call
+//! `check_access` for search (`MAY_EXEC`) and write permission on the directories they pass, but
+//! without an inode extension table there is no owner or mode to check against, so these calls
+//! always succeed.
+//!
+
+use crate::c_dirs_support::FileSystemC;
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::get_direntries;
+use crate::inode_ext::{inline_capacity, pack_inline, unpack_inline};
+use crate::options::{MkfsOptions, MountOptions};
+use crate::permissions::{Credentials, MAY_EXEC, MAY_READ, MAY_WRITE, MODE_MASK};
+use crate::symlink::MAX_SYMLINK_HOPS;
+use cplfs_api::controller::Device;
+use cplfs_api::fs::{
+    BlockSupport, DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport, PathSupport,
+};
+use cplfs_api::types::{
+    Block, Buffer, DirEntry, FType, Inode, InodeLike, SuperBlock, DIRENTRY_SIZE, DIRNAME_SIZE,
+    ROOT_INUM,
+};
+use std::collections::VecDeque;
+use std::path::Path;
 
 /// You are free to choose the name for your file system. As we will use
 /// automated tests when grading your assignment, indicate here the name of
 /// your file system data type so we can just use `FSName` instead of
 /// having to manually figure out the name.
 ///
-pub type FSName = ();
+pub type FSName = FileSystemD;
+
+#[derive(Debug)]
+/// File system with path support, built on top of the directory support of `FileSystemC`
+pub struct FileSystemD {
+    /// The file system with directory support, whose methods we reuse
+    pub fs: FileSystemC,
+    /// Current working directory, as an absolute path without "." and ".." in it
+    cwd: String,
+}
+
+impl FileSystemD {
+    /// This function creates a filesystem_d given a filesystem with directory support
+    pub fn create_filesystem(fs: FileSystemC) -> FileSystemD {
+        FileSystemD {
+            fs,
+            cwd: "/".to_string(),
+        }
+    }
+
+    /// Same as `mkfs`, but lets the caller pick some layout options
+    pub fn mkfs_with_options<P: AsRef<Path>>(
+        path: P,
+        sb: &SuperBlock,
+        options: &MkfsOptions,
+    ) -> Result<Self, FileSystemError> {
+        let fs = FileSystemC::mkfs_with_options(path, sb, options)?;
+        let mut fs_d = FileSystemD::create_filesystem(fs);
+        // The root is its own parent, and counts as linked once
+        let mut root = fs_d.i_get(ROOT_INUM)?;
+        root.disk_node.nlink = 1;
+        fs_d.i_put(&root)?;
+        fs_d.dirlink(&mut root, ".", ROOT_INUM)?;
+        fs_d.dirlink(&mut root, "..", ROOT_INUM)?;
+        Ok(fs_d)
+    }
+
+    /// Same as `mountfs`, but lets the caller pick some mount options
+    pub fn mountfs_with_options(
+        dev: Device,
+        options: &MountOptions,
+    ) -> Result<Self, FileSystemError> {
+        let fs = FileSystemC::mountfs_with_options(dev, options)?;
+        Ok(FileSystemD::create_filesystem(fs))
+    }
+
+    /// Writes all pending changes to the device, see `FileSystem::sync`
+    pub fn sync(&mut self) -> Result<(), FileSystemError> {
+        self.fs.sync()
+    }
+
+    /// Unmounts the file system, reporting write errors, see `FileSystem::unmountfs_checked`
+    pub fn unmountfs_checked(self) -> Result<Device, (FileSystemError, Device)> {
+        self.fs.unmountfs_checked()
+    }
+
+    /// The user on whose behalf path operations run
+    pub fn credentials(&self) -> &Credentials {
+        &self.fs.fs.creds
+    }
+
+    /// Runs all following path operations on behalf of the user with credentials `creds`
+    pub fn set_credentials(&mut self, creds: Credentials) {
+        self.fs.fs.creds = creds;
+    }
+
+    /// Runs `f` on behalf of the user with credentials `creds`, and restores the current
+    /// credentials afterwards
+    pub fn with_credentials<R>(&mut self, creds: Credentials, f: impl FnOnce(&mut Self) -> R) -> R {
+        let old = std::mem::replace(&mut self.fs.fs.creds, creds);
+        let result = f(self);
+        self.fs.fs.creds = old;
+        result
+    }
+
+    /// Changes the permission bits of the inode at `path` to `mode`
+    /// Only its owner and the root user may do this.
+    pub fn chmod(&mut self, path: &str, mode: u32) -> Result<(), FileSystemError> {
+        let inum = self.resolve_path(path)?.inum;
+        let mut ext = self.fs.i_ext_get(inum)?;
+        let creds = self.credentials();
+        if !creds.is_root() && creds.uid != ext.uid {
+            return Err(FileSystemError::PermissionDenied());
+        }
+        ext.mode = mode & MODE_MASK;
+        self.fs.fs.i_ext_put(inum, &ext)?;
+        self.fs.fs.touch_changed(inum)
+    }
+
+    /// Changes the owner and group of the inode at `path`
+    /// Only the root user may give an inode away; its owner may only change its group to one of
+    /// their own groups.
+    pub fn chown(&mut self, path: &str, uid: u32, gid: u32) -> Result<(), FileSystemError> {
+        let inum = self.resolve_path(path)?.inum;
+        let mut ext = self.fs.i_ext_get(inum)?;
+        let creds = self.credentials();
+        if !creds.is_root() && (creds.uid != ext.uid || uid != ext.uid || !creds.in_group(gid)) {
+            return Err(FileSystemError::PermissionDenied());
+        }
+        ext.uid = uid;
+        ext.gid = gid;
+        self.fs.fs.i_ext_put(inum, &ext)?;
+        self.fs.fs.touch_changed(inum)
+    }
+
+    /// Splits a valid path into its names, and tells whether it is absolute
+    pub(crate) fn split_path(path: &str) -> (bool, Vec<&str>) {
+        if path == "/" {
+            return (true, vec![]);
+        }
+        match path.strip_prefix('/') {
+            Some(rest) => (true, rest.split('/').collect()),
+            None => (false, path.split('/').collect()),
+        }
+    }
+
+    /// Follows `names` through the file system, starting from directory `start`
+    /// Symbolic links are followed, except for the last name if `follow_last` is false. Relative
+    /// link targets start at the directory that holds the link.
+    fn walk(
+        &self,
+        start: Inode,
+        names: &[&str],
+        follow_last: bool,
+    ) -> Result<Inode, FileSystemError> {
+        let mut current = start;
+        let mut todo: VecDeque<String> = names.iter().map(|name| name.to_string()).collect();
+        let mut hops = 0;
+        while let Some(name) = todo.pop_front() {
+            if current.get_ft() != FType::TDir {
+                return Err(FileSystemError::INodeNotADirectory());
+            }
+            self.fs.fs.check_access(current.inum, MAY_EXEC)?;
+            if name == "." {
+                continue;
+            }
+            let next = self.dirlookup(&current, &name)?.0;
+            if (follow_last || !todo.is_empty()) && self.is_symlink(next.inum)? {
+                hops += 1;
+                if hops > MAX_SYMLINK_HOPS {
+                    return Err(FileSystemError::TooManyLinks());
+                }
+                let target = self.link_target(&next)?;
+                let (absolute, link_names) = FileSystemD::split_path(&target);
+                if absolute {
+                    current = self.i_get(ROOT_INUM)?;
+                }
+                for link_name in link_names.into_iter().rev() {
+                    todo.push_front(link_name.to_string());
+                }
+            } else {
+                current = next;
+            }
+        }
+        Ok(current)
+    }
+
+    /// The inode a valid path starts from: the root or the current working directory
+    fn start_inode(&self, absolute: bool) -> Result<Inode, FileSystemError> {
+        let root = self.i_get(ROOT_INUM)?;
+        if absolute {
+            return Ok(root);
+        }
+        let (_, cwd) = FileSystemD::split_path(&self.cwd);
+        self.walk(root, &cwd, true)
+    }
+
+    /// Resolves `path` like `resolve_path`, but without following a symbolic link in its last
+    /// name, so the link itself is returned
+    pub fn resolve_nofollow(&self, path: &str) -> Result<Inode, FileSystemError> {
+        if !FileSystemD::valid_path(path) {
+            return Err(FileSystemError::InvalidPath());
+        }
+        let (absolute, names) = FileSystemD::split_path(path);
+        self.walk(self.start_inode(absolute)?, &names, false)
+    }
+
+    /// Resolves all but the last name of `path`, which has to be a directory the current user may
+    /// change, and returns it together with the last name. Errors if the last name is "." or "..".
+    pub(crate) fn resolve_parent<'a>(
+        &self,
+        path: &'a str,
+    ) -> Result<(Inode, &'a str), FileSystemError> {
+        if !FileSystemD::valid_path(path) {
+            return Err(FileSystemError::InvalidPath());
+        }
+        let (absolute, names) = FileSystemD::split_path(path);
+        let (&name, prefix) = names
+            .split_last()
+            .ok_or_else(FileSystemError::InvalidPath)?;
+        if name == "." || name == ".." {
+            return Err(FileSystemError::InvalidDirname());
+        }
+        let parent = self.walk(self.start_inode(absolute)?, prefix, true)?;
+        if parent.get_ft() != FType::TDir {
+            return Err(FileSystemError::INodeNotADirectory());
+        }
+        self.fs.fs.check_access(parent.inum, MAY_WRITE | MAY_EXEC)?;
+        Ok((parent, name))
+    }
+
+    /// Whether directory `dir` has no entries other than "." and ".."
+    fn is_empty_dir(&self, dir: &Inode) -> Result<bool, FileSystemError> {
+        let entries = get_direntries(&self.fs.fs, dir)?;
+        Ok(entries.iter().all(|(de, _)| {
+            de.inum == 0 || [".", ".."].contains(&FileSystemC::get_name_str(de).as_str())
+        }))
+    }
+
+    /// Removes the entry called `name` from directory `dir`, by overwriting it with an unused
+    /// entry
+    fn clear_entry(&mut self, dir: &Inode, name: &str) -> Result<(), FileSystemError> {
+        let unused = DirEntry {
+            inum: 0,
+            name: ['\0'; DIRNAME_SIZE],
+        };
+        if self.fs.fs.is_inline(dir.inum)? {
+            let offset = self.dirlookup(dir, name)?.1;
+            let area = unpack_inline(&dir.disk_node.direct_blocks, inline_capacity());
+            let mut area = Buffer::new(area.into_boxed_slice());
+            area.serialize_into(&unused, offset)?;
+            let mut dir = self.i_get(dir.inum)?;
+            dir.disk_node.direct_blocks = pack_inline(area.contents_as_ref());
+            return self.i_put(&dir);
+        }
+        let block_size = self.fs.fs.superblock.block_size;
+        for &block_no in dir.disk_node.direct_blocks.iter().filter(|&&b| b != 0) {
+            let mut block: Block = self.b_get(block_no)?;
+            for k in 0..block_size / *DIRENTRY_SIZE {
+                let offset = k * *DIRENTRY_SIZE;
+                let de = block.deserialize_from::<DirEntry>(offset)?;
+                if de.inum != 0 && FileSystemC::get_name_str(&de) == name {
+                    block.serialize_into(&unused, offset)?;
+                    return self.b_put(&block);
+                }
+            }
+        }
+        Err(FileSystemError::DirectoryNotFound())
+    }
+}
+
+impl FileSysSupport for FileSystemD {
+    type Error = FileSystemError;
+
+    fn sb_valid(sb: &SuperBlock) -> bool {
+        FileSystemC::sb_valid(sb)
+    }
+
+    fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
+        FileSystemD::mkfs_with_options(path, sb, &MkfsOptions::default())
+    }
+
+    fn mountfs(dev: Device) -> Result<Self, Self::Error> {
+        FileSystemD::mountfs_with_options(dev, &MountOptions::default())
+    }
+
+    fn unmountfs(self) -> Device {
+        self.fs.unmountfs()
+    }
+}
+
+impl BlockSupport for FileSystemD {
+    fn b_get(&self, i: u64) -> Result<Block, Self::Error> {
+        self.fs.b_get(i)
+    }
+
+    fn b_put(&mut self, b: &Block) -> Result<(), Self::Error> {
+        self.fs.b_put(b)
+    }
+
+    fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_free(i)
+    }
+
+    fn b_zero(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_zero(i)
+    }
+
+    fn b_alloc(&mut self) -> Result<u64, Self::Error> {
+        self.fs.b_alloc()
+    }
+
+    fn sup_get(&self) -> Result<SuperBlock, Self::Error> {
+        self.fs.sup_get()
+    }
+
+    fn sup_put(&mut self, sup: &SuperBlock) -> Result<(), Self::Error> {
+        self.fs.sup_put(sup)
+    }
+}
+
+impl InodeSupport for FileSystemD {
+    type Inode = Inode;
+
+    fn i_get(&self, i: u64) -> Result<Self::Inode, Self::Error> {
+        self.fs.i_get(i)
+    }
+
+    fn i_put(&mut self, ino: &Self::Inode) -> Result<(), Self::Error> {
+        self.fs.i_put(ino)
+    }
+
+    fn i_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        let ino = self.i_get(i)?;
+        if ino.get_ft() == FType::TFree {
+            return Err(FileSystemError::AllreadyFreeError());
+        }
+        // Inodes that are still linked somewhere are left alone
+        if ino.get_nlink() > 0 {
+            return Ok(());
+        }
+        self.fs.i_free(i)
+    }
+
+    fn i_alloc(&mut self, ft: FType) -> Result<u64, Self::Error> {
+        self.fs.i_alloc(ft)
+    }
+
+    fn i_trunc(&mut self, inode: &mut Self::Inode) -> Result<(), Self::Error> {
+        self.fs.i_trunc(inode)
+    }
+}
+
+impl DirectorySupport for FileSystemD {
+    fn new_de(inum: u64, name: &str) -> Option<DirEntry> {
+        FileSystemC::new_de(inum, name)
+    }
+
+    fn get_name_str(de: &DirEntry) -> String {
+        FileSystemC::get_name_str(de)
+    }
+
+    fn set_name_str(de: &mut DirEntry, name: &str) -> Option<()> {
+        FileSystemC::set_name_str(de, name)
+    }
+
+    fn dirlookup(
+        &self,
+        inode: &Self::Inode,
+        name: &str,
+    ) -> Result<(Self::Inode, u64), Self::Error> {
+        self.fs.dirlookup(inode, name)
+    }
+
+    fn dirlink(
+        &mut self,
+        inode: &mut Self::Inode,
+        name: &str,
+        inum: u64,
+    ) -> Result<u64, Self::Error> {
+        self.fs.dirlink(inode, name, inum)
+    }
+}
+
+impl InodeRWSupport for FileSystemD {
+    fn i_read(
+        &self,
+        inode: &Self::Inode,
+        buf: &mut Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<u64, Self::Error> {
+        self.fs.fs.check_access(inode.inum, MAY_READ)?;
+        self.fs.i_read(inode, buf, off, n)
+    }
+
+    fn i_write(
+        &mut self,
+        inode: &mut Self::Inode,
+        buf: &Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<(), Self::Error> {
+        self.fs.fs.check_access(inode.inum, MAY_WRITE)?;
+        self.fs.i_write(inode, buf, off, n)
+    }
+}
+
+impl PathSupport for FileSystemD {
+    fn valid_path(path: &str) -> bool {
+        if path.is_empty() {
+            return false;
+        }
+        let (absolute, names) = FileSystemD::split_path(path);
+        if !absolute && names[0] != "." && names[0] != ".." {
+            return false;
+        }
+        names
+            .iter()
+            .all(|name| FileSystemD::new_de(0, name).is_some())
+    }
+
+    fn get_cwd(&self) -> String {
+        self.cwd.clone()
+    }
+
+    fn set_cwd(&mut self, path: &str) -> Option<()> {
+        if !FileSystemD::valid_path(path) {
+            return None;
+        }
+        let (absolute, names) = FileSystemD::split_path(path);
+        let mut cwd: Vec<&str> = if absolute {
+            vec![]
+        } else {
+            FileSystemD::split_path(&self.cwd).1
+        };
+        for name in names {
+            match name {
+                "." => (),
+                ".." => {
+                    cwd.pop();
+                }
+                _ => cwd.push(name),
+            }
+        }
+        self.cwd = format!("/{}", cwd.join("/"));
+        Some(())
+    }
+
+    fn resolve_path(&self, path: &str) -> Result<Self::Inode, Self::Error> {
+        if !FileSystemD::valid_path(path) {
+            return Err(FileSystemError::InvalidPath());
+        }
+        let (absolute, names) = FileSystemD::split_path(path);
+        self.walk(self.start_inode(absolute)?, &names, true)
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<Self::Inode, Self::Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        if self.dirlookup(&parent, name).is_ok() {
+            return Err(FileSystemError::EntryExists());
+        }
+        let inum = self.fs.in_op(|fs| {
+            let inum = fs.i_alloc_near(FType::TDir, parent.inum)?;
+            let mut dir = fs.i_get(inum)?;
+            fs.dirlink(&mut dir, ".", inum)?;
+            fs.dirlink(&mut dir, "..", parent.inum)?;
+            // The parent changed on disk when ".." was linked
+            let mut parent = fs.i_get(parent.inum)?;
+            fs.dirlink(&mut parent, name, inum)?;
+            Ok(inum)
+        })?;
+        self.i_get(inum)
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), Self::Error> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let (target, _) = self.dirlookup(&parent, name)?;
+        if target.get_ft() == FType::TDir && !self.is_empty_dir(&target)? {
+            return Err(FileSystemError::DirectoryNotEmpty());
+        }
+        let snapshot = self.fs.fs.start_op();
+        let result = self.unlink_entry(&parent, name, target);
+        self.fs.fs.finish_op(snapshot, result)
+    }
+}
+
+impl FileSystemD {
+    /// Body of `unlink`, which runs inside a single file system operation
+    fn unlink_entry(
+        &mut self,
+        parent: &Inode,
+        name: &str,
+        mut target: Inode,
+    ) -> Result<(), FileSystemError> {
+        self.clear_entry(parent, name)?;
+        self.fs.fs.touch_modified(parent.inum)?;
+        // Removing a reference of a directory to itself does not change its link count
+        if target.inum == parent.inum {
+            return Ok(());
+        }
+        drop_link(&mut target)?;
+        self.i_put(&target)?;
+        if target.get_nlink() > 0 {
+            return self.fs.fs.touch_changed(target.inum);
+        }
+        // The ".." entry of a directory that is freed no longer refers to its parent
+        if target.get_ft() == FType::TDir {
+            if let Ok((mut up, _)) = self.dirlookup(&target, "..") {
+                if up.inum != target.inum {
+                    drop_link(&mut up)?;
+                    self.i_put(&up)?;
+                }
+            }
+        }
+        self.i_free(target.inum)
+    }
+}
+
+/// Removes one link from `ino`, failing if its link count is already 0
+fn drop_link(ino: &mut Inode) -> Result<(), FileSystemError> {
+    ino.disk_node.nlink = ino
+        .disk_node
+        .nlink
+        .checked_sub(1)
+        .ok_or(FileSystemError::NoLinksLeft(ino.inum))?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::FSName;
+    use crate::filesystem_errors::FileSystemError;
+    use cplfs_api::fs::{FileSysSupport, InodeSupport, PathSupport};
+    use cplfs_api::types::SuperBlock;
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: 1000,
+        nblocks: 12,
+        ninodes: 8,
+        inodestart: 1,
+        ndatablocks: 7,
+        bmapstart: 4,
+        datastart: 5,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-d-path-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn unlink_without_links() {
+        let path = disk_prep_path("no-links");
+        let mut my_fs = FSName::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+
+        //An entry to an inode whose link count was corrupted to 0
+        let mut dir = my_fs.mkdir("/dir").unwrap();
+        dir.disk_node.nlink = 0;
+        my_fs.i_put(&dir).unwrap();
+        assert!(matches!(
+            my_fs.unlink("/dir"),
+            Err(FileSystemError::NoLinksLeft(_))
+        ));
 
-//
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
 
 // WARNING: DO NOT TOUCH THE BELOW CODE -- IT IS REQUIRED FOR TESTING -- YOU WILL LOSE POINTS IF I MANUALLY HAVE TO FIX YOUR TESTS
 #[cfg(all(test, any(feature = "d", feature = "all")))]
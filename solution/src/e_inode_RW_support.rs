@@ -26,8 +26,9 @@
 
 use crate::c_dirs_support::FileSystemC;
 use crate::filesystem_errors::FileSystemError;
+use crate::inode_ext::{inline_capacity, pack_inline, unpack_inline};
 use cplfs_api::fs::{BlockSupport, InodeRWSupport, InodeSupport};
-use cplfs_api::types::{Buffer, InodeLike};
+use cplfs_api::types::{Buffer, Inode, InodeLike};
 use std::convert::TryFrom;
 
 /// You are free to choose the name for your file system. As we will use
@@ -37,60 +38,22 @@ use std::convert::TryFrom;
 ///
 pub type FSName = FileSystemC;
 
-impl InodeRWSupport for FileSystemC {
-
-    fn i_read(&self,inode: &Self::Inode,buf: &mut Buffer,off: u64,n: u64 ) -> Result<u64, Self::Error> {
-        let mut ofsset = off;
-
-        let mut length = n;
-        let mut list = Vec::new();
-        let mut bytes_read = 0;
-
-        if ofsset > u64::try_from(inode.get_size()).unwrap(){
-            return Err(FileSystemError::ReadError());// WRITE EERROR
-        }
-        if ofsset == inode.get_size() {
-            return Ok(0)
-        }
-
-        for i in inode.disk_node.direct_blocks.iter() {
-            if *i != 0 && length > 0 {
-                if ofsset >= self.fs.superblock.block_size {
-                    ofsset -= self.fs.superblock.block_size;
-                } else {
-                    let block = self.fs.b_get(*i)?;
-                    let  contents_ref;
-                    contents_ref = block.contents_as_ref();
-
-                    if length + ofsset > self.fs.superblock.block_size {
-                        let start = usize::try_from(ofsset).unwrap();
-                        let end = usize::try_from(self.fs.superblock.block_size).unwrap();
-                        list.extend_from_slice(&contents_ref[start..end]);
-                        bytes_read = bytes_read + self.fs.superblock.block_size - ofsset;
-                        length = length - (self.fs.superblock.block_size - ofsset);
-                    } else {
-                        let start = usize::try_from(ofsset).unwrap();
-                        let end = usize::try_from(ofsset + length).unwrap();
-                        list.extend_from_slice(&contents_ref[start..end]);
-                        //bytes_read = bytes_read + length - ofsset;
-                        bytes_read = bytes_read + u64::try_from(end-start).unwrap();
-                        length = 0;
-                    }
-                    ofsset = 0;
-                }
+impl FileSystemC {
+    /// Tells the read-ahead tracker which logical blocks `[first, last]` of `inode` were read,
+    /// and prefetches the blocks it asks for
+    fn read_ahead(&self, inode: &Inode, first: u64, last: u64) -> Result<(), FileSystemError> {
+        let blocks = self.fs.readahead.borrow_mut().on_read(inode.inum, first, last);
+        for l in blocks {
+            match inode.disk_node.direct_blocks.get(l as usize) {
+                Some(&b) if b != 0 => self.fs.prefetch(b)?,
+                _ => break,
             }
         }
-        let limit = usize::try_from(buf.len()).unwrap();
-
-        if limit < list.len() {
-            buf.write_data(&list.as_slice()[0..limit], 0)?;
-        } else {
-            buf.write_data(&list.as_slice(), 0)?;
-        }
-        return Ok(bytes_read);
+        Ok(())
     }
 
-    fn i_write(&mut self,inode: &mut Self::Inode,buf: &Buffer,off: u64,n: u64,) -> Result<(), Self::Error> {
+    /// Body of `i_write`, which runs inside a single file system operation
+    fn write_inode(&mut self,inode: &mut Inode,buf: &Buffer,off: u64,n: u64,) -> Result<(), FileSystemError> {
         let mut ofsset = off;
 
         let mut towrite_length:usize = usize::try_from(n).unwrap();
@@ -108,6 +71,20 @@ impl InodeRWSupport for FileSystemC {
             return Ok(())
         }
 
+        // Inline contents stay in the inode as long as they fit, and move to a block otherwise
+        if self.fs.is_inline(inode.inum)? {
+            if ofsset + n <= inline_capacity() {
+                let mut data = unpack_inline(&inode.disk_node.direct_blocks, inode.get_size());
+                data.resize(usize::try_from(inode.get_size().max(ofsset + n)).unwrap(), 0);
+                let start = usize::try_from(ofsset).unwrap();
+                data[start..start + towrite_length].copy_from_slice(&vector[0..towrite_length]);
+                inode.disk_node.direct_blocks = pack_inline(&data);
+                inode.disk_node.size = u64::try_from(data.len()).unwrap();
+                return self.i_put(inode)
+            }
+            self.fs.spill_inline(inode)?;
+        }
+
         let mut potential_size = 0;
 
         for i in 0..inode.disk_node.direct_blocks.len() {
@@ -122,7 +99,7 @@ impl InodeRWSupport for FileSystemC {
             for i in 0..inode.disk_node.direct_blocks.len() {
                 if inode.disk_node.direct_blocks[i] == 0 && potential_size < ofsset+n{
                     // found a new block
-                    let mut new_block_data_index = self.b_alloc()?;
+                    let mut new_block_data_index = self.b_alloc_near(inode.inum)?;
                     let block_index = new_block_data_index + self.fs.superblock.datastart;
                     inode.disk_node.direct_blocks[i] = block_index;
 
@@ -158,11 +135,11 @@ impl InodeRWSupport for FileSystemC {
 
                     let mut block_space = usize::try_from(block.len() - ofsset).unwrap();
 
-                    if block_space > towrite_length{
+                    if block_space >= towrite_length{
                         let mut temp = vector.get(0..towrite_length).unwrap();
                         block.write_data(temp,ofsset)?;
-                        self.b_put(&block);
-                        self.i_put(inode);
+                        self.fs.data_put(&block)?;
+                        self.i_put(inode)?;
                         return Ok(())
                     }
                     else{
@@ -171,8 +148,8 @@ impl InodeRWSupport for FileSystemC {
                         vector = vector.split_off(block_space).to_vec();
 
                         towrite_length = towrite_length - block_space;
-                        self.b_put(&block);
-                        self.i_put(inode);
+                        self.fs.data_put(&block)?;
+                        self.i_put(inode)?;
                         ofsset = 0;
                     }
 
@@ -184,6 +161,177 @@ impl InodeRWSupport for FileSystemC {
     }
 }
 
+impl InodeRWSupport for FileSystemC {
+
+    fn i_read(&self,inode: &Self::Inode,buf: &mut Buffer,off: u64,n: u64 ) -> Result<u64, Self::Error> {
+        let mut ofsset = off;
+
+        let mut length = n;
+        let mut list = Vec::new();
+        let mut bytes_read = 0;
+
+        if ofsset > u64::try_from(inode.get_size()).unwrap(){
+            return Err(FileSystemError::ReadError());// WRITE EERROR
+        }
+        if ofsset == inode.get_size() {
+            return Ok(0)
+        }
+
+        if self.fs.is_inline(inode.inum)? {
+            let data = unpack_inline(&inode.disk_node.direct_blocks, inode.get_size());
+            let end = inode.get_size().min(off + n.min(buf.len()));
+            buf.write_data(&data[usize::try_from(off).unwrap()..usize::try_from(end).unwrap()], 0)?;
+            if end > off {
+                self.fs.touch_accessed(inode.inum);
+            }
+            return Ok(end - off)
+        }
+
+        for i in inode.disk_node.direct_blocks.iter() {
+            if *i != 0 && length > 0 {
+                if ofsset >= self.fs.superblock.block_size {
+                    ofsset -= self.fs.superblock.block_size;
+                } else {
+                    let block = self.fs.b_get(*i)?;
+                    let  contents_ref;
+                    contents_ref = block.contents_as_ref();
+
+                    if length + ofsset > self.fs.superblock.block_size {
+                        let start = usize::try_from(ofsset).unwrap();
+                        let end = usize::try_from(self.fs.superblock.block_size).unwrap();
+                        list.extend_from_slice(&contents_ref[start..end]);
+                        bytes_read = bytes_read + self.fs.superblock.block_size - ofsset;
+                        length = length - (self.fs.superblock.block_size - ofsset);
+                    } else {
+                        let start = usize::try_from(ofsset).unwrap();
+                        let end = usize::try_from(ofsset + length).unwrap();
+                        list.extend_from_slice(&contents_ref[start..end]);
+                        //bytes_read = bytes_read + length - ofsset;
+                        bytes_read = bytes_read + u64::try_from(end-start).unwrap();
+                        length = 0;
+                    }
+                    ofsset = 0;
+                }
+            }
+        }
+        let limit = usize::try_from(buf.len()).unwrap();
+
+        if limit < list.len() {
+            buf.write_data(&list.as_slice()[0..limit], 0)?;
+        } else {
+            buf.write_data(&list.as_slice(), 0)?;
+        }
+        if bytes_read > 0 {
+            let block_size = self.fs.superblock.block_size;
+            self.read_ahead(inode, off / block_size, (off + bytes_read - 1) / block_size)?;
+            self.fs.touch_accessed(inode.inum);
+        }
+        return Ok(bytes_read);
+    }
+
+    fn i_write(&mut self,inode: &mut Self::Inode,buf: &Buffer,off: u64,n: u64,) -> Result<(), Self::Error> {
+        let result = self.in_op(|fs| {
+            if let Err(e) = fs.write_inode(inode, buf, off, n) {
+                // Without a log nothing is rolled back, so the blocks allocated for the write are
+                // freed again here
+                if !fs.fs.is_inline(inode.inum)? {
+                    let stored = fs.i_get(inode.inum)?;
+                    for &b in inode.disk_node.direct_blocks.iter() {
+                        if b != 0 && !stored.disk_node.direct_blocks.contains(&b) {
+                            fs.b_free(b - fs.fs.superblock.datastart)?;
+                        }
+                    }
+                }
+                return Err(e);
+            }
+            fs.fs.touch_modified(inode.inum)
+        });
+        if result.is_err() {
+            // Its new blocks are gone, and with a log the whole write was rolled back, so the
+            // inode on disk is the one to go on with
+            *inode = self.i_get(inode.inum)?;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::FSName;
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 300;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: 11,
+        ninodes: 6,
+        inodestart: 1,
+        ndatablocks: 6,
+        bmapstart: 4,
+        datastart: 5,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-e-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn write_fills_block() {
+        let path = disk_prep_path("fills_block");
+        let mut my_fs = FSName::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //A write that ends exactly at the end of the first block
+        let buf = Buffer::new(vec![1; 100].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, 100).unwrap();
+        let buf = Buffer::new(vec![2; 200].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 100, 200).unwrap();
+        assert_eq!(ino.get_size(), BLOCK_SIZE);
+        assert_eq!(ino.get_block(1), 0);
+
+        //And one that fills a whole new block
+        let buf = Buffer::new(vec![3; 300].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, BLOCK_SIZE, BLOCK_SIZE).unwrap();
+        assert_eq!(ino.get_size(), 2 * BLOCK_SIZE);
+        assert_eq!(my_fs.i_get(inum).unwrap(), ino);
+
+        let mut read = Buffer::new_zero(2 * BLOCK_SIZE);
+        assert_eq!(my_fs.i_read(&ino, &mut read, 0, 2 * BLOCK_SIZE).unwrap(), 600);
+        let mut expected = vec![1; 100];
+        expected.append(&mut vec![2; 200]);
+        expected.append(&mut vec![3; 300]);
+        assert_eq!(read.contents_as_ref(), &expected[..]);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn write_out_of_space() {
+        let path = disk_prep_path("out_of_space");
+        let mut my_fs = FSName::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //Without a log, the blocks taken by a write that does not fit are freed again
+        let buf = Buffer::new_zero(7 * BLOCK_SIZE);
+        assert!(my_fs.i_write(&mut ino, &buf, 0, buf.len()).is_err());
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 6);
+        assert_eq!(ino.get_size(), 0);
+        assert_eq!(ino.get_block(0), 0);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
+
 //
 // WARNING: DO NOT TOUCH THE BELOW CODE -- IT IS REQUIRED FOR TESTING -- YOU WILL LOSE POINTS IF I MANUALLY HAVE TO FIX YOUR TESTS
 #[cfg(all(test, any(feature = "e", feature = "all")))]
@@ -0,0 +1,505 @@
+//! Extent-based inodes for the file system of assignment f
+//!
+//! A file system created with `MkfsOptions::extents` (`FEATURE_EXTENTS`) maps the blocks of its
+//! `IndirectInode`s with extents instead of block pointers. An extent maps a run of logical blocks
+//! onto a run of physical blocks of the same length, so a file that was allocated contiguously
+//! needs a single extent, however large it is.
+//!
+//! The extents reuse the pointer fields of the inode:
+//! - up to `INLINE_EXTENTS` extents are stored inline in `direct_blocks`, as three `u64`s each,
+//! - more extents are stored in an extent tree, whose root node is `indirect_block`. Leaf nodes
+//!   hold extents, index nodes hold the first logical block and the block number of each of their
+//!   children, and every node starts with a `NodeHeader`.
+//!
+//! The double- and triple-indirect blocks stay 0. The extent tree is rebuilt as a whole whenever
+//! the extents of a file change, reusing its old nodes.
+//!
+//! Holes are filled with power-of-two runs of contiguous blocks (see `FileSystem::b_alloc_run`),
+//! and extents that follow each other both logically and physically are merged, so a file that
+//! is written sequentially ends up as a handful of extents.
+
+use cplfs_api::fs::BlockSupport;
+use cplfs_api::types::{Block, SuperBlock, DIRECT_POINTERS};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::f_indirect_inodes::{FileSystemF, IndirectInode};
+use crate::filesystem_errors::FileSystemError;
+use crate::superblock_ext::FEATURE_EXTENTS;
+
+/// Number of extents that are stored inline, in the `direct_blocks` of an inode
+pub const INLINE_EXTENTS: usize = DIRECT_POINTERS as usize / 3;
+
+/// Magic number at the start of every node of an extent tree
+const NODE_MAGIC: u64 = 0x4558_5445_4e54_4e44;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// A run of `len` logical blocks of a file, starting at logical block `lbn`, that is stored in
+/// the blocks starting at block number `start`
+pub struct Extent {
+    /// First logical block
+    pub lbn: u64,
+    /// Block number of the first block
+    pub start: u64,
+    /// Number of blocks
+    pub len: u64,
+}
+
+impl Extent {
+    /// Block number of logical block `lbn`, if this extent maps it
+    pub fn map(&self, lbn: u64) -> Option<u64> {
+        if lbn >= self.lbn && lbn - self.lbn < self.len {
+            Some(self.start + lbn - self.lbn)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `next` continues this extent, logically as well as physically
+    fn continued_by(&self, next: &Extent) -> bool {
+        self.lbn + self.len == next.lbn && self.start + self.len == next.start
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+/// Header of a node of an extent tree
+struct NodeHeader {
+    /// Equal to `NODE_MAGIC`
+    magic: u64,
+    /// Distance to the leaves, 0 for a leaf node
+    depth: u64,
+    /// Number of entries in the node
+    count: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+/// Entry of an index node of an extent tree
+struct IndexEntry {
+    /// First logical block mapped by the child
+    lbn: u64,
+    /// Block number of the child
+    block: u64,
+}
+
+lazy_static! {
+    /// Size of the header of an extent tree node on disk, in bytes.
+    static ref NODE_HEADER_SIZE : u64 = bincode::serialize(&NodeHeader::default()).unwrap().len() as u64;
+    /// Size of an extent on disk, in bytes.
+    static ref EXTENT_SIZE : u64 = bincode::serialize(&Extent::default()).unwrap().len() as u64;
+    /// Size of an entry of an index node on disk, in bytes.
+    static ref INDEX_ENTRY_SIZE : u64 = bincode::serialize(&IndexEntry::default()).unwrap().len() as u64;
+}
+
+/// Number of extents that fit in a leaf node
+fn leaf_capacity(sb: &SuperBlock) -> u64 {
+    sb.block_size.saturating_sub(*NODE_HEADER_SIZE) / *EXTENT_SIZE
+}
+
+/// Number of children that fit in an index node
+fn index_capacity(sb: &SuperBlock) -> u64 {
+    sb.block_size.saturating_sub(*NODE_HEADER_SIZE) / *INDEX_ENTRY_SIZE
+}
+
+/// Number of the sorted `extents` that start at or before logical block `lbn`
+fn starts_until(extents: &[Extent], lbn: u64) -> usize {
+    match extents.binary_search_by_key(&lbn, |e| e.lbn) {
+        Ok(i) => i + 1,
+        Err(i) => i,
+    }
+}
+
+/// Block number of logical block `lbn` according to the sorted `extents`, or 0 if it is not mapped
+pub fn lookup(extents: &[Extent], lbn: u64) -> u64 {
+    let i = starts_until(extents, lbn);
+    i.checked_sub(1)
+        .and_then(|i| extents[i].map(lbn))
+        .unwrap_or(0)
+}
+
+/// Sorts `extents` and merges the ones that continue each other
+fn normalize(extents: &mut Vec<Extent>) {
+    extents.sort_by_key(|e| e.lbn);
+    let mut merged: Vec<Extent> = Vec::with_capacity(extents.len());
+    for e in extents.drain(..) {
+        match merged.last_mut() {
+            Some(last) if last.continued_by(&e) => last.len += e.len,
+            _ => merged.push(e),
+        }
+    }
+    *extents = merged;
+}
+
+impl FileSystemF {
+    /// Whether this file system maps the blocks of its inodes with extents
+    pub fn has_extents(&self) -> bool {
+        self.fs.sb_ext.has_feature(FEATURE_EXTENTS)
+    }
+
+    /// The extents of `inode`, sorted by logical block
+    pub fn extents(&self, inode: &IndirectInode) -> Result<Vec<Extent>, FileSystemError> {
+        Ok(self.load_tree(inode)?.0)
+    }
+
+    /// Number of blocks owned by `inode`, the nodes of its extent tree included
+    pub(crate) fn extent_blocks(&self, inode: &IndirectInode) -> Result<u64, FileSystemError> {
+        let (extents, nodes) = self.load_tree(inode)?;
+        Ok(extents.iter().map(|e| e.len).sum::<u64>() + nodes.len() as u64)
+    }
+
+    /// The extents of `inode`, and the block numbers of the nodes of its extent tree
+    fn load_tree(&self, inode: &IndirectInode) -> Result<(Vec<Extent>, Vec<u64>), FileSystemError> {
+        let mut extents = Vec::new();
+        let mut nodes = Vec::new();
+        if inode.disk_node.indirect_block == 0 {
+            for slot in inode.disk_node.direct_blocks.chunks_exact(3) {
+                if slot[2] != 0 {
+                    extents.push(Extent {
+                        lbn: slot[0],
+                        start: slot[1],
+                        len: slot[2],
+                    });
+                }
+            }
+        } else {
+            self.read_node(inode.disk_node.indirect_block, &mut extents, &mut nodes)?;
+        }
+        Ok((extents, nodes))
+    }
+
+    /// Appends the extents in the subtree rooted at node `block_no` to `extents`, and the block
+    /// numbers of its nodes to `nodes`
+    fn read_node(
+        &self,
+        block_no: u64,
+        extents: &mut Vec<Extent>,
+        nodes: &mut Vec<u64>,
+    ) -> Result<(), FileSystemError> {
+        let block = self.b_get(block_no)?;
+        let header = block.deserialize_from::<NodeHeader>(0)?;
+        let sb = &self.fs.superblock;
+        let capacity = match header.depth {
+            0 => leaf_capacity(sb),
+            _ => index_capacity(sb),
+        };
+        if header.magic != NODE_MAGIC || header.count > capacity {
+            return Err(FileSystemError::ReadError());
+        }
+        nodes.push(block_no);
+        for i in 0..header.count {
+            if header.depth == 0 {
+                let offset = *NODE_HEADER_SIZE + i * *EXTENT_SIZE;
+                extents.push(block.deserialize_from::<Extent>(offset)?);
+            } else {
+                let offset = *NODE_HEADER_SIZE + i * *INDEX_ENTRY_SIZE;
+                let entry = block.deserialize_from::<IndexEntry>(offset)?;
+                self.read_node(entry.block, extents, nodes)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the extents of `inode` by `extents`, which have to be sorted and merged
+    /// Nodes of the old extent tree are reused for the new one, or freed.
+    /// Only changes `inode` in memory; the caller has to write it back.
+    fn store_extents(
+        &mut self,
+        inode: &mut IndirectInode,
+        extents: &[Extent],
+    ) -> Result<(), FileSystemError> {
+        let mut spare = self.load_tree(inode)?.1;
+        inode.disk_node.direct_blocks = [0; DIRECT_POINTERS as usize];
+        inode.disk_node.indirect_block = 0;
+        if extents.len() <= INLINE_EXTENTS {
+            let slots = inode.disk_node.direct_blocks.chunks_exact_mut(3);
+            for (slot, e) in slots.zip(extents) {
+                slot.copy_from_slice(&[e.lbn, e.start, e.len]);
+            }
+        } else {
+            let sb = self.fs.superblock;
+            let mut level = Vec::new();
+            for chunk in extents.chunks(leaf_capacity(&sb) as usize) {
+                let block = self.write_node(inode.inum, &mut spare, 0, chunk, *EXTENT_SIZE)?;
+                level.push(IndexEntry {
+                    lbn: chunk[0].lbn,
+                    block,
+                });
+            }
+            let mut depth = 0;
+            while level.len() > 1 {
+                depth += 1;
+                let mut parents = Vec::new();
+                for chunk in level.chunks(index_capacity(&sb) as usize) {
+                    let block =
+                        self.write_node(inode.inum, &mut spare, depth, chunk, *INDEX_ENTRY_SIZE)?;
+                    parents.push(IndexEntry {
+                        lbn: chunk[0].lbn,
+                        block,
+                    });
+                }
+                level = parents;
+            }
+            inode.disk_node.indirect_block = level[0].block;
+        }
+        for block_no in spare {
+            self.free_block(block_no)?;
+        }
+        Ok(())
+    }
+
+    /// Makes `dst`, which has no blocks, map the same data blocks as `src`, with an extent tree
+    /// of its own; the data blocks get one more reference each
+    /// Only changes `dst` in memory; the caller has to write it back.
+    pub(crate) fn share_extents(
+        &mut self,
+        src: &IndirectInode,
+        dst: &mut IndirectInode,
+    ) -> Result<(), FileSystemError> {
+        let extents = self.extents(src)?;
+        for e in &extents {
+            for block_no in e.start..e.start + e.len {
+                self.share_block(block_no)?;
+            }
+        }
+        self.store_extents(dst, &extents)
+    }
+
+    /// Writes an extent tree node at `depth` with `entries` of `entry_size` bytes each, to one of
+    /// the `spare` blocks or to a newly allocated block, and returns its block number
+    fn write_node<T: Serialize>(
+        &mut self,
+        inum: u64,
+        spare: &mut Vec<u64>,
+        depth: u64,
+        entries: &[T],
+        entry_size: u64,
+    ) -> Result<u64, FileSystemError> {
+        let block_no = match spare.pop() {
+            Some(block_no) => block_no,
+            None => self.alloc_block(inum)?,
+        };
+        let mut block = Block::new_zero(block_no, self.fs.superblock.block_size);
+        let header = NodeHeader {
+            magic: NODE_MAGIC,
+            depth,
+            count: entries.len() as u64,
+        };
+        block.serialize_into(&header, 0)?;
+        for (i, entry) in entries.iter().enumerate() {
+            block.serialize_into(entry, *NODE_HEADER_SIZE + i as u64 * entry_size)?;
+        }
+        self.b_put(&block)?;
+        Ok(block_no)
+    }
+
+    /// Allocates a run of at most `max_len` contiguous zeroed blocks, as long as the free space
+    /// allows, and returns the block number of its first block and its length
+    fn alloc_run(&mut self, max_len: u64) -> Result<(u64, u64), FileSystemError> {
+        let mut order = 63 - max_len.leading_zeros() as usize;
+        loop {
+            match self.fs.b_alloc_run(order) {
+                Ok(index) => return Ok((index + self.fs.superblock.datastart, 1 << order)),
+                Err(FileSystemError::AllocationError()) if order > 0 => order -= 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Block numbers of logical blocks `first..=last` of `inode`, allocating the ones that are not
+    /// mapped yet
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn extent_map_alloc(
+        &mut self,
+        inode: &mut IndirectInode,
+        first: u64,
+        last: u64,
+    ) -> Result<Vec<u64>, FileSystemError> {
+        let mut extents = self.extents(inode)?;
+        let mut added = Vec::new();
+        let mut lbn = first;
+        while lbn <= last {
+            let i = starts_until(&extents, lbn);
+            if let Some(e) = i.checked_sub(1).map(|i| extents[i]) {
+                if e.map(lbn).is_some() {
+                    lbn = e.lbn + e.len;
+                    continue;
+                }
+            }
+            let hole_end = extents.get(i).map_or(last + 1, |e| e.lbn.min(last + 1));
+            match self.alloc_run(hole_end - lbn) {
+                Ok((start, len)) => {
+                    added.push(Extent { lbn, start, len });
+                    lbn += len;
+                }
+                Err(e) => {
+                    for block_no in added.iter().flat_map(|e| e.start..e.start + e.len) {
+                        self.free_block(block_no)?;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if !added.is_empty() {
+            extents.extend(added);
+            normalize(&mut extents);
+            self.store_extents(inode, &extents)?;
+        }
+        Ok((first..=last).map(|lbn| lookup(&extents, lbn)).collect())
+    }
+
+    /// Frees logical blocks `first..end` of `inode`, the ones that are allocated, and the nodes
+    /// of its extent tree that are no longer needed
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn free_extent_range(
+        &mut self,
+        inode: &mut IndirectInode,
+        first: u64,
+        end: u64,
+    ) -> Result<(), FileSystemError> {
+        let extents = self.extents(inode)?;
+        let mut kept = Vec::new();
+        for &e in &extents {
+            // The parts of `e` before and after the range survive
+            let head = first.saturating_sub(e.lbn).min(e.len);
+            let tail = (e.lbn + e.len).saturating_sub(end).min(e.len - head);
+            for block_no in e.start + head..e.start + e.len - tail {
+                self.free_block(block_no)?;
+            }
+            if head > 0 {
+                kept.push(Extent { len: head, ..e });
+            }
+            if tail > 0 {
+                let skip = e.len - tail;
+                kept.push(Extent {
+                    lbn: e.lbn + skip,
+                    start: e.start + skip,
+                    len: tail,
+                });
+            }
+        }
+        if kept != extents {
+            self.store_extents(inode, &kept)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::{Extent, INLINE_EXTENTS};
+    use crate::f_indirect_inodes::FileSystemF;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::test_support::{format_options, mkfs_f, BLOCK_SIZE};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-extents-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn sequential_file() {
+        let path = disk_prep_path("sequential_file");
+        let mut my_fs = mkfs_f(&path, &format_options(true));
+        assert!(my_fs.has_extents());
+
+        //A large sequential write is allocated contiguously, and becomes a single extent
+        let size = 60 * BLOCK_SIZE - 7;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_write(&mut ino, &buf, 0, 45 * BLOCK_SIZE).unwrap();
+        let tail = Buffer::new(data[45 * BLOCK_SIZE as usize..].to_vec().into_boxed_slice());
+        my_fs
+            .i_write(&mut ino, &tail, 45 * BLOCK_SIZE, size - 45 * BLOCK_SIZE)
+            .unwrap();
+        let extents = my_fs.extents(&ino).unwrap();
+        assert_eq!(
+            extents,
+            vec![Extent {
+                lbn: 0,
+                start: extents[0].start,
+                len: 60
+            }]
+        );
+        assert_eq!(ino.disk_node.indirect_block, 0);
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 60);
+
+        //The extents survive a remount
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemF::mountfs(dev).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let mut all = Buffer::new_zero(size);
+        assert_eq!(my_fs.i_read(&ino, &mut all, 0, size).unwrap(), size);
+        assert_eq!(all.contents_as_ref(), &data[..]);
+
+        //The file cannot grow beyond the free space, and a failed write allocates nothing
+        let big = Buffer::new_zero(11 * BLOCK_SIZE);
+        assert!(matches!(
+            my_fs.i_write(&mut ino, &big, size, 11 * BLOCK_SIZE),
+            Err(FileSystemError::AllocationError())
+        ));
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 10);
+
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+        assert_eq!(my_fs.extents(&ino).unwrap(), vec![]);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn extent_tree() {
+        let path = disk_prep_path("extent_tree");
+        let mut my_fs = mkfs_f(&path, &format_options(true));
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //Every other block gives an extent of its own; (300 - 24) / 24 = 11 of them fit in a leaf
+        for k in 0..INLINE_EXTENTS as u64 {
+            my_fs.bmap_alloc(&mut ino, 2 * k).unwrap();
+        }
+        assert_eq!(ino.disk_node.indirect_block, 0);
+        for k in INLINE_EXTENTS as u64..25 {
+            my_fs.bmap_alloc(&mut ino, 2 * k).unwrap();
+        }
+        assert_eq!(my_fs.extents(&ino).unwrap().len(), 25);
+        assert_ne!(ino.disk_node.indirect_block, 0);
+        //3 leaves and the index node above them
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 25 - 4);
+        for k in 0..25 {
+            assert_ne!(my_fs.bmap(&ino, 2 * k).unwrap(), 0);
+            assert_eq!(my_fs.bmap(&ino, 2 * k + 1).unwrap(), 0);
+        }
+
+        //The tree survives a remount, and truncation frees its nodes too
+        let extents = my_fs.extents(&ino).unwrap();
+        my_fs.i_put(&ino).unwrap();
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemF::mountfs(dev).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(my_fs.extents(&ino).unwrap(), extents);
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+        assert_eq!(ino.disk_node.indirect_block, 0);
+
+        //Appending block by block extends the last extent
+        let block = Buffer::new(vec![7; BLOCK_SIZE as usize].into_boxed_slice());
+        for k in 0..20 {
+            my_fs
+                .i_write(&mut ino, &block, k * BLOCK_SIZE, BLOCK_SIZE)
+                .unwrap();
+        }
+        assert_eq!(my_fs.extents(&ino).unwrap().len(), 1);
+        assert_eq!(ino.get_size(), 20 * BLOCK_SIZE);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
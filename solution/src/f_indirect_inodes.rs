@@ -37,21 +37,1057 @@
 //! or you want to explain your approach, write it down after the comments
 //! section. If you had no major issues and everything works, there is no need to write any comments.
 //!
-//! COMPLETED: NO
+//! COMPLETED: YES
 //!
 //! COMMENTS:
 //!
-//! ...
+//! `FileSystemF` keeps using the `FileSystem` of assignment b for everything that concerns blocks
+//! (allocation, the buffer cache, the log, ...), and stores its own `IndirectInode`s in the inode
+//! region. The inodes are bigger than the ones of the API, so the inode region needs more blocks;
+//! `sb_valid` checks this. Only the flat layout is supported, without block groups or an inode
+//! extension table, since those assume the inode format of the API.
+//! Besides the indirect block, inodes have a double- and a triple-indirect block, which hold the
+//! block numbers of indirect and double-indirect blocks respectively; `max_file_size` reports the
+//! file size this allows for a given superblock.
+//! File systems created with `MkfsOptions::extents` store extents in these fields instead, see
+//! `crate::extents`; the same maximum file size applies to them.
+//! Files can be sparse: writes past the end of a file leave the blocks in between unallocated,
+//! and those holes read as zeros (see `crate::sparse`).
+//! On file systems created with `MkfsOptions::reflink`, files can share data blocks, which are
+//! copied on the first write to them (see `crate::reflink`).
+//! With `MkfsOptions::checksums`, the inodes of this format are checksummed like the ones of the
+//! API (see `crate::checksum`).
 //!
 
+use crate::b_inode_support::FileSystem;
+use crate::extents::lookup;
+use crate::filesystem_errors::FileSystemError;
+use crate::options::{MkfsOptions, MountOptions};
+use crate::space::StatFs;
+use cplfs_api::controller::Device;
+use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeRWSupport, InodeSupport};
+use cplfs_api::types::{Block, Buffer, FType, InodeLike, SuperBlock, DIRECT_POINTERS};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
 /// You are free to choose the name for your file system. As we will use
 /// automated tests when grading your assignment, indicate here the name of
 /// your file system data type so we can just use `FSName` instead of
 /// having to manually figure out the name.
 ///
-pub type FSName = ();
+pub type FSName = FileSystemF;
+
+/// Size of a block number stored in an indirect block, in bytes
+const POINTER_SIZE: u64 = 8;
+
+/// Number of levels of indirection an inode supports: indirect, double- and triple-indirect
+const MAX_LEVELS: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+/// Disk representation of an inode with an indirect block, like the `DInode` of the API
+pub struct DIndirectInode {
+    /// Registers the file type
+    pub ft: FType,
+    /// Counts the number of links to this inode in the file system
+    pub nlink: u16,
+    /// Size of the file in bytes
+    pub size: u64,
+    /// Block numbers of the first `DIRECT_POINTERS` blocks of the file, 0 if not allocated
+    pub direct_blocks: [u64; DIRECT_POINTERS as usize],
+    /// Block number of the indirect block, which holds the block numbers of the blocks past the
+    /// direct ones; 0 if not allocated
+    pub indirect_block: u64,
+    /// Block number of the double-indirect block, which holds the block numbers of indirect
+    /// blocks for the blocks past the ones of `indirect_block`; 0 if not allocated
+    pub double_indirect_block: u64,
+    /// Block number of the triple-indirect block, which holds the block numbers of
+    /// double-indirect blocks for the blocks past the ones of `double_indirect_block`; 0 if not
+    /// allocated
+    pub triple_indirect_block: u64,
+}
+
+impl DIndirectInode {
+    /// Block number of the root of the tree of indirect blocks with `levels` levels (1 to
+    /// `MAX_LEVELS`)
+    fn tree_root(&self, levels: u32) -> u64 {
+        match levels {
+            1 => self.indirect_block,
+            2 => self.double_indirect_block,
+            _ => self.triple_indirect_block,
+        }
+    }
+
+    /// Mutable version of `tree_root`
+    fn tree_root_mut(&mut self, levels: u32) -> &mut u64 {
+        match levels {
+            1 => &mut self.indirect_block,
+            2 => &mut self.double_indirect_block,
+            _ => &mut self.triple_indirect_block,
+        }
+    }
+}
+
+lazy_static! {
+    /// Size of an inode with an indirect block on disk, in bytes.
+    pub static ref DINDIRECT_INODE_SIZE : u64 = bincode::serialize(&DIndirectInode::default()).unwrap().len() as u64;
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+/// An inode with an indirect block, together with its number, like the `Inode` of the API
+pub struct IndirectInode {
+    /// The number of the inode
+    pub inum: u64,
+    /// The inode as stored on disk
+    pub disk_node: DIndirectInode,
+}
+
+impl InodeLike for IndirectInode {
+    fn new(inum: u64, ft: &FType, nlink: u64, size: u64, blocks: &[u64]) -> Option<Self> {
+        if nlink > u16::MAX as u64 || blocks.len() > DIRECT_POINTERS as usize + 1 {
+            return None;
+        }
+        let mut disk_node = DIndirectInode {
+            ft: *ft,
+            nlink: nlink as u16,
+            size,
+            ..DIndirectInode::default()
+        };
+        for (i, &b) in blocks.iter().enumerate() {
+            match disk_node.direct_blocks.get_mut(i) {
+                Some(slot) => *slot = b,
+                None => disk_node.indirect_block = b,
+            }
+        }
+        Some(IndirectInode { inum, disk_node })
+    }
+
+    fn get_ft(&self) -> FType {
+        self.disk_node.ft
+    }
+
+    fn get_nlink(&self) -> u64 {
+        self.disk_node.nlink as u64
+    }
+
+    fn get_size(&self) -> u64 {
+        self.disk_node.size
+    }
+
+    /// Index `DIRECT_POINTERS` gives the indirect block, see the module documentation
+    fn get_block(&self, i: u64) -> u64 {
+        match i {
+            i if i < DIRECT_POINTERS => self.disk_node.direct_blocks[i as usize],
+            i if i == DIRECT_POINTERS => self.disk_node.indirect_block,
+            _ => 0,
+        }
+    }
+
+    fn get_inum(&self) -> u64 {
+        self.inum
+    }
+}
+
+/// Number of inodes with an indirect block that fit in a block
+fn inodes_per_block(sb: &SuperBlock) -> u64 {
+    sb.block_size / *DINDIRECT_INODE_SIZE
+}
+
+/// Number of block numbers that fit in an indirect block
+fn pointers_per_block(sb: &SuperBlock) -> u64 {
+    sb.block_size / POINTER_SIZE
+}
+
+/// Number of data blocks a tree of indirect blocks with `levels` levels can point to
+fn tree_blocks(sb: &SuperBlock, levels: u32) -> u64 {
+    pointers_per_block(sb).saturating_pow(levels)
+}
+
+/// Maximum number of blocks of a file
+pub fn max_blocks(sb: &SuperBlock) -> u64 {
+    (1..=MAX_LEVELS).fold(DIRECT_POINTERS, |total, levels| {
+        total.saturating_add(tree_blocks(sb, levels))
+    })
+}
+
+/// Maximum size of a file on a file system with superblock `sb`, in bytes
+pub fn max_file_size(sb: &SuperBlock) -> u64 {
+    max_blocks(sb).saturating_mul(sb.block_size)
+}
+
+#[derive(Debug)]
+/// File system whose inodes have an indirect block, built on top of the `FileSystem` of
+/// assignment b
+pub struct FileSystemF {
+    /// The file system whose block methods we reuse
+    pub fs: FileSystem,
+}
+
+impl FileSystemF {
+    /// Same as `mkfs`, but lets the caller pick some layout options
+    /// Block groups and inode extension tables are not supported.
+    pub fn mkfs_with_options<P: AsRef<Path>>(
+        path: P,
+        sb: &SuperBlock,
+        options: &MkfsOptions,
+    ) -> Result<Self, FileSystemError> {
+        if !FileSystemF::sb_valid(sb)
+            || options.blocks_per_group.is_some()
+            || options.extended_inodes
+        {
+            return Err(FileSystemError::InvalidSuperBlock());
+        }
+        let mut fs_f = FileSystemF {
+            fs: FileSystem::mkfs_with_options(path, sb, options)?,
+        };
+        // The inode region was formatted for the inodes of the API, overwrite it with ours
+        for i in sb.inodestart..sb.bmapstart {
+            fs_f.b_put(&Block::new_zero(i, sb.block_size))?;
+        }
+        for inum in 0..sb.ninodes {
+            fs_f.i_put(&IndirectInode {
+                inum,
+                ..IndirectInode::default()
+            })?;
+        }
+        Ok(fs_f)
+    }
+
+    /// Same as `mountfs`, but lets the caller pick some mount options
+    pub fn mountfs_with_options(
+        dev: Device,
+        options: &MountOptions,
+    ) -> Result<Self, FileSystemError> {
+        let fs = FileSystem::mountfs_with_options(dev, options)?;
+        if !FileSystemF::sb_valid(&fs.superblock) || fs.is_grouped() || fs.has_inode_ext() {
+            return Err(FileSystemError::InvalidSuperBlock());
+        }
+        Ok(FileSystemF { fs })
+    }
+
+    /// Unmounts the file system, reporting write errors, see `FileSystem::unmountfs_checked`
+    pub fn unmountfs_checked(self) -> Result<Device, (FileSystemError, Device)> {
+        self.fs.unmountfs_checked()
+    }
+
+    /// Reports the used and free space of this file system, see `FileSystem::statfs`
+    pub fn statfs(&self) -> Result<StatFs, FileSystemError> {
+        let mut free_inodes = 0;
+        for i in 1..self.fs.superblock.ninodes {
+            if self.i_get(i)?.get_ft() == FType::TFree {
+                free_inodes += 1;
+            }
+        }
+        self.fs.statfs_with(free_inodes)
+    }
+
+    /// Runs `f` as a single file system operation, see `FileSystem::in_op`
+    pub fn in_op<R>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<R, FileSystemError>,
+    ) -> Result<R, FileSystemError> {
+        let snapshot = self.fs.start_op();
+        let result = f(self);
+        self.fs.finish_op(snapshot, result)
+    }
+
+    /// Block and byte offset in that block of inode `inum`
+    fn inode_location(&self, inum: u64) -> (u64, u64) {
+        let sb = &self.fs.superblock;
+        let per_block = inodes_per_block(sb);
+        (
+            sb.inodestart + inum / per_block,
+            inum % per_block * *DINDIRECT_INODE_SIZE,
+        )
+    }
+
+    /// Reads the `index`th block number stored in block `block_no`
+    fn read_pointer(&self, block_no: u64, index: u64) -> Result<u64, FileSystemError> {
+        Ok(self
+            .b_get(block_no)?
+            .deserialize_from::<u64>(index * POINTER_SIZE)?)
+    }
+
+    /// Stores `pointer` as the `index`th block number in block `block_no`
+    fn write_pointer(
+        &mut self,
+        block_no: u64,
+        index: u64,
+        pointer: u64,
+    ) -> Result<(), FileSystemError> {
+        let mut block = self.b_get(block_no)?;
+        block.serialize_into(&pointer, index * POINTER_SIZE)?;
+        self.b_put(&block)
+    }
+
+    /// Allocates a zeroed block for inode `inum`, and returns its block number
+    pub(crate) fn alloc_block(&mut self, inum: u64) -> Result<u64, FileSystemError> {
+        Ok(self.fs.b_alloc_near(inum)? + self.fs.superblock.datastart)
+    }
+
+    /// Frees the block with block number `block_no`
+    pub(crate) fn free_block(&mut self, block_no: u64) -> Result<(), FileSystemError> {
+        self.b_free(block_no - self.fs.superblock.datastart)
+    }
+
+    /// Finds logical block `lbn` in the trees of indirect blocks: returns the number of levels of
+    /// the tree that holds it, and its index among the data blocks of that tree
+    fn locate(&self, lbn: u64) -> Result<(u32, u64), FileSystemError> {
+        let mut index = lbn - DIRECT_POINTERS;
+        for levels in 1..=MAX_LEVELS {
+            let blocks = tree_blocks(&self.fs.superblock, levels);
+            if index < blocks {
+                return Ok((levels, index));
+            }
+            index -= blocks;
+        }
+        Err(FileSystemError::AllocationError())
+    }
+
+    /// The slots to follow from the root of a tree with `levels` levels down to its data block
+    /// with index `index`
+    fn tree_path(&self, levels: u32, index: u64) -> Vec<u64> {
+        let ppb = pointers_per_block(&self.fs.superblock);
+        (0..levels)
+            .rev()
+            .map(|level| index / ppb.pow(level) % ppb)
+            .collect()
+    }
+
+    /// Block number of logical block `lbn` of `inode`, or 0 if it was not allocated
+    pub(crate) fn bmap(&self, inode: &IndirectInode, lbn: u64) -> Result<u64, FileSystemError> {
+        if self.has_extents() {
+            return Ok(lookup(&self.extents(inode)?, lbn));
+        }
+        if lbn < DIRECT_POINTERS {
+            return Ok(inode.disk_node.direct_blocks[lbn as usize]);
+        }
+        let (levels, index) = match self.locate(lbn) {
+            Ok(location) => location,
+            Err(_) => return Ok(0),
+        };
+        let mut block_no = inode.disk_node.tree_root(levels);
+        for slot in self.tree_path(levels, index) {
+            if block_no == 0 {
+                break;
+            }
+            block_no = self.read_pointer(block_no, slot)?;
+        }
+        Ok(block_no)
+    }
+
+    /// Like `bmap`, but allocates logical block `lbn`, and the indirect blocks on the way to it,
+    /// if needed
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn bmap_alloc(
+        &mut self,
+        inode: &mut IndirectInode,
+        lbn: u64,
+    ) -> Result<u64, FileSystemError> {
+        if self.has_extents() {
+            return Ok(self.extent_map_alloc(inode, lbn, lbn)?[0]);
+        }
+        let inum = inode.inum;
+        if lbn < DIRECT_POINTERS {
+            if inode.disk_node.direct_blocks[lbn as usize] == 0 {
+                inode.disk_node.direct_blocks[lbn as usize] = self.alloc_block(inum)?;
+            }
+            return Ok(inode.disk_node.direct_blocks[lbn as usize]);
+        }
+        let (levels, index) = self.locate(lbn)?;
+        let root = inode.disk_node.tree_root_mut(levels);
+        if *root == 0 {
+            *root = self.alloc_block(inum)?;
+        }
+        let mut block_no = *root;
+        for slot in self.tree_path(levels, index) {
+            let mut next = self.read_pointer(block_no, slot)?;
+            if next == 0 {
+                next = self.alloc_block(inum)?;
+                self.write_pointer(block_no, slot, next)?;
+            }
+            block_no = next;
+        }
+        Ok(block_no)
+    }
+
+    /// Block numbers of logical blocks `first..=last` of `inode`, 0 for the ones that were not
+    /// allocated
+    pub(crate) fn map_range(
+        &self,
+        inode: &IndirectInode,
+        first: u64,
+        last: u64,
+    ) -> Result<Vec<u64>, FileSystemError> {
+        if self.has_extents() {
+            let extents = self.extents(inode)?;
+            return Ok((first..=last).map(|lbn| lookup(&extents, lbn)).collect());
+        }
+        (first..=last).map(|lbn| self.bmap(inode, lbn)).collect()
+    }
+
+    /// Tells the read-ahead tracker which logical blocks `[first, last]` of `inode` were read,
+    /// and prefetches the blocks it asks for. Holes and blocks past the end of the file are skipped.
+    fn read_ahead(
+        &self,
+        inode: &IndirectInode,
+        first: u64,
+        last: u64,
+    ) -> Result<(), FileSystemError> {
+        let block_size = self.fs.superblock.block_size;
+        let ahead = self
+            .fs
+            .readahead
+            .borrow_mut()
+            .on_read(inode.inum, first, last);
+        let nblocks = (inode.get_size() + block_size - 1) / block_size;
+        let end = ahead.end.min(nblocks);
+        if ahead.start >= end {
+            return Ok(());
+        }
+        for b in self.map_range(inode, ahead.start, end - 1)? {
+            if b != 0 {
+                self.fs.prefetch(b)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like `map_range`, but allocates the blocks that were not allocated yet
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn map_range_alloc(
+        &mut self,
+        inode: &mut IndirectInode,
+        first: u64,
+        last: u64,
+    ) -> Result<Vec<u64>, FileSystemError> {
+        if self.has_extents() {
+            return self.extent_map_alloc(inode, first, last);
+        }
+        (first..=last)
+            .map(|lbn| self.bmap_alloc(inode, lbn))
+            .collect()
+    }
+
+    /// Number of blocks in the tree of indirect blocks rooted at block `block_no`, which has
+    /// `levels` levels (or 1 for a data block if `levels` is 0), the indirect blocks included
+    fn count_tree(&self, block_no: u64, levels: u32) -> Result<u64, FileSystemError> {
+        let mut count = 1;
+        if levels > 0 {
+            for slot in 0..pointers_per_block(&self.fs.superblock) {
+                let child = self.read_pointer(block_no, slot)?;
+                if child != 0 {
+                    count += self.count_tree(child, levels - 1)?;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Copies the tree of indirect blocks rooted at block `block_no`, which has `levels` levels,
+    /// for inode `inum`, and returns the block number of the copy
+    /// The data blocks are shared with the original tree instead of copied.
+    fn share_tree(
+        &mut self,
+        inum: u64,
+        block_no: u64,
+        levels: u32,
+    ) -> Result<u64, FileSystemError> {
+        let copy_no = self.alloc_block(inum)?;
+        let mut copy = self.b_get(copy_no)?;
+        for slot in 0..pointers_per_block(&self.fs.superblock) {
+            let mut child = self.read_pointer(block_no, slot)?;
+            if child == 0 {
+                continue;
+            }
+            if levels > 1 {
+                child = self.share_tree(inum, child, levels - 1)?;
+            } else {
+                self.share_block(child)?;
+            }
+            copy.serialize_into(&child, slot * POINTER_SIZE)?;
+        }
+        self.b_put(&copy)?;
+        Ok(copy_no)
+    }
+
+    /// Makes `dst`, which has no blocks, map the same data blocks as `src`, with indirect blocks
+    /// of its own; the data blocks get one more reference each
+    /// Only changes `dst` in memory; the caller has to write it back.
+    pub(crate) fn share_pointers(
+        &mut self,
+        src: &IndirectInode,
+        dst: &mut IndirectInode,
+    ) -> Result<(), FileSystemError> {
+        for (j, &block_no) in src.disk_node.direct_blocks.iter().enumerate() {
+            if block_no != 0 {
+                self.share_block(block_no)?;
+            }
+            dst.disk_node.direct_blocks[j] = block_no;
+        }
+        for levels in 1..=MAX_LEVELS {
+            let root = src.disk_node.tree_root(levels);
+            if root != 0 {
+                *dst.disk_node.tree_root_mut(levels) = self.share_tree(dst.inum, root, levels)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of blocks owned by `inode`, its indirect blocks or extent tree nodes included
+    pub fn allocated_blocks(&self, inode: &IndirectInode) -> Result<u64, FileSystemError> {
+        if self.has_extents() {
+            return self.extent_blocks(inode);
+        }
+        let direct = inode.disk_node.direct_blocks.iter().filter(|&&b| b != 0);
+        let mut count = direct.count() as u64;
+        for levels in 1..=MAX_LEVELS {
+            let root = inode.disk_node.tree_root(levels);
+            if root != 0 {
+                count += self.count_tree(root, levels)?;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Frees block `block_no`, which is the root of a tree of indirect blocks with `levels`
+    /// levels (or a data block if `levels` is 0), together with all blocks in the tree
+    fn free_tree(&mut self, block_no: u64, levels: u32) -> Result<(), FileSystemError> {
+        if levels > 0 {
+            for slot in 0..pointers_per_block(&self.fs.superblock) {
+                let child = self.read_pointer(block_no, slot)?;
+                if child != 0 {
+                    self.free_tree(child, levels - 1)?;
+                }
+            }
+        }
+        self.free_block(block_no)
+    }
+
+    /// Frees the blocks in the tree of indirect blocks rooted at block `block_no`, which has
+    /// `levels` levels, whose index among the data blocks of the tree is at least `keep`,
+    /// together with the indirect blocks that become empty; returns whether the whole tree was
+    /// freed
+    fn prune_tree(
+        &mut self,
+        block_no: u64,
+        levels: u32,
+        keep: u64,
+    ) -> Result<bool, FileSystemError> {
+        if keep == 0 {
+            self.free_tree(block_no, levels)?;
+            return Ok(true);
+        }
+        let span = tree_blocks(&self.fs.superblock, levels - 1);
+        let mut empty = true;
+        for slot in 0..pointers_per_block(&self.fs.superblock) {
+            let child = self.read_pointer(block_no, slot)?;
+            if child == 0 {
+                continue;
+            }
+            let child_keep = keep.saturating_sub(slot * span);
+            if child_keep < span && self.prune_tree(child, levels - 1, child_keep)? {
+                self.write_pointer(block_no, slot, 0)?;
+            } else {
+                empty = false;
+            }
+        }
+        if empty {
+            self.free_block(block_no)?;
+        }
+        Ok(empty)
+    }
+
+    /// Frees the blocks of `inode` from logical block `lbn` on, together with the indirect
+    /// blocks that become empty
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn free_blocks_from(
+        &mut self,
+        inode: &mut IndirectInode,
+        lbn: u64,
+    ) -> Result<(), FileSystemError> {
+        if self.has_extents() {
+            return self.free_extent_range(inode, lbn, u64::MAX);
+        }
+        for i in lbn.min(DIRECT_POINTERS) as usize..DIRECT_POINTERS as usize {
+            let block_no = inode.disk_node.direct_blocks[i];
+            if block_no != 0 {
+                self.free_block(block_no)?;
+                inode.disk_node.direct_blocks[i] = 0;
+            }
+        }
+        let mut first = DIRECT_POINTERS;
+        for levels in 1..=MAX_LEVELS {
+            let root = inode.disk_node.tree_root(levels);
+            if root != 0 && self.prune_tree(root, levels, lbn.saturating_sub(first))? {
+                *inode.disk_node.tree_root_mut(levels) = 0;
+            }
+            first = first.saturating_add(tree_blocks(&self.fs.superblock, levels));
+        }
+        Ok(())
+    }
+
+    /// Frees logical block `lbn` of `inode`, if it is allocated, together with the indirect
+    /// blocks that become empty
+    /// Only changes `inode` in memory; the caller has to write it back.
+    fn unmap_block(&mut self, inode: &mut IndirectInode, lbn: u64) -> Result<(), FileSystemError> {
+        if lbn < DIRECT_POINTERS {
+            let block_no = inode.disk_node.direct_blocks[lbn as usize];
+            if block_no != 0 {
+                self.free_block(block_no)?;
+                inode.disk_node.direct_blocks[lbn as usize] = 0;
+            }
+            return Ok(());
+        }
+        let (levels, index) = match self.locate(lbn) {
+            Ok(location) => location,
+            Err(_) => return Ok(()),
+        };
+        let mut path = Vec::new();
+        let mut block_no = inode.disk_node.tree_root(levels);
+        for slot in self.tree_path(levels, index) {
+            if block_no == 0 {
+                return Ok(());
+            }
+            path.push((block_no, slot));
+            block_no = self.read_pointer(block_no, slot)?;
+        }
+        if block_no == 0 {
+            return Ok(());
+        }
+        self.free_block(block_no)?;
+        // Walk back up, freeing the indirect blocks that no longer point anywhere
+        while let Some((node, slot)) = path.pop() {
+            self.write_pointer(node, slot, 0)?;
+            if self.b_get(node)?.contents_as_ref().iter().any(|&b| b != 0) {
+                return Ok(());
+            }
+            self.free_block(node)?;
+        }
+        *inode.disk_node.tree_root_mut(levels) = 0;
+        Ok(())
+    }
+
+    /// Frees logical blocks `first..end` of `inode`, the ones that are allocated, together with
+    /// the indirect blocks that become empty
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn free_range(
+        &mut self,
+        inode: &mut IndirectInode,
+        first: u64,
+        end: u64,
+    ) -> Result<(), FileSystemError> {
+        if self.has_extents() {
+            return self.free_extent_range(inode, first, end);
+        }
+        // Up to the last block a file can have, walking the trees is cheaper than every block
+        if end >= max_blocks(&self.fs.superblock) {
+            return self.free_blocks_from(inode, first);
+        }
+        for lbn in first..end {
+            self.unmap_block(inode, lbn)?;
+        }
+        Ok(())
+    }
+
+    /// Frees all blocks of `inode`, the indirect blocks included, and sets its size to 0
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn free_blocks(&mut self, inode: &mut IndirectInode) -> Result<(), FileSystemError> {
+        self.free_blocks_from(inode, 0)?;
+        inode.disk_node.size = 0;
+        Ok(())
+    }
+
+    /// Body of `i_write`, which runs inside a single file system operation
+    fn write_inode(
+        &mut self,
+        inode: &mut IndirectInode,
+        buf: &Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<(), FileSystemError> {
+        if n == 0 {
+            return Ok(());
+        }
+        let block_size = self.fs.superblock.block_size;
+        let first = off / block_size;
+        let blocks = self.map_range_alloc(inode, first, (off + n - 1) / block_size)?;
+        let data = buf.contents_as_ref();
+        let mut pos = off;
+        while pos < off + n {
+            let lbn = pos / block_size;
+            let block_no = self.unshare(inode, lbn, blocks[(lbn - first) as usize])?;
+            let start = pos % block_size;
+            let len = (block_size - start).min(off + n - pos);
+            let from = (pos - off) as usize;
+            let mut block = self.b_get(block_no)?;
+            block.write_data(&data[from..from + len as usize], start)?;
+            self.fs.data_put(&block)?;
+            pos += len;
+        }
+        inode.disk_node.size = inode.disk_node.size.max(off + n);
+        self.i_put(inode)
+    }
+}
+
+impl FileSysSupport for FileSystemF {
+    type Error = FileSystemError;
+
+    fn sb_valid(sb: &SuperBlock) -> bool {
+        let per_block = inodes_per_block(sb);
+        FileSystem::sb_valid(sb)
+            && per_block > 0
+            && sb.inodestart + (sb.ninodes + per_block - 1) / per_block <= sb.bmapstart
+    }
+
+    fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
+        FileSystemF::mkfs_with_options(path, sb, &MkfsOptions::default())
+    }
+
+    fn mountfs(dev: Device) -> Result<Self, Self::Error> {
+        FileSystemF::mountfs_with_options(dev, &MountOptions::default())
+    }
+
+    fn unmountfs(self) -> Device {
+        self.fs.unmountfs()
+    }
+}
+
+impl BlockSupport for FileSystemF {
+    fn b_get(&self, i: u64) -> Result<Block, Self::Error> {
+        self.fs.b_get(i)
+    }
+
+    fn b_put(&mut self, b: &Block) -> Result<(), Self::Error> {
+        self.fs.b_put(b)
+    }
+
+    fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_free(i)
+    }
+
+    fn b_zero(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_zero(i)
+    }
+
+    fn b_alloc(&mut self) -> Result<u64, Self::Error> {
+        self.fs.b_alloc()
+    }
+
+    fn sup_get(&self) -> Result<SuperBlock, Self::Error> {
+        self.fs.sup_get()
+    }
+
+    fn sup_put(&mut self, sup: &SuperBlock) -> Result<(), Self::Error> {
+        self.fs.sup_put(sup)
+    }
+}
+
+impl InodeSupport for FileSystemF {
+    type Inode = IndirectInode;
+
+    fn i_get(&self, i: u64) -> Result<Self::Inode, Self::Error> {
+        if i >= self.fs.superblock.ninodes {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        let (block_no, offset) = self.inode_location(i);
+        let block = self.b_get(block_no)?;
+        self.fs
+            .verify_inode(i, &block, offset, *DINDIRECT_INODE_SIZE)?;
+        let disk_node = block.deserialize_from::<DIndirectInode>(offset)?;
+        Ok(IndirectInode { inum: i, disk_node })
+    }
+
+    fn i_put(&mut self, ino: &Self::Inode) -> Result<(), Self::Error> {
+        if ino.inum >= self.fs.superblock.ninodes {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        let (block_no, offset) = self.inode_location(ino.inum);
+        let mut block = self.b_get(block_no)?;
+        block.serialize_into(&ino.disk_node, offset)?;
+        self.fs
+            .put_inode_checksum(ino.inum, &block, offset, *DINDIRECT_INODE_SIZE)?;
+        self.b_put(&block)
+    }
+
+    fn i_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.in_op(|fs| {
+            let mut ino = fs.i_get(i)?;
+            if ino.get_ft() == FType::TFree {
+                return Err(FileSystemError::AllreadyFreeError());
+            }
+            if ino.get_nlink() > 0 {
+                return Err(FileSystemError::INodeNotFreeable());
+            }
+            fs.free_blocks(&mut ino)?;
+            fs.fs.readahead.get_mut().forget(i);
+            ino.disk_node.ft = FType::TFree;
+            fs.i_put(&ino)
+        })
+    }
+
+    fn i_alloc(&mut self, ft: FType) -> Result<u64, Self::Error> {
+        self.in_op(|fs| {
+            for inum in 1..fs.fs.superblock.ninodes {
+                if fs.i_get(inum)?.get_ft() == FType::TFree {
+                    let disk_node = DIndirectInode {
+                        ft,
+                        ..DIndirectInode::default()
+                    };
+                    fs.i_put(&IndirectInode { inum, disk_node })?;
+                    return Ok(inum);
+                }
+            }
+            Err(FileSystemError::AllocationError())
+        })
+    }
+
+    fn i_trunc(&mut self, inode: &mut Self::Inode) -> Result<(), Self::Error> {
+        self.in_op(|fs| {
+            fs.free_blocks(inode)?;
+            fs.i_put(inode)
+        })
+    }
+}
+
+impl InodeRWSupport for FileSystemF {
+    fn i_read(
+        &self,
+        inode: &Self::Inode,
+        buf: &mut Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<u64, Self::Error> {
+        if off > inode.get_size() {
+            return Err(FileSystemError::ReadError());
+        }
+        let block_size = self.fs.superblock.block_size;
+        let end = (off + n.min(buf.len())).min(inode.get_size());
+        if end == off {
+            return Ok(0);
+        }
+        let first = off / block_size;
+        let last = (end - 1) / block_size;
+        let blocks = self.map_range(inode, first, last)?;
+        self.read_ahead(inode, first, last)?;
+        let mut pos = off;
+        while pos < end {
+            let start = pos % block_size;
+            let len = (block_size - start).min(end - pos);
+            let block_no = blocks[(pos / block_size - first) as usize];
+            let mut data = vec![0; len as usize];
+            if block_no != 0 {
+                self.b_get(block_no)?.read_data(&mut data, start)?;
+            }
+            buf.write_data(&data, pos - off)?;
+            pos += len;
+        }
+        Ok(end - off)
+    }
+
+    fn i_write(
+        &mut self,
+        inode: &mut Self::Inode,
+        buf: &Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<(), Self::Error> {
+        if n > buf.len() {
+            return Err(FileSystemError::ReadError());
+        }
+        let max = max_file_size(&self.fs.superblock);
+        if off.checked_add(n).map_or(true, |end| end > max) {
+            return Err(FileSystemError::AllocationError());
+        }
+        if n == 0 {
+            return Ok(());
+        }
+        let result = self.in_op(|fs| {
+            let block_size = fs.fs.superblock.block_size;
+            let (first, last) = (off / block_size, (off + n - 1) / block_size);
+            let before = fs.map_range(inode, first, last)?;
+            if let Err(e) = fs.write_inode(inode, buf, off, n) {
+                // Without a log nothing is rolled back, so the blocks mapped for the write are
+                // freed again here
+                for (lbn, _) in (first..).zip(before).filter(|&(_, b)| b == 0) {
+                    fs.free_range(inode, lbn, lbn + 1)?;
+                }
+                return Err(e);
+            }
+            Ok(())
+        });
+        if result.is_err() {
+            // Its new blocks are gone, and with a log the whole write was rolled back, so the
+            // inode on disk is the one to go on with
+            *inode = self.i_get(inode.inum)?;
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::{DIndirectInode, FileSystemF};
+    use crate::options::MountOptions;
+    use crate::test_support::{BLOCK_SIZE, SUPERBLOCK_F};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock, DIRECT_POINTERS};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-f-indirect-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn indirect_levels() {
+        let path = disk_prep_path("indirect_levels");
+        let too_small = SuperBlock {
+            bmapstart: 3,
+            ..SUPERBLOCK_F
+        };
+        assert!(!FileSystemF::sb_valid(&too_small));
+        let mut my_fs = FileSystemF::mkfs(&path, &SUPERBLOCK_F).unwrap();
+
+        //12 direct blocks, then 300 / 8 = 37 blocks through the indirect block, and the
+        //double-indirect block takes over after that
+        let single_end = (DIRECT_POINTERS + 37) * BLOCK_SIZE;
+        let size = single_end + 2 * BLOCK_SIZE;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_write(&mut ino, &buf, 0, single_end).unwrap();
+        let stat = my_fs.statfs().unwrap();
+        assert_eq!(stat.free_blocks, 70 - 49 - 1);
+        assert_eq!(stat.free_inodes, 4);
+        assert_eq!(ino.disk_node.double_indirect_block, 0);
+        let tail = Buffer::new(data[single_end as usize..].to_vec().into_boxed_slice());
+        my_fs
+            .i_write(&mut ino, &tail, single_end, size - single_end)
+            .unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 51 - 3);
+        assert_ne!(ino.disk_node.double_indirect_block, 0);
+
+        //Reads cross the boundaries between the levels
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemF::mountfs(dev).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(ino.get_size(), size);
+        for &boundary in &[DIRECT_POINTERS * BLOCK_SIZE, single_end] {
+            let mut part = Buffer::new_zero(20);
+            assert_eq!(
+                my_fs.i_read(&ino, &mut part, boundary - 10, 20).unwrap(),
+                20
+            );
+            assert_eq!(
+                part.contents_as_ref(),
+                &data[(boundary - 10) as usize..(boundary + 10) as usize]
+            );
+        }
+        let mut all = Buffer::new_zero(size);
+        assert_eq!(my_fs.i_read(&ino, &mut all, 0, size + 5).unwrap(), size);
+        assert_eq!(all.contents_as_ref(), &data[..]);
+
+        //A block in the triple-indirect tree takes the root and one block of each level
+        let triple_start = DIRECT_POINTERS + 37 + 37 * 37;
+        let free = my_fs.statfs().unwrap().free_blocks;
+        let lbn = triple_start + 5 * 37 * 37 + 2 * 37 + 3;
+        let block_no = my_fs.bmap_alloc(&mut ino, lbn).unwrap();
+        assert_eq!(my_fs.bmap(&ino, lbn).unwrap(), block_no);
+        assert_eq!(my_fs.bmap(&ino, lbn + 1).unwrap(), 0);
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free - 4);
+
+        //Truncation frees all trees of indirect blocks
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+        let ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(ino.get_block(DIRECT_POINTERS), 0);
+        assert_eq!(
+            ino.disk_node,
+            DIndirectInode {
+                ft: FType::TFile,
+                ..DIndirectInode::default()
+            }
+        );
+
+        //Without a log, a write that runs out of space frees the blocks it took again
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let big = Buffer::new_zero(71 * BLOCK_SIZE);
+        assert!(my_fs.i_write(&mut ino, &big, 0, big.len()).is_err());
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+        assert_eq!(ino.get_size(), 0);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn streaming_read() {
+        let path = disk_prep_path("streaming_read");
+        let mut my_fs = FileSystemF::mkfs(&path, &SUPERBLOCK_F).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let nblocks = DIRECT_POINTERS + 8;
+        let data = Buffer::new(vec![7; (nblocks * BLOCK_SIZE) as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &data, 0, data.len()).unwrap();
+
+        //Start with an empty cache
+        let dev = my_fs.unmountfs();
+        let options = MountOptions {
+            cache_blocks: 32,
+            readahead_blocks: 8,
+            ..MountOptions::default()
+        };
+        let my_fs = FileSystemF::mountfs_with_options(dev, &options).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+
+        let mut buf = Buffer::new_zero(BLOCK_SIZE);
+        assert_eq!(
+            my_fs.i_read(&ino, &mut buf, 0, BLOCK_SIZE).unwrap(),
+            BLOCK_SIZE
+        );
+        let stats = my_fs.fs.cache_stats();
+        assert_eq!(stats.prefetches, 2);
+        for i in 1..nblocks {
+            let off = i * BLOCK_SIZE;
+            assert_eq!(
+                my_fs.i_read(&ino, &mut buf, off, BLOCK_SIZE).unwrap(),
+                BLOCK_SIZE
+            );
+            assert_eq!(
+                buf.contents_as_ref(),
+                &data.contents_as_ref()[..BLOCK_SIZE as usize]
+            );
+        }
+        //Blocks behind the indirect block were prefetched as well, nothing past the end of the file
+        //was, and only the indirect block itself had to be read on demand
+        assert_eq!(my_fs.fs.cache_stats().prefetches, nblocks - 1);
+        assert_eq!(my_fs.fs.cache_stats().misses, stats.misses + 1);
 
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
 
+    #[test]
+    fn max_file_size() {
+        assert_eq!(
+            super::max_file_size(&SUPERBLOCK_F),
+            (12 + 37 + 37 * 37 + 37 * 37 * 37) * BLOCK_SIZE
+        );
+        let big = SuperBlock {
+            block_size: 4096,
+            ..SUPERBLOCK_F
+        };
+        assert_eq!(
+            super::max_file_size(&big),
+            (12 + 512 + 512 * 512 + 512 * 512 * 512) * 4096
+        );
+    }
+}
 
 // WARNING: DO NOT TOUCH THE BELOW CODE -- IT IS REQUIRED FOR TESTING -- YOU WILL LOSE POINTS IF I MANUALLY HAVE TO FIX YOUR TESTS
 #[cfg(all(test, any(feature = "f", feature = "all")))]
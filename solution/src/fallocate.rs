@@ -0,0 +1,190 @@
+//! Preallocation and hole punching for the file system of assignment f, like `fallocate`
+//!
+//! - `i_allocate` reserves the blocks of a byte range of a file ahead of the writes to it, so
+//!   that those writes cannot run out of space. The blocks are allocated like the ones of
+//!   `i_write`, which zeroes them, so the unwritten range reads as zeros. Unless `keep_size` is
+//!   set, the file grows to cover the range.
+//! - `i_punch_hole` deallocates a byte range in the middle of a file: the blocks that lie entirely
+//!   inside the range are freed, together with the indirect blocks or extent tree nodes that
+//!   become empty, and the part of the range in the other blocks is zeroed. The range becomes a
+//!   hole (see `crate::sparse`), and the size of the file does not change.
+
+use cplfs_api::fs::InodeSupport;
+use cplfs_api::types::InodeLike;
+
+use crate::f_indirect_inodes::{max_file_size, FileSystemF, IndirectInode};
+use crate::filesystem_errors::FileSystemError;
+
+impl FileSystemF {
+    /// Allocates the blocks of bytes `[off, off + len)` of `inode` that are not allocated yet,
+    /// and grows the file to `off + len` bytes unless `keep_size` is set
+    pub fn i_allocate(
+        &mut self,
+        inode: &mut IndirectInode,
+        off: u64,
+        len: u64,
+        keep_size: bool,
+    ) -> Result<(), FileSystemError> {
+        let max = max_file_size(&self.fs.superblock);
+        if off.checked_add(len).map_or(true, |end| end > max) {
+            return Err(FileSystemError::AllocationError());
+        }
+        if len == 0 {
+            return Ok(());
+        }
+        self.in_op(|fs| {
+            let block_size = fs.fs.superblock.block_size;
+            let (first, last) = (off / block_size, (off + len - 1) / block_size);
+            let before = fs.map_range(inode, first, last)?;
+            if let Err(e) = fs.map_range_alloc(inode, first, last) {
+                // Running out of space halfway leaves nothing allocated
+                for (lbn, _) in (first..).zip(before).filter(|&(_, b)| b == 0) {
+                    fs.free_range(inode, lbn, lbn + 1)?;
+                }
+                return Err(e);
+            }
+            if !keep_size {
+                inode.disk_node.size = inode.get_size().max(off + len);
+            }
+            fs.i_put(inode)
+        })
+    }
+
+    /// Deallocates bytes `[off, off + len)` of `inode`, which then read as zeros
+    pub fn i_punch_hole(
+        &mut self,
+        inode: &mut IndirectInode,
+        off: u64,
+        len: u64,
+    ) -> Result<(), FileSystemError> {
+        let end = off
+            .checked_add(len)
+            .ok_or_else(FileSystemError::IndexOutOfBounds)?;
+        // No block can be mapped past the largest file size, so there is nothing to free there
+        let end = end.min(max_file_size(&self.fs.superblock));
+        if end <= off {
+            return Ok(());
+        }
+        self.in_op(|fs| {
+            let block_size = fs.fs.superblock.block_size;
+            let first_whole = (off + block_size - 1) / block_size;
+            let end_whole = end / block_size;
+            // The blocks at the edges of the range that are only partly inside it
+            let mut partial = vec![off / block_size, (end - 1) / block_size];
+            partial.dedup();
+            for lbn in partial {
+                let start = off.max(lbn * block_size) - lbn * block_size;
+                let stop = end.min((lbn + 1) * block_size) - lbn * block_size;
+                let block_no = fs.bmap(inode, lbn)?;
+                if (start, stop) != (0, block_size) && block_no != 0 {
+                    let block_no = fs.unshare(inode, lbn, block_no)?;
+                    fs.fs.zero_data(block_no, start, stop)?;
+                }
+            }
+            if first_whole < end_whole {
+                fs.free_range(inode, first_whole, end_whole)?;
+            }
+            fs.i_put(inode)
+        })
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::sparse::FileStat;
+    use crate::test_support::{format_options, mkfs_f, BLOCK_SIZE};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, DIRECT_POINTERS};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-fallocate-".to_string() + name), "img")
+    }
+
+    /// Preallocates a file, fills it, and punches holes in it
+    fn fallocate(extents: bool) {
+        let path = disk_prep_path(&format!("fallocate-{}", extents));
+        let mut my_fs = mkfs_f(&path, &format_options(extents));
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //Preallocation reserves the blocks, with or without growing the file
+        let size = (DIRECT_POINTERS + 8) * BLOCK_SIZE;
+        my_fs.i_allocate(&mut ino, 0, 1000, true).unwrap();
+        assert_eq!(ino.get_size(), 0);
+        my_fs.i_allocate(&mut ino, 0, size, false).unwrap();
+        let meta = if extents { 0 } else { 1 };
+        assert_eq!(
+            my_fs.stat(&ino).unwrap(),
+            FileStat {
+                size,
+                blocks: 20 + meta
+            }
+        );
+        let mut read = Buffer::new_zero(size);
+        my_fs.i_read(&ino, &mut read, 0, size).unwrap();
+        assert!(read.contents_as_ref().iter().all(|&b| b == 0));
+
+        //Writing into the preallocated range needs no more blocks
+        let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, size).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 20 - meta);
+        assert!(my_fs
+            .i_allocate(&mut ino, 0, 71 * BLOCK_SIZE, false)
+            .is_err());
+        assert!(my_fs.i_allocate(&mut ino, u64::MAX, 2, false).is_err());
+        assert_eq!(
+            my_fs.stat(&ino).unwrap(),
+            FileStat {
+                size,
+                blocks: 20 + meta
+            }
+        );
+
+        //Punching frees the whole blocks and zeroes the partial ones
+        my_fs.i_punch_hole(&mut ino, 150, 3 * BLOCK_SIZE).unwrap();
+        assert_eq!(my_fs.stat(&ino).unwrap().blocks, 18 + meta);
+        assert_eq!(my_fs.seek_hole(&ino, 0).unwrap(), Some(BLOCK_SIZE));
+        assert_eq!(
+            my_fs.seek_data(&ino, BLOCK_SIZE).unwrap(),
+            Some(3 * BLOCK_SIZE)
+        );
+        my_fs.i_read(&ino, &mut read, 0, size).unwrap();
+        let read_data = read.contents_as_ref();
+        assert_eq!(&read_data[..150], &data[..150]);
+        assert!(read_data[150..1050].iter().all(|&b| b == 0));
+        assert_eq!(&read_data[1050..], &data[1050..]);
+
+        //A range inside a single block only zeroes it
+        my_fs
+            .i_punch_hole(&mut ino, 3 * BLOCK_SIZE + 10, 20)
+            .unwrap();
+        assert_eq!(my_fs.stat(&ino).unwrap().blocks, 18 + meta);
+
+        //Punching out everything past the direct blocks frees the indirect block as well
+        assert!(my_fs.i_punch_hole(&mut ino, 1, u64::MAX).is_err());
+        let off = DIRECT_POINTERS * BLOCK_SIZE;
+        my_fs.i_punch_hole(&mut ino, off, u64::MAX - off).unwrap();
+        assert_eq!(my_fs.stat(&ino).unwrap().blocks, 10);
+        assert_eq!(ino.disk_node.indirect_block, 0);
+        assert_eq!(ino.get_size(), size);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn fallocate_pointers() {
+        fallocate(false);
+    }
+
+    #[test]
+    fn fallocate_extents() {
+        fallocate(true);
+    }
+}
@@ -41,6 +41,61 @@ pub enum FileSystemError {
 
     ///Raised when reading a part of a block will result in an error
     ReadError(),
+
+    /// Raised when an operation writes more blocks than fit in the log
+    LogFull(),
+
+    /// Raised when a transaction is started on a file system without a log or not in journal mode,
+    /// or inside another operation
+    TransactionError(),
+
+    /// Raised when a path is not valid, see `PathSupport::valid_path`
+    InvalidPath(),
+
+    /// Raised when a directory entry with the given name exists already
+    EntryExists(),
+
+    /// Raised when removing a directory that still has entries other than "." and ".."
+    DirectoryNotEmpty(),
+
+    /// Raised when the credentials of the file system do not allow an operation on an inode
+    PermissionDenied(),
+
+    /// Raised when resolving a path follows more symbolic links than `MAX_SYMLINK_HOPS`
+    TooManyLinks(),
+
+    /// Raised when reading the target of an inode that is not a symbolic link
+    NotASymlink(),
+
+    /// Raised when an operation needs a feature the file system was not created with
+    FeatureNotSupported(),
+
+    /// Raised when an extended attribute name has no valid namespace prefix, or is too long
+    InvalidXattrName(),
+
+    /// Raised when an inode has no extended attribute with the given name
+    XattrNotFound(),
+
+    /// Raised when an extended attribute value is too long, or the attributes no longer fit
+    XattrNoSpace(),
+
+    /// Raised when the checksum of the inode with this number does not match its contents
+    CorruptInode(u64),
+
+    /// Raised when the checksum of the block with this number does not match its contents
+    CorruptBlock(u64),
+
+    /// Raised when `i_get` looks for an inode that is not in the inode cache
+    InodeNotCached(),
+
+    /// Raised when freeing an inode of which other handles are still around
+    InodeInUse(),
+
+    /// Raised when every entry of the inode cache is still referenced by other handles
+    InodeCacheFull(),
+
+    /// Raised when removing a link to the inode with this number, whose link count is already 0
+    NoLinksLeft(u64),
 }
 
 impl fmt::Display for FileSystemError {
@@ -69,7 +124,43 @@ impl fmt::Display for FileSystemError {
             FileSystemError::DirectoryNotFound() =>
                 write!(f,"Directory not found in the filesystem"),
             FileSystemError::ReadError() =>
-                write!(f,"Something went wrong reading a Block/Inode")
+                write!(f,"Something went wrong reading a Block/Inode"),
+            FileSystemError::LogFull() =>
+                write!(f,"The operation writes more blocks than fit in the log"),
+            FileSystemError::TransactionError() =>
+                write!(f,"A transaction needs a log in journal mode and cannot be nested in another operation"),
+            FileSystemError::InvalidPath() =>
+                write!(f,"The provided path is not valid"),
+            FileSystemError::EntryExists() =>
+                write!(f,"An entry with this name exists already"),
+            FileSystemError::DirectoryNotEmpty() =>
+                write!(f,"The directory is not empty"),
+            FileSystemError::PermissionDenied() =>
+                write!(f,"Permission denied"),
+            FileSystemError::TooManyLinks() =>
+                write!(f,"Too many levels of symbolic links"),
+            FileSystemError::NotASymlink() =>
+                write!(f,"The inode is not a symbolic link"),
+            FileSystemError::FeatureNotSupported() =>
+                write!(f,"The file system was not created with the features this operation needs"),
+            FileSystemError::InvalidXattrName() =>
+                write!(f,"The extended attribute name is not valid"),
+            FileSystemError::XattrNotFound() =>
+                write!(f,"No extended attribute with this name"),
+            FileSystemError::XattrNoSpace() =>
+                write!(f,"No room left for the extended attribute"),
+            FileSystemError::CorruptInode(inum) =>
+                write!(f,"Checksum mismatch in inode {}",inum),
+            FileSystemError::CorruptBlock(block_no) =>
+                write!(f,"Checksum mismatch in block {}",block_no),
+            FileSystemError::InodeNotCached() =>
+                write!(f,"The inode is not in the inode cache"),
+            FileSystemError::InodeInUse() =>
+                write!(f,"The inode is still in use elsewhere"),
+            FileSystemError::InodeCacheFull() =>
+                write!(f,"Every entry of the inode cache is still in use"),
+            FileSystemError::NoLinksLeft(inum) =>
+                write!(f,"Inode {} has no links left to remove",inum)
         }
     }
 }
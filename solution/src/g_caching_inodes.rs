@@ -62,21 +62,490 @@
 //! or you want to explain your approach, write it down after the comments
 //! section. If you had no major issues and everything works, there is no need to write any comments.
 //!
-//! COMPLETED: NO
+//! COMPLETED: YES
 //!
 //! COMMENTS:
 //!
-//! ...
+//! The cached inodes are `InodeCached(Arc<RwLock<Inode>>)` rather than `Rc<RefCell<Inode>>`, so
+//! that handles are `Send + Sync` and can be handed to worker threads. `Arc::strong_count` plays
+//! the role of `Rc::strong_count` when evicting and freeing. `FileSystemG` itself is `Send`; to
+//! share it across threads, wrap it in an `Arc<Mutex<_>>`, while the handles it gave out can be
+//! read and locked by the threads independently.
 //!
 
+use crate::b_inode_support::FileSystem;
+use crate::c_dirs_support::FileSystemC;
+use crate::filesystem_errors::FileSystemError;
+use cplfs_api::controller::Device;
+use cplfs_api::fs::{
+    BlockSupport, FileSysSupport, InodeCacheSupport, InodeRWSupport, InodeSupport,
+};
+use cplfs_api::types::{Block, Buffer, FType, Inode, InodeLike, SuperBlock};
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
 /// You are free to choose the name for your file system. As we will use
 /// automated tests when grading your assignment, indicate here the name of
 /// your file system data type so we can just use `FSName` instead of
 /// having to manually figure out the name.
-/// **TODO**: replace the below type by the type of your file system
-pub type FSName = ();
+pub type FSName = FileSystemG;
+
+/// Number of entries in the inode cache of `mkfs` and `mountfs`
+pub const DEFAULT_CACHE_ENTRIES: u64 = 5;
+
+#[derive(Debug, Default, Clone)]
+/// A shared handle to an inode in the inode cache
+/// Clones refer to the same inode, so changes through one of them are visible in all others.
+pub struct InodeCached(Arc<RwLock<Inode>>);
+
+impl InodeCached {
+    /// Locks the inode for reading
+    pub fn read(&self) -> RwLockReadGuard<'_, Inode> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the inode for writing
+    pub fn write(&self) -> RwLockWriteGuard<'_, Inode> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Is this handle the only one to its inode?
+    pub fn is_unique(&self) -> bool {
+        Arc::strong_count(&self.0) == 1
+    }
+
+    /// Do both handles refer to the same inode?
+    pub fn ptr_eq(&self, other: &InodeCached) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl From<Inode> for InodeCached {
+    fn from(inode: Inode) -> Self {
+        InodeCached(Arc::new(RwLock::new(inode)))
+    }
+}
+
+impl PartialEq for InodeCached {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr_eq(other) || *self.read() == *other.read()
+    }
+}
+
+impl Eq for InodeCached {}
+
+impl InodeLike for InodeCached {
+    fn new(inum: u64, ft: &FType, nlink: u64, size: u64, blocks: &[u64]) -> Option<Self> {
+        <Inode as InodeLike>::new(inum, ft, nlink, size, blocks).map(InodeCached::from)
+    }
+
+    fn get_ft(&self) -> FType {
+        self.read().get_ft()
+    }
+
+    fn get_nlink(&self) -> u64 {
+        self.read().get_nlink()
+    }
+
+    fn get_size(&self) -> u64 {
+        self.read().get_size()
+    }
+
+    fn get_block(&self, i: u64) -> u64 {
+        self.read().get_block(i)
+    }
+
+    fn get_inum(&self) -> u64 {
+        self.read().get_inum()
+    }
+}
+
+#[derive(Debug)]
+/// This is the filesystem structure for this part of the assignment project
+pub struct FileSystemG {
+    /// The file system with inode reading and writing that the cache sits on top of
+    pub fs: FileSystemC,
+    /// The entries of the inode cache, each of which is empty or holds an inode
+    cache: Vec<Option<InodeCached>>,
+}
+
+impl FileSystemG {
+    /// Puts an inode cache with `nb_cache_entries` entries on top of `fs`
+    fn create_filesystem(fs: FileSystem, nb_cache_entries: u64) -> Result<Self, FileSystemError> {
+        if nb_cache_entries == 0 {
+            return Err(FileSystemError::AllocationError());
+        }
+        Ok(FileSystemG {
+            fs: FileSystemC::create_filesystem(fs),
+            cache: vec![None; nb_cache_entries as usize],
+        })
+    }
+
+    /// The cached entry of inode `inum`, if any
+    fn lookup(&self, inum: u64) -> Option<&InodeCached> {
+        self.cache
+            .iter()
+            .flatten()
+            .find(|entry| entry.get_inum() == inum)
+    }
+
+    /// The first entry of the cache that may be replaced: an empty one, or else one without other
+    /// handles to it
+    fn victim(&self) -> Result<usize, FileSystemError> {
+        self.cache
+            .iter()
+            .position(Option::is_none)
+            .or_else(|| {
+                self.cache
+                    .iter()
+                    .position(|entry| entry.as_ref().map_or(false, InodeCached::is_unique))
+            })
+            .ok_or(FileSystemError::InodeCacheFull())
+    }
+
+    /// Puts `inode` in the entry `slot` of the cache, persisting the inode it evicts
+    fn insert(&mut self, slot: usize, inode: Inode) -> Result<InodeCached, FileSystemError> {
+        if let Some(old) = self.cache[slot].take() {
+            self.fs.i_put(&old.read())?;
+        }
+        let entry = InodeCached::from(inode);
+        self.cache[slot] = Some(entry.clone());
+        Ok(entry)
+    }
+
+    /// Rereads the cached entry of the inode of `inode` from disk, unless `inode` is that entry
+    /// This keeps the cache up to date after changes made through an inode that is not cached.
+    fn refresh(&mut self, inode: &InodeCached) -> Result<(), FileSystemError> {
+        let inum = inode.get_inum();
+        if let Some(entry) = self.lookup(inum) {
+            if !entry.ptr_eq(inode) {
+                *entry.write() = self.fs.i_get(inum)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes all cached inodes back to disk
+    pub fn flush_cache(&mut self) -> Result<(), FileSystemError> {
+        for entry in self.cache.iter().flatten() {
+            self.fs.i_put(&entry.read())?;
+        }
+        Ok(())
+    }
+
+    /// Same as `unmountfs`, but reports it when the cached inodes or the other pending changes
+    /// could not be written back, see `FileSystem::unmountfs_checked`
+    pub fn unmountfs_checked(mut self) -> Result<Device, (FileSystemError, Device)> {
+        let flushed = self.flush_cache();
+        let device = self.fs.unmountfs_checked()?;
+        match flushed {
+            Ok(()) => Ok(device),
+            Err(e) => Err((e, device)),
+        }
+    }
+}
+
+impl FileSysSupport for FileSystemG {
+    type Error = FileSystemError;
+
+    fn sb_valid(sb: &SuperBlock) -> bool {
+        FileSystemC::sb_valid(sb)
+    }
+
+    fn mkfs<P: AsRef<Path>>(path: P, sb: &SuperBlock) -> Result<Self, Self::Error> {
+        FileSystemG::mkfs_cached(path, sb, DEFAULT_CACHE_ENTRIES)
+    }
+
+    fn mountfs(dev: Device) -> Result<Self, Self::Error> {
+        FileSystemG::mountfs_cached(dev, DEFAULT_CACHE_ENTRIES)
+    }
+
+    fn unmountfs(self) -> Device {
+        // Errors while writing back are dropped, `unmountfs_checked` is the way to see them
+        match self.unmountfs_checked() {
+            Ok(device) => device,
+            Err((_, device)) => device,
+        }
+    }
+}
+
+impl BlockSupport for FileSystemG {
+    fn b_get(&self, i: u64) -> Result<Block, Self::Error> {
+        self.fs.b_get(i)
+    }
+
+    fn b_put(&mut self, b: &Block) -> Result<(), Self::Error> {
+        self.fs.b_put(b)
+    }
+
+    fn b_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_free(i)
+    }
+
+    fn b_zero(&mut self, i: u64) -> Result<(), Self::Error> {
+        self.fs.b_zero(i)
+    }
+
+    fn b_alloc(&mut self) -> Result<u64, Self::Error> {
+        self.fs.b_alloc()
+    }
+
+    fn sup_get(&self) -> Result<SuperBlock, Self::Error> {
+        self.fs.sup_get()
+    }
+
+    fn sup_put(&mut self, sup: &SuperBlock) -> Result<(), Self::Error> {
+        self.fs.sup_put(sup)
+    }
+}
+
+impl InodeSupport for FileSystemG {
+    type Inode = InodeCached;
+
+    fn i_get(&self, i: u64) -> Result<Self::Inode, Self::Error> {
+        self.lookup(i)
+            .cloned()
+            .ok_or(FileSystemError::InodeNotCached())
+    }
+
+    fn i_put(&mut self, ino: &Self::Inode) -> Result<(), Self::Error> {
+        self.fs.i_put(&ino.read())?;
+        self.refresh(ino)
+    }
+
+    fn i_free(&mut self, i: u64) -> Result<(), Self::Error> {
+        match self.lookup(i).cloned() {
+            Some(entry) => {
+                //Our own clone is the one other than the cache
+                if Arc::strong_count(&entry.0) > 2 {
+                    return Err(FileSystemError::InodeInUse());
+                }
+                if entry.get_nlink() > 0 {
+                    return Ok(());
+                }
+                if entry.get_ft() == FType::TFree {
+                    return Err(FileSystemError::AllreadyFreeError());
+                }
+                self.fs.i_put(&entry.read())?;
+                self.fs.i_free(i)?;
+                *entry.write() = self.fs.i_get(i)?;
+                Ok(())
+            }
+            None => {
+                let ino = self.fs.i_get(i)?;
+                if ino.get_nlink() > 0 {
+                    return Ok(());
+                }
+                if ino.get_ft() == FType::TFree {
+                    return Err(FileSystemError::AllreadyFreeError());
+                }
+                self.fs.i_free(i)
+            }
+        }
+    }
+
+    fn i_alloc(&mut self, ft: FType) -> Result<u64, Self::Error> {
+        //Make sure there is room in the cache before allocating on disk
+        let slot = self.victim()?;
+        let inum = self.fs.i_alloc(ft)?;
+        let ino = self.fs.i_get(inum)?;
+        match self.lookup(inum) {
+            Some(entry) => *entry.write() = ino,
+            None => {
+                self.insert(slot, ino)?;
+            }
+        }
+        Ok(inum)
+    }
+
+    fn i_trunc(&mut self, inode: &mut Self::Inode) -> Result<(), Self::Error> {
+        {
+            let mut ino = inode.write();
+            self.fs.i_put(&ino)?;
+            self.fs.i_trunc(&mut ino)?;
+        }
+        self.refresh(inode)
+    }
+}
+
+impl InodeRWSupport for FileSystemG {
+    fn i_read(
+        &self,
+        inode: &Self::Inode,
+        buf: &mut Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<u64, Self::Error> {
+        self.fs.i_read(&inode.read(), buf, off, n)
+    }
+
+    fn i_write(
+        &mut self,
+        inode: &mut Self::Inode,
+        buf: &Buffer,
+        off: u64,
+        n: u64,
+    ) -> Result<(), Self::Error> {
+        self.fs.i_write(&mut inode.write(), buf, off, n)?;
+        self.refresh(inode)
+    }
+}
+
+impl InodeCacheSupport for FileSystemG {
+    fn i_get_mut(&mut self, i: u64) -> Result<Self::Inode, Self::Error> {
+        if let Some(entry) = self.lookup(i) {
+            return Ok(entry.clone());
+        }
+        let slot = self.victim()?;
+        let ino = self.fs.i_get(i)?;
+        self.insert(slot, ino)
+    }
+
+    fn is_cached(&self, inum: u64) -> bool {
+        self.lookup(inum).is_some()
+    }
+
+    fn mkfs_cached<P: AsRef<Path>>(
+        path: P,
+        sb: &SuperBlock,
+        nb_cache_entries: u64,
+    ) -> Result<Self, Self::Error> {
+        FileSystemG::create_filesystem(FileSystem::mkfs(path, sb)?, nb_cache_entries)
+    }
+
+    fn mountfs_cached(dev: Device, nb_cache_entries: u64) -> Result<Self, Self::Error> {
+        FileSystemG::create_filesystem(FileSystem::mountfs(dev)?, nb_cache_entries)
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::{FileSystemG, InodeCached};
+    use cplfs_api::fs::{FileSysSupport, InodeCacheSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock};
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: 20,
+        ninodes: 10,
+        inodestart: 1,
+        ndatablocks: 15,
+        bmapstart: 4,
+        datastart: 5,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-g-caching-".to_string() + name), "img")
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    fn assert_send<T: Send>() {}
+
+    #[test]
+    fn thread_safe_types() {
+        assert_send_sync::<InodeCached>();
+        assert_send::<FileSystemG>();
+    }
+
+    #[test]
+    fn eviction_skips_referenced() {
+        let path = disk_prep_path("eviction");
+        let mut my_fs = FileSystemG::mkfs_cached(&path, &SUPERBLOCK_GOOD, 2).unwrap();
+
+        assert_eq!(my_fs.i_alloc(FType::TFile).unwrap(), 1);
+        assert_eq!(my_fs.i_alloc(FType::TFile).unwrap(), 2);
+        let i1 = my_fs.i_get(1).unwrap();
+        //Inode 1 comes first, but its handle keeps it in the cache
+        assert_eq!(my_fs.i_alloc(FType::TFile).unwrap(), 3);
+        assert!(my_fs.is_cached(1));
+        assert!(!my_fs.is_cached(2));
+        assert!(my_fs.i_get(2).is_err());
+
+        //With every entry referenced, nothing can be read into the cache
+        let i3 = my_fs.i_get_mut(3).unwrap();
+        assert!(my_fs.i_get_mut(2).is_err());
+        assert!(my_fs.i_alloc(FType::TFile).is_err());
+        drop(i3);
+        //The failed allocation did not use up an inode on disk
+        assert_eq!(my_fs.i_alloc(FType::TFile).unwrap(), 4);
+        assert_eq!(my_fs.i_get_mut(2).unwrap().get_inum(), 2);
+        assert!(my_fs.is_cached(1));
+
+        //Changes made through a handle are persisted on eviction
+        i1.write().disk_node.size = 42;
+        drop(i1);
+        my_fs.i_get_mut(3).unwrap();
+        assert!(!my_fs.is_cached(1));
+        assert_eq!(my_fs.i_get_mut(1).unwrap().get_size(), 42);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn unmount_errors() {
+        let path = disk_prep_path("unmount-errors");
+        let mut my_fs = FileSystemG::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+
+        //A cached inode that does not fit in the inode region cannot be written back
+        my_fs.cache[0] = InodeCached::new(100, &FType::TFile, 0, 0, &[]);
+        let (_, dev) = my_fs.unmountfs_checked().unwrap_err();
+
+        //`unmountfs` hands the device back all the same
+        let mut my_fs = FileSystemG::mountfs(dev).unwrap();
+        my_fs.cache[0] = InodeCached::new(100, &FType::TFile, 0, 0, &[]);
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn shared_across_threads() {
+        let path = disk_prep_path("threads");
+        let my_fs = FileSystemG::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let my_fs = Arc::new(Mutex::new(my_fs));
+
+        let workers: Vec<_> = (0..4u8)
+            .map(|t| {
+                let my_fs = Arc::clone(&my_fs);
+                thread::spawn(move || {
+                    let mut fs = my_fs.lock().unwrap();
+                    let inum = fs.i_alloc(FType::TFile).unwrap();
+                    let mut ino = fs.i_get_mut(inum).unwrap();
+                    let buf = Buffer::new(vec![t + 1; 100].into_boxed_slice());
+                    fs.i_write(&mut ino, &buf, 0, 100).unwrap();
+                    (inum, ino)
+                })
+            })
+            .collect();
+        let handles: Vec<_> = workers.into_iter().map(|w| w.join().unwrap()).collect();
+
+        //The handles the workers got alias the cached inodes
+        let mut fs = my_fs.lock().unwrap();
+        for (inum, ino) in handles {
+            assert_eq!(ino.get_size(), 100);
+            assert!(fs.i_get(inum).unwrap().ptr_eq(&ino));
+            let mut buf = Buffer::new_zero(100);
+            fs.i_read(&ino, &mut buf, 0, 100).unwrap();
+            let t = buf.contents_as_ref()[0];
+            assert!(buf.contents_as_ref().iter().all(|&b| b == t));
+            assert!(fs.i_free(inum).is_err());
+        }
+        drop(fs);
 
-// **TODO** define your own tests here.
+        let my_fs = Arc::try_unwrap(my_fs).unwrap().into_inner().unwrap();
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
 
 // WARNING: DO NOT TOUCH THE BELOW CODE -- IT IS REQUIRED FOR TESTING -- YOU WILL LOSE POINTS IF I MANUALLY HAVE TO FIX YOUR TESTS
 #[cfg(all(test, any(feature = "g", feature = "all")))]
@@ -2,14 +2,18 @@
 
 use cplfs_api::controller::Device;
 use cplfs_api::types::{
-    Block, DirEntry, FType, Inode, InodeLike, SuperBlock, DINODE_SIZE, DIRENTRY_SIZE, DIRNAME_SIZE,
+    Block, Buffer, DirEntry, FType, Inode, InodeLike, SuperBlock, DINODE_SIZE, DIRECT_POINTERS,
+    DIRENTRY_SIZE, DIRNAME_SIZE,
 };
 
 use crate::b_inode_support::FileSystem;
+use crate::bitmap::Bitmap;
 use cplfs_api::fs::{BlockSupport, InodeSupport};
 
 use crate::b_inode_support::FSName;
 use crate::filesystem_errors::FileSystemError;
+use crate::inode_ext::{inline_capacity, pack_inline, unpack_inline, INODE_FLAG_INLINE};
+use crate::superblock_ext::{write_sb_ext, SuperBlockExt};
 use anyhow::Error;
 use std::convert::TryInto;
 
@@ -22,6 +26,17 @@ pub fn write_sb(sb: &SuperBlock, dev: &mut Device) -> Result<(), FileSystemError
     Ok(())
 }
 
+/// Writes a Superblock extension into the given device, behind the superblock itself
+pub fn write_sb_ext_to_device(
+    ext: &SuperBlockExt,
+    dev: &mut Device,
+) -> Result<(), FileSystemError> {
+    let mut firstblock = dev.read_block(0)?;
+    write_sb_ext(&mut firstblock, ext)?;
+    dev.write_block(&firstblock)?;
+    Ok(())
+}
+
 /// Alocates bitmapregion given a sevice and a superblock
 pub fn allocate_bitmapregion(sb: &SuperBlock, dev: &mut Device) -> Result<(), FileSystemError> {
     let nbitmapblocks = get_nbitmapblocks(sb);
@@ -36,13 +51,9 @@ pub fn allocate_bitmapregion(sb: &SuperBlock, dev: &mut Device) -> Result<(), Fi
 
 /// Calculates the number of bitmapblocks given a superblock
 pub fn get_nbitmapblocks(sb: &SuperBlock) -> u64 {
-    let mut nbitmapblocks = sb.ndatablocks / sb.block_size;
-
-    if sb.ndatablocks % sb.block_size != 0 {
-        nbitmapblocks = nbitmapblocks + 1;
-        //if number is fraction add another block
-    }
-    return nbitmapblocks;
+    // Every bitmap block holds a bit for `8 * block_size` data blocks
+    let bits_per_block = sb.block_size * 8;
+    (sb.ndatablocks + bits_per_block - 1) / bits_per_block
 }
 
 /// Calculates the number of inode blocks given a superblock
@@ -83,57 +94,49 @@ pub fn allocate_dataregion(sb: &SuperBlock, dev: &mut Device) -> Result<(), File
     Ok(())
 }
 
-/// Calculates a ceratin position by bits,bytes and blocks index in the dataregion given a superblock and a index
-pub fn get_bit_byte_blockindex(
-    sb: &SuperBlock,
-    index: u64,
-) -> Result<(u8, u16, u64), FileSystemError> {
-    let mut blockindex = 0;
-    let mut bitindex = index;
-    while sb.block_size <= bitindex {
-        blockindex += 1;
-        bitindex -= sb.block_size;
-    }
-
-    let mut byteindex = 0;
-    while 8 <= bitindex {
-        byteindex += 1;
-        bitindex -= 8;
-    }
-    let mut bitindexsmall = 0;
-    while bitindex > 0 && bitindex < u8::max_value() as u64 {
-        bitindexsmall += 1;
-        bitindex -= 1;
-    }
-    // TO find the block where the bit must be changed
-
-    let bmblock_index = bitindex + sb.bmapstart + blockindex;
+/// Calculates in which bitmap block, and at which bit of that block, the bitmap bit of the
+/// datablock with index `index` is stored
+pub fn get_bit_blockindex(sb: &SuperBlock, index: u64) -> (u64, u64) {
+    let bits_per_block = sb.block_size * 8;
+    (
+        sb.bmapstart + index / bits_per_block,
+        index % bits_per_block,
+    )
+}
 
-    return Ok((bitindexsmall, byteindex, bmblock_index));
+/// Reads the given bitmap block of a filesystem as a `Bitmap`, together with the block itself
+pub fn read_bitmap_block<F: BlockSupport<Error = FileSystemError>>(
+    fs: &F,
+    sb: &SuperBlock,
+    bmblock_index: u64,
+) -> Result<(Block, Bitmap), FileSystemError> {
+    let block = fs.b_get(bmblock_index)?;
+    let bitmap = Bitmap::from_bytes(block.contents_as_ref(), sb.block_size * 8);
+    Ok((block, bitmap))
 }
 
 /// Sets the bitmap bit of a given filesystem that belongs to the datablock (data_index) to
 /// 1 if n = true, 0 if n is false
-pub fn set_bitmapbit(
+pub fn set_bitmapbit<F: BlockSupport<Error = FileSystemError>>(
+    fs: &mut F,
     sb: &SuperBlock,
-    dev: &mut Device,
     data_index: u64,
     n: bool,
 ) -> Result<(), FileSystemError> {
     check_data_index_outofbounds(sb, data_index)?;
+    let (bmblock_index, bit) = get_bit_blockindex(sb, data_index);
+    let (mut bmblock, mut bitmap) = read_bitmap_block(fs, sb, bmblock_index)?;
 
-    //let mut dev = fs.device.as_mut().ok_or_else(||FileSystemError::DeviceNotSet())?;
-
-    let temp = get_bit_byte_blockindex(sb, data_index)?;
-    let bitindex = temp.0;
-    let byteindex = temp.1;
-    let bmblock_index = temp.2;
-
-    let mut bmblock = read_block(dev, bmblock_index)?;
-
-    let to_write_block = set_bit_of_block(&mut bmblock, byteindex, bitindex, n)?;
-    write_block(dev, &to_write_block)?;
+    if n {
+        bitmap.set(bit);
+    } else if bitmap.get(bit) {
+        bitmap.clear(bit);
+    } else {
+        return Err(FileSystemError::AllreadyFreeError());
+    }
 
+    bmblock.write_data(&bitmap.to_bytes(), 0)?;
+    fs.b_put(&bmblock)?;
     Ok(())
 }
 
@@ -145,35 +148,73 @@ fn check_data_index_outofbounds(sb: &SuperBlock, data_index: u64) -> Result<(),
     Ok(())
 }
 
-/// Sets a certain bit of a block to 1 if n = true, 0 if n is false
-fn set_bit_of_block(
-    b: &mut Block,
-    byteindex: u16,
-    bitindex: u8,
-    n: bool,
-) -> Result<&mut Block, FileSystemError> {
-    let byte_array = b.contents_as_ref();
-    let byte = byte_array.get(usize::from(byteindex)).unwrap();
+/// Checks whether the bitmap bit that belongs to the datablock (data_index) is set
+pub fn get_bitmapbit<F: BlockSupport<Error = FileSystemError>>(
+    fs: &F,
+    sb: &SuperBlock,
+    data_index: u64,
+) -> Result<bool, FileSystemError> {
+    check_data_index_outofbounds(sb, data_index)?;
+    let (bmblock_index, bit) = get_bit_blockindex(sb, data_index);
+    let (_, bitmap) = read_bitmap_block(fs, sb, bmblock_index)?;
+    Ok(bitmap.get(bit))
+}
 
-    if (byte >> bitindex).trailing_ones() == 0 && !n {
-        return Err(FileSystemError::AllreadyFreeError());
+/// Calls `f` with every bitmap block that describes datablocks in [start, end), as a `Bitmap`,
+/// together with the range of bits of that bitmap that fall in [start, end) and the datablock
+/// index of the first bit of the block. Stops as soon as `f` returns something.
+fn scan_bitmap<F, R, G>(
+    fs: &F,
+    sb: &SuperBlock,
+    start: u64,
+    end: u64,
+    mut f: G,
+) -> Result<Option<R>, FileSystemError>
+where
+    F: BlockSupport<Error = FileSystemError>,
+    G: FnMut(&Bitmap, u64, u64, u64) -> Option<R>,
+{
+    let end = end.min(sb.ndatablocks);
+    let bits_per_block = sb.block_size * 8;
+    let mut pos = start;
+    while pos < end {
+        let (bmblock_index, bit) = get_bit_blockindex(sb, pos);
+        let block_first = pos - bit;
+        let local_end = (end - block_first).min(bits_per_block);
+        let (_, bitmap) = read_bitmap_block(fs, sb, bmblock_index)?;
+        if let Some(result) = f(&bitmap, bit, local_end, block_first) {
+            return Ok(Some(result));
+        }
+        pos = block_first + local_end;
     }
+    Ok(None)
+}
 
-    if n {
-        // to make bit 1
-        let mut mutator = 0b00000001u8;
-        mutator = mutator << bitindex; // FOR THE OR OPERATOR
-        let to_write_byte = &[(*byte | mutator)]; // FOR THE OR OPERATOR
-        b.write_data(to_write_byte, byteindex as u64)?;
-    } else {
-        // to make bit 0
-        let mut mutator = 0b11111110u8;
-        mutator = mutator.rotate_left(bitindex as u32);
-        let to_write_byte = &[(*byte & mutator)]; // FOR THE AND OPERATOR
-        b.write_data(to_write_byte, byteindex as u64)?;
-    }
+/// Finds the first datablock index in [start, end) whose bitmap bit is not set
+pub fn find_free_data_index<F: BlockSupport<Error = FileSystemError>>(
+    fs: &F,
+    sb: &SuperBlock,
+    start: u64,
+    end: u64,
+) -> Result<Option<u64>, FileSystemError> {
+    scan_bitmap(fs, sb, start, end, |bitmap, from, to, first| {
+        bitmap.find_first_zero(from, to).map(|bit| first + bit)
+    })
+}
 
-    return Ok(b);
+/// Counts the datablocks in [start, end) whose bitmap bit is not set
+pub fn count_free_data_blocks<F: BlockSupport<Error = FileSystemError>>(
+    fs: &F,
+    sb: &SuperBlock,
+    start: u64,
+    end: u64,
+) -> Result<u64, FileSystemError> {
+    let mut free = 0;
+    scan_bitmap(fs, sb, start, end, |bitmap, from, to, _| {
+        free += (to - from) - bitmap.count_ones_in(from, to);
+        None::<()>
+    })?;
+    Ok(free)
 }
 
 ///  Helper functions that reads the block at ith position of a given device
@@ -192,19 +233,6 @@ pub fn write_block(dev: &mut Device, b: &Block) -> Result<(), FileSystemError> {
     }
 }
 
-/// Finds the index of the bitmap that is not set to 0
-/// This index is where a new datablock is not in use and can be allocated
-pub fn get_bytesarray_free_index(byte_array: &[u8]) -> Result<u16, FileSystemError> {
-    let mut byte_index = 0;
-
-    for x in byte_array {
-        if x.count_zeros() > 0 {
-            return Ok(byte_index);
-        }
-        byte_index += 1;
-    }
-    Err(FileSystemError::AllocationError())
-}
 /// Checks whether the superblock is valid or not and returns the result of the check
 pub fn sb_valid(sb: &SuperBlock) -> bool {
     // Step 1: Check Order
@@ -272,6 +300,18 @@ pub fn get_inode_block(
 
 /// Truncate the given inode of a Filesystem
 pub fn trunc(fs: &mut FileSystem, ino: &mut Inode) -> Result<(), FileSystemError> {
+    // Inline contents do not own any blocks, the pointer area just has to be cleared. Files on
+    // a file system with inline data stay inline.
+    let mut ext = fs.i_ext_get(ino.inum)?;
+    if ext.flags & INODE_FLAG_INLINE != 0 {
+        if !fs.has_inline_data() {
+            ext.flags &= !INODE_FLAG_INLINE;
+            fs.i_ext_put(ino.inum, &ext)?;
+        }
+        ino.disk_node.direct_blocks = [0; DIRECT_POINTERS as usize];
+        ino.disk_node.size = 0;
+        return Ok(());
+    }
     let size = get_inode_block_size(fs, ino);
 
     for j in 0..size as usize {
@@ -341,6 +381,16 @@ pub fn get_direntries(
 ) -> Result<Vec<(DirEntry, u64)>, FileSystemError> {
     let mut list: Vec<(DirEntry, u64)> = vec![];
 
+    if fs.is_inline(inode.inum)? {
+        let area = unpack_inline(&inode.disk_node.direct_blocks, inline_capacity());
+        let area = Buffer::new(area.into_boxed_slice());
+        for i in 0..inline_capacity() / *DIRENTRY_SIZE {
+            let dir_offset = i * *DIRENTRY_SIZE;
+            list.push((area.deserialize_from::<DirEntry>(dir_offset)?, dir_offset));
+        }
+        return Ok(list);
+    }
+
     let dirs_per_block = fs.superblock.block_size / *DIRENTRY_SIZE;
     let size = get_inode_block_size(fs, inode);
 
@@ -368,6 +418,22 @@ pub fn write_dir(
     inode: &mut Inode,
     dir: &DirEntry,
 ) -> Result<u64, FileSystemError> {
+    if fs.is_inline(inode.inum)? {
+        // Fill the first unused slot of the pointer area, or move the entries to a block
+        let area = unpack_inline(&inode.disk_node.direct_blocks, inline_capacity());
+        let mut area = Buffer::new(area.into_boxed_slice());
+        for i in 0..inline_capacity() / *DIRENTRY_SIZE {
+            let dir_offset = i * *DIRENTRY_SIZE;
+            if area.deserialize_from::<DirEntry>(dir_offset)?.inum == 0 {
+                area.serialize_into(dir, dir_offset)?;
+                inode.disk_node.direct_blocks = pack_inline(area.contents_as_ref());
+                inode.disk_node.size = inode.get_size().max(dir_offset + *DIRENTRY_SIZE);
+                return Ok(dir_offset);
+            }
+        }
+        fs.spill_inline(inode)?;
+    }
+
     let size = inode.get_size();
     let size_after = size + *DIRENTRY_SIZE; // This is the size after adding the direntry
 
@@ -385,7 +451,7 @@ pub fn write_dir(
 
     if potential_dirs_room < dirs_needed {
         // we need a new block
-        let block_nr = fs.b_alloc()? + fs.superblock.datastart;
+        let block_nr = fs.b_alloc_near(inode.inum)? + fs.superblock.datastart;
         add_block_to_inode(inode, block_nr)?;
         fs.i_put(&inode)?; // update new inode in fs
     }
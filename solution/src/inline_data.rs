@@ -0,0 +1,247 @@
+//! Inline data for small files and directories
+//!
+//! On a file system created with `MkfsOptions::inline_data` (`FEATURE_INLINE_DATA`), new files
+//! and directories keep their contents in the `direct_blocks` of their inode, with
+//! `INODE_FLAG_INLINE` set in their extension record (see `crate::inode_ext`), for as long as the
+//! contents fit in `inline_capacity()` bytes. A directory that only holds "." and ".." then takes
+//! no data block at all.
+//!
+//! As soon as a write or a new directory entry grows the contents past the inline capacity, they
+//! move out to a data block (`spill_inline`), and the inode uses block pointers from then on.
+//! Truncation moves contents that fit back into the inode (`reinline`), but only on file systems
+//! mounted with `MountOptions::reinline`.
+//!
+//! `i_read`, `i_write` and the directory helpers (`get_direntries`, `write_dir`) understand both
+//! representations.
+
+use cplfs_api::fs::{BlockSupport, InodeSupport};
+use cplfs_api::types::{FType, Inode, InodeLike, DIRECT_POINTERS};
+
+use crate::b_inode_support::FileSystem;
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::trunc;
+use crate::inode_ext::{inline_capacity, pack_inline, unpack_inline, INODE_FLAG_INLINE};
+use crate::superblock_ext::FEATURE_INLINE_DATA;
+
+impl FileSystem {
+    /// Whether this file system was created with inline data
+    pub fn has_inline_data(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_INLINE_DATA)
+    }
+
+    /// Whether the contents of inode `inum` are stored inline
+    pub fn is_inline(&self, inum: u64) -> Result<bool, FileSystemError> {
+        Ok(self.i_ext_get(inum)?.flags & INODE_FLAG_INLINE != 0)
+    }
+
+    /// Moves the inline contents of `ino` to a newly allocated data block, after which `ino` uses
+    /// block pointers. Does nothing if `ino` is not inline.
+    pub fn spill_inline(&mut self, ino: &mut Inode) -> Result<(), FileSystemError> {
+        let mut ext = self.i_ext_get(ino.inum)?;
+        if ext.flags & INODE_FLAG_INLINE == 0 {
+            return Ok(());
+        }
+        self.in_op(|fs| {
+            let data = unpack_inline(&ino.disk_node.direct_blocks, ino.get_size());
+            ino.disk_node.direct_blocks = [0; DIRECT_POINTERS as usize];
+            if !data.is_empty() {
+                let block_no = fs.b_alloc_near(ino.inum)? + fs.superblock.datastart;
+                let mut block = fs.b_get(block_no)?;
+                block.write_data(&data, 0)?;
+                if ino.get_ft() == FType::TDir {
+                    fs.b_put(&block)?;
+                } else {
+                    fs.data_put(&block)?;
+                }
+                ino.disk_node.direct_blocks[0] = block_no;
+            }
+            ext.flags &= !INODE_FLAG_INLINE;
+            fs.i_ext_put(ino.inum, &ext)?;
+            fs.i_put(ino)
+        })
+    }
+
+    /// Moves the contents of `ino` back into the inode, freeing its data blocks, if the file
+    /// system is mounted with `MountOptions::reinline` and they fit
+    /// Only changes `ino` in memory; the caller has to write it back.
+    pub fn reinline(&mut self, ino: &mut Inode) -> Result<(), FileSystemError> {
+        let size = ino.get_size();
+        if !self.reinline || !self.has_inline_data() || size > inline_capacity() {
+            return Ok(());
+        }
+        let mut ext = self.i_ext_get(ino.inum)?;
+        if ext.flags & INODE_FLAG_INLINE != 0 {
+            return Ok(());
+        }
+        let mut data = vec![0; size as usize];
+        if size > 0 && ino.disk_node.direct_blocks[0] != 0 {
+            self.b_get(ino.disk_node.direct_blocks[0])?
+                .read_data(&mut data, 0)?;
+        }
+        trunc(self, ino)?;
+        ext.flags |= INODE_FLAG_INLINE;
+        self.i_ext_put(ino.inum, &ext)?;
+        ino.disk_node.direct_blocks = pack_inline(&data);
+        ino.disk_node.size = size;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::d_path_support::FileSystemD;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::{MkfsOptions, MountOptions};
+    use cplfs_api::fs::{
+        DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport, PathSupport,
+    };
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 41;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn inline() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            inline_data: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-inline-data-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn needs_inode_ext() {
+        let path = disk_prep_path("needs_inode_ext");
+        let options = MkfsOptions {
+            extended_inodes: false,
+            ..inline()
+        };
+        assert!(matches!(
+            FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &options),
+            Err(FileSystemError::InvalidSuperBlock())
+        ));
+    }
+
+    #[test]
+    fn inline_files() {
+        let path = disk_prep_path("inline_files");
+        let mut my_fs = FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &inline()).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        my_fs
+            .dirlink(&mut my_fs.i_get(1).unwrap(), "tiny", inum)
+            .unwrap();
+        let free = my_fs.fs.fs.statfs().unwrap().free_blocks;
+        assert_eq!(free, 32);
+
+        //Small writes stay in the inode
+        let data: Vec<u8> = (0..250).map(|i| i as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_write(&mut ino, &buf, 0, 20).unwrap();
+        let part = Buffer::new(data[20..90].to_vec().into_boxed_slice());
+        my_fs.i_write(&mut ino, &part, 20, 70).unwrap();
+        assert!(my_fs.fs.fs.is_inline(inum).unwrap());
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+        let mut read = Buffer::new_zero(100);
+        assert_eq!(my_fs.i_read(&ino, &mut read, 5, 100).unwrap(), 85);
+        assert_eq!(&read.contents_as_ref()[..85], &data[5..90]);
+        assert!(matches!(
+            my_fs.i_read(&ino, &mut read, 91, 1),
+            Err(FileSystemError::ReadError())
+        ));
+
+        //Growing past the inline capacity moves the contents to a data block
+        let rest = Buffer::new(data[90..].to_vec().into_boxed_slice());
+        my_fs.i_write(&mut ino, &rest, 90, 160).unwrap();
+        assert!(!my_fs.fs.fs.is_inline(inum).unwrap());
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free - 1);
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemD::mountfs(dev).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let mut all = Buffer::new_zero(250);
+        assert_eq!(my_fs.i_read(&ino, &mut all, 0, 250).unwrap(), 250);
+        assert_eq!(all.contents_as_ref(), &data[..]);
+
+        //Truncation only moves the contents back in when the file system is mounted so
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert!(!my_fs.fs.fs.is_inline(inum).unwrap());
+        let dev = my_fs.unmountfs();
+        let reinline = MountOptions {
+            reinline: true,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemD::mountfs_with_options(dev, &reinline).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_write(&mut ino, &buf, 0, 250).unwrap();
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free - 1);
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert!(my_fs.fs.fs.is_inline(inum).unwrap());
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+        my_fs.i_write(&mut ino, &buf, 0, 10).unwrap();
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+
+        //Freeing an inline file frees nothing but the inode
+        my_fs.unlink("/tiny").unwrap();
+        assert_eq!(my_fs.i_get(inum).unwrap().get_ft(), FType::TFree);
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn inline_directories() {
+        let path = disk_prep_path("inline_directories");
+        let mut my_fs = FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &inline()).unwrap();
+
+        //Directories with a few entries take no data blocks
+        let dir = my_fs.mkdir("/dir").unwrap();
+        assert!(my_fs.fs.fs.is_inline(1).unwrap());
+        assert!(my_fs.fs.fs.is_inline(dir.inum).unwrap());
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, 32);
+        assert_eq!(my_fs.resolve_path("/dir/..").unwrap().inum, 1);
+        my_fs.mkdir("/dir/a").unwrap();
+        assert!(my_fs.fs.fs.is_inline(dir.inum).unwrap());
+
+        //More entries move them to a data block, and lookups keep working
+        let names = ["b", "c", "d", "e"];
+        for name in &names {
+            my_fs.mkdir(&format!("/dir/{}", name)).unwrap();
+        }
+        assert!(!my_fs.fs.fs.is_inline(dir.inum).unwrap());
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, 31);
+        let dir = my_fs.i_get(dir.inum).unwrap();
+        for name in ["a"].iter().chain(names.iter()) {
+            let (ino, _) = my_fs.dirlookup(&dir, name).unwrap();
+            assert_eq!(ino.get_ft(), FType::TDir);
+        }
+
+        //Unlinking works on both representations
+        my_fs.unlink("/dir/a").unwrap();
+        my_fs.unlink("/dir/e").unwrap();
+        assert!(my_fs.resolve_path("/dir/a").is_err());
+        assert!(my_fs.resolve_path("/dir/b").is_ok());
+        my_fs.mkdir("/empty").unwrap();
+        my_fs.unlink("/empty").unwrap();
+        assert!(my_fs.resolve_path("/empty").is_err());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
@@ -0,0 +1,246 @@
+//! Extension of the inodes with the metadata the API `DInode` has no room for
+//!
+//! The `DInode` type of the API crate cannot be changed, so, like the `SuperBlockExt`, everything
+//! extra we want to store about an inode lives in an `InodeExt` record. The records are kept in
+//! the inode extension table, a region right behind the data region (and the log, if there is
+//! one), that `mkfs` creates when `MkfsOptions::extended_inodes` is set. Record `i` belongs to
+//! inode `i`; records never cross a block boundary.
+//! The table is read and written with `b_get` and `b_put`, so record updates go through the buffer
+//! cache and the log like any other metadata.
+//!
+//! File systems without the table behave as if every inode has the default (all zeroes) record,
+//! and drop any updates.
+
+use cplfs_api::controller::Device;
+use cplfs_api::types::{Block, SuperBlock, DIRECT_POINTERS};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::write_block;
+use crate::superblock_ext::{SuperBlockExt, FEATURE_LOG};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Extra metadata of a single inode, stored in the inode extension table
+pub struct InodeExt {
+    /// Time of the last read of the contents
+    pub atime: u64,
+    /// Time of the last change of the contents
+    pub mtime: u64,
+    /// Time of the last change of the inode: its contents, links or other metadata
+    pub ctime: u64,
+    /// Time at which the inode was allocated
+    pub crtime: u64,
+    /// User id of the owner
+    pub uid: u32,
+    /// Group id of the owning group
+    pub gid: u32,
+    /// Permission bits, see `crate::permissions`
+    pub mode: u32,
+    /// Combination of the `INODE_FLAG_*` bits
+    pub flags: u32,
+    /// Block holding the extended attributes, or 0 if there are none, see `crate::xattr`
+    pub xattr_block: u64,
+}
+
+/// Inode flag: the inode is a symbolic link, whose contents are the target path
+/// The API `FType` cannot be extended, so symbolic links are `TFile` inodes with this flag set.
+pub const INODE_FLAG_SYMLINK: u32 = 1;
+
+/// Inode flag: the contents are stored in the `direct_blocks` of the inode itself, instead of in
+/// the data blocks they point to
+pub const INODE_FLAG_INLINE: u32 = 2;
+
+/// Number of bytes that fit in the `direct_blocks` of an inode
+pub fn inline_capacity() -> u64 {
+    DIRECT_POINTERS * 8
+}
+
+/// Packs at most `inline_capacity()` bytes into a pointer area, padding it with zeroes
+pub fn pack_inline(data: &[u8]) -> [u64; DIRECT_POINTERS as usize] {
+    let mut area = [0; DIRECT_POINTERS as usize];
+    for (word, chunk) in area.iter_mut().zip(data.chunks(8)) {
+        let mut bytes = [0; 8];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        *word = u64::from_le_bytes(bytes);
+    }
+    area
+}
+
+/// Unpacks the first `len` bytes of a pointer area filled by `pack_inline`
+pub fn unpack_inline(area: &[u64; DIRECT_POINTERS as usize], len: u64) -> Vec<u8> {
+    let mut data: Vec<u8> = area.iter().flat_map(|word| word.to_le_bytes()).collect();
+    data.truncate(len.min(inline_capacity()) as usize);
+    data
+}
+
+lazy_static! {
+    /// Size of an inode extension record on disk, in bytes.
+    pub static ref INODE_EXT_SIZE : u64 = bincode::serialize(&InodeExt::default()).unwrap().len() as u64;
+}
+
+/// Number of records in a block of the table
+fn records_per_block(sb: &SuperBlock) -> u64 {
+    sb.block_size / *INODE_EXT_SIZE
+}
+
+/// Number of blocks the inode extension table needs
+pub fn inode_ext_blocks(sb: &SuperBlock) -> u64 {
+    match records_per_block(sb) {
+        0 => 0,
+        per_block => (sb.ninodes + per_block - 1) / per_block,
+    }
+}
+
+/// Checks whether the table described by the extension fits on the disk, behind the data region
+/// and the log
+pub fn inode_ext_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    let mut first_free = sb.datastart + sb.ndatablocks;
+    if ext.has_feature(FEATURE_LOG) {
+        first_free = first_free.max(ext.logstart + ext.nlog);
+    }
+    records_per_block(sb) > 0
+        && ext.iextstart >= first_free
+        && ext.niextblocks >= inode_ext_blocks(sb)
+        && ext.iextstart + ext.niextblocks <= sb.nblocks
+}
+
+/// Block and byte offset in that block of the record of inode `inum`
+pub fn inode_ext_location(sb: &SuperBlock, ext: &SuperBlockExt, inum: u64) -> (u64, u64) {
+    let per_block = records_per_block(sb);
+    (
+        ext.iextstart + inum / per_block,
+        inum % per_block * *INODE_EXT_SIZE,
+    )
+}
+
+/// Zeroes the inode extension table, so every inode starts with the default record
+pub fn format_inode_ext(
+    dev: &mut Device,
+    sb: &SuperBlock,
+    ext: &SuperBlockExt,
+) -> Result<(), FileSystemError> {
+    for i in ext.iextstart..ext.iextstart + ext.niextblocks {
+        write_block(dev, &Block::new_zero(i, sb.block_size))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::clock::ManualClock;
+    use crate::d_path_support::FileSystemD;
+    use crate::options::{MkfsOptions, MountOptions};
+    use cplfs_api::fs::{
+        DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport, PathSupport,
+    };
+    use cplfs_api::types::{Buffer, FType, SuperBlock, ROOT_INUM};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 41;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn extended() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-inode-ext-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn timestamps() {
+        let path = disk_prep_path("timestamps");
+        let clock = ManualClock::new(100);
+        let mut my_fs =
+            FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &extended()).unwrap();
+        my_fs.fs.set_clock(clock.clone());
+        assert!(my_fs.fs.fs.has_inode_ext());
+
+        //Allocation sets all timestamps
+        clock.set(200);
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let ext = my_fs.fs.i_ext_get(inum).unwrap();
+        assert_eq!(
+            (ext.atime, ext.mtime, ext.ctime, ext.crtime),
+            (200, 200, 200, 200)
+        );
+
+        //Writes change the contents, links only the inode
+        clock.set(300);
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let buf = Buffer::new(vec![1; 10].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, 10).unwrap();
+        let ext = my_fs.fs.i_ext_get(inum).unwrap();
+        assert_eq!((ext.mtime, ext.ctime, ext.crtime), (300, 300, 200));
+        clock.set(400);
+        let mut root = my_fs.i_get(ROOT_INUM).unwrap();
+        my_fs.dirlink(&mut root, "file", inum).unwrap();
+        let ext = my_fs.fs.i_ext_get(inum).unwrap();
+        assert_eq!((ext.mtime, ext.ctime), (300, 400));
+        assert_eq!(my_fs.fs.i_ext_get(ROOT_INUM).unwrap().mtime, 400);
+
+        //Reads only update the access time, which is written out on sync
+        clock.set(500);
+        let ino = my_fs.i_get(inum).unwrap();
+        let mut out = Buffer::new_zero(10);
+        assert_eq!(my_fs.i_read(&ino, &mut out, 0, 10).unwrap(), 10);
+        let ext = my_fs.fs.i_ext_get(inum).unwrap();
+        assert_eq!((ext.atime, ext.mtime), (500, 300));
+        my_fs.sync().unwrap();
+
+        //Truncation and unlinking
+        clock.set(600);
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_trunc(&mut ino).unwrap();
+        assert_eq!(my_fs.fs.i_ext_get(inum).unwrap().mtime, 600);
+        my_fs
+            .dirlink(&mut my_fs.i_get(ROOT_INUM).unwrap(), "link", inum)
+            .unwrap();
+        clock.set(700);
+        my_fs.unlink("/file").unwrap();
+        assert_eq!(my_fs.fs.i_ext_get(inum).unwrap().ctime, 700);
+        assert_eq!(my_fs.fs.i_ext_get(ROOT_INUM).unwrap().mtime, 700);
+
+        //The timestamps are stored in the image; noatime skips access time updates
+        let dev = my_fs.unmountfs();
+        let noatime = MountOptions {
+            noatime: true,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemD::mountfs_with_options(dev, &noatime).unwrap();
+        my_fs.fs.set_clock(clock.clone());
+        let ext = my_fs.fs.i_ext_get(inum).unwrap();
+        assert_eq!(
+            (ext.atime, ext.mtime, ext.ctime, ext.crtime),
+            (500, 600, 700, 200)
+        );
+        clock.set(800);
+        let ino = my_fs.i_get(inum).unwrap();
+        let buf = Buffer::new(vec![2; 10].into_boxed_slice());
+        my_fs
+            .i_write(&mut my_fs.i_get(inum).unwrap(), &buf, 0, 10)
+            .unwrap();
+        my_fs.i_read(&ino, &mut out, 0, 10).unwrap();
+        my_fs.sync().unwrap();
+        assert_eq!(my_fs.fs.i_ext_get(inum).unwrap().atime, 500);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
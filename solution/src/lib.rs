@@ -64,5 +64,31 @@ pub mod g_caching_inodes;
 
 // Declare additional modules below or declare them in other modules.
 
+pub mod alloc_bitmap;
+pub mod bitmap;
+pub mod block_groups;
+pub mod buddy;
+pub mod buffer_cache;
+pub mod checksum;
+pub mod clock;
+pub mod extents;
+pub mod fallocate;
 pub mod filesystem_errors;
 pub mod helpers;
+pub mod inline_data;
+pub mod inode_ext;
+pub mod options;
+pub mod permissions;
+pub mod readahead;
+pub mod reflink;
+pub mod secure_delete;
+pub mod space;
+pub mod sparse;
+pub mod superblock_ext;
+pub mod symlink;
+#[cfg(test)]
+mod test_support;
+pub mod transaction;
+pub mod truncate;
+pub mod wal;
+pub mod xattr;
@@ -0,0 +1,144 @@
+//! Options that can be passed when creating a file system
+//!
+//! The `mkfs` and `mountfs` methods of the `FileSysSupport` trait only take a superblock or a
+//! device, so the file systems in this crate offer `mkfs_with_options` and `mountfs_with_options`
+//! variants as well. Passing the default options to them is the same as calling `mkfs` or `mountfs`.
+
+use crate::buffer_cache::DEFAULT_CACHE_BLOCKS;
+use crate::readahead::DEFAULT_READAHEAD_BLOCKS;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Options that influence the on-disk layout chosen by `mkfs`
+pub struct MkfsOptions {
+    /// Split the data region into block groups of this many data blocks, ext2-style.
+    /// `None` keeps the flat layout of the base project.
+    pub blocks_per_group: Option<u64>,
+    /// Percentage (0 to 100) of the data blocks that is kept free for privileged allocations
+    pub reserved_percent: u64,
+    /// Size of the write-ahead log region, header included, which is put right behind the data
+    /// region. 0 means no log.
+    pub log_blocks: u64,
+    /// Create an inode extension table (see `crate::inode_ext`) behind the data region and the
+    /// log, which holds the timestamps of the inodes
+    pub extended_inodes: bool,
+    /// Store the blocks of files as extents instead of block pointers (see `crate::extents`).
+    /// Only the inodes of assignment f (`FileSystemF`) have this format.
+    pub extents: bool,
+    /// Keep the contents of small files and directories inside their inode (see
+    /// `crate::inline_data`). Needs `extended_inodes`, which holds the flag that marks them.
+    pub inline_data: bool,
+    /// Keep a reference count for every data block (see `crate::reflink`), so that files can
+    /// share blocks through `clone_file`
+    pub reflink: bool,
+    /// Keep a CRC32C of every inode and metadata block, that is verified whenever it is read (see
+    /// `crate::checksum`)
+    pub checksums: bool,
+    /// Keep a CRC32C of the blocks holding the contents of files as well. Needs `checksums`.
+    pub data_checksums: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// When changes to the in-memory allocation bitmap reach the disk
+pub enum BitmapWritePolicy {
+    /// Every allocation or free writes the changed bitmap block right away
+    WriteThrough,
+    /// Changed bitmap blocks are only written on `sync` and when unmounting
+    WriteBack,
+}
+
+impl Default for BitmapWritePolicy {
+    fn default() -> Self {
+        BitmapWritePolicy::WriteThrough
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// How free data blocks are picked
+pub enum AllocatorStrategy {
+    /// The first free block in the bitmap (of the preferred block group, if any)
+    FirstFit,
+    /// A buddy allocator, that hands out aligned power-of-two runs and merges them when freed.
+    /// It ignores the preferred block group.
+    Buddy,
+}
+
+impl Default for AllocatorStrategy {
+    fn default() -> Self {
+        AllocatorStrategy::FirstFit
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What happens to the contents of data blocks that are freed
+pub enum SecureDelete {
+    /// Freed blocks keep their contents until they are allocated again
+    Off,
+    /// Freed blocks are overwritten with this byte right away
+    Overwrite(u8),
+    /// Freed blocks are zeroed in batches, by `scrub_freed`, `sync` and when unmounting
+    Lazy,
+}
+
+impl Default for SecureDelete {
+    fn default() -> Self {
+        SecureDelete::Off
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Which blocks go through the write-ahead log, for file systems that have one (see `crate::wal`)
+/// Metadata (inodes, directories, the bitmap, group descriptors) is always logged; the modes
+/// differ in how the contents of files, as written by `i_write`, are handled.
+pub enum JournalMode {
+    /// File contents bypass the log and reach the disk whenever the buffer cache writes them back.
+    /// After a crash, a file may show stale contents in blocks that were just allocated to it.
+    Writeback,
+    /// File contents bypass the log, but are written to the disk before the transaction that
+    /// points to them commits
+    Ordered,
+    /// File contents go through the log like metadata, so they are committed atomically with it
+    Journal,
+}
+
+impl Default for JournalMode {
+    fn default() -> Self {
+        JournalMode::Journal
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Options that influence how a mounted file system behaves
+pub struct MountOptions {
+    /// When to write changes of the allocation bitmap to disk
+    pub bitmap_policy: BitmapWritePolicy,
+    /// How free data blocks are picked
+    pub allocator: AllocatorStrategy,
+    /// Whether freed data blocks are scrubbed
+    pub secure_delete: SecureDelete,
+    /// Number of blocks kept in the buffer cache, 0 turns the cache off
+    pub cache_blocks: usize,
+    /// Largest number of blocks that is read ahead for sequential reads, 0 turns read-ahead off
+    pub readahead_blocks: u64,
+    /// Which blocks go through the write-ahead log
+    pub journal_mode: JournalMode,
+    /// Do not update the access time of inodes when they are read
+    pub noatime: bool,
+    /// Move the contents of a file back into its inode when a truncate shrinks it enough, on file
+    /// systems with inline data
+    pub reinline: bool,
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            bitmap_policy: BitmapWritePolicy::default(),
+            allocator: AllocatorStrategy::default(),
+            secure_delete: SecureDelete::default(),
+            cache_blocks: DEFAULT_CACHE_BLOCKS,
+            readahead_blocks: DEFAULT_READAHEAD_BLOCKS,
+            journal_mode: JournalMode::default(),
+            noatime: false,
+            reinline: false,
+        }
+    }
+}
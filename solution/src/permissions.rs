@@ -0,0 +1,201 @@
+//! Ownership and permission bits of inodes
+//!
+//! On file systems with an inode extension table (see `crate::inode_ext`) every inode records an
+//! owner, a group and a mode word with the usual POSIX `rwx` bits for the owner, the group and
+//! all others. The file system runs with a set of `Credentials`, which decide what path
+//! operations are allowed to do:
+//! - every directory that is searched while resolving a path needs execute (search) permission,
+//! - creating or removing an entry needs write and search permission on its directory,
+//! - reading and writing the contents of an inode needs read or write permission on it.
+//!
+//! The root user (uid 0) may do anything. File systems without the table have nowhere to store
+//! ownership, so they skip all checks.
+
+use cplfs_api::types::FType;
+
+use crate::inode_ext::InodeExt;
+
+/// Permission to read the contents of an inode, or list a directory
+pub const MAY_READ: u32 = 4;
+/// Permission to write the contents of an inode, or add and remove entries of a directory
+pub const MAY_WRITE: u32 = 2;
+/// Permission to search a directory
+pub const MAY_EXEC: u32 = 1;
+
+/// All bits of the mode word that are meaningful
+pub const MODE_MASK: u32 = 0o777;
+
+/// Mode of newly created directories
+pub const DEFAULT_DIR_MODE: u32 = 0o755;
+/// Mode of newly created files
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// The user id of the root user
+pub const ROOT_UID: u32 = 0;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// The user on whose behalf path operations run
+pub struct Credentials {
+    /// User id
+    pub uid: u32,
+    /// Primary group id, given to the inodes this user creates
+    pub gid: u32,
+    /// Supplementary group ids
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// Credentials of the root user, which the file system runs with by default
+    pub fn root() -> Credentials {
+        Credentials::default()
+    }
+
+    /// Credentials of user `uid` with primary group `gid` and no supplementary groups
+    pub fn new(uid: u32, gid: u32) -> Credentials {
+        Credentials {
+            uid,
+            gid,
+            groups: vec![],
+        }
+    }
+
+    /// Whether these are the credentials of the root user
+    pub fn is_root(&self) -> bool {
+        self.uid == ROOT_UID
+    }
+
+    /// Whether the user is a member of group `gid`
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Whether the user may access an inode with extension record `ext` in the ways in `want`,
+    /// a combination of `MAY_READ`, `MAY_WRITE` and `MAY_EXEC`
+    pub fn permits(&self, ext: &InodeExt, want: u32) -> bool {
+        if self.is_root() {
+            return true;
+        }
+        let bits = if self.uid == ext.uid {
+            ext.mode >> 6
+        } else if self.in_group(ext.gid) {
+            ext.mode >> 3
+        } else {
+            ext.mode
+        };
+        bits & want == want
+    }
+}
+
+/// Mode that new inodes of type `ft` get
+pub fn default_mode(ft: FType) -> u32 {
+    match ft {
+        FType::TDir => DEFAULT_DIR_MODE,
+        _ => DEFAULT_FILE_MODE,
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::Credentials;
+    use crate::d_path_support::FileSystemD;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::MkfsOptions;
+    use cplfs_api::fs::{
+        DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport, PathSupport,
+    };
+    use cplfs_api::types::{Buffer, FType, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 41;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn extended() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-permissions-".to_string() + name), "img")
+    }
+
+    fn denied<T>(result: Result<T, FileSystemError>) -> bool {
+        matches!(result, Err(FileSystemError::PermissionDenied()))
+    }
+
+    #[test]
+    fn permissions() {
+        let path = disk_prep_path("permissions");
+        let mut my_fs =
+            FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &extended()).unwrap();
+        let alice = Credentials::new(1000, 100);
+        let bob = Credentials::new(1001, 100);
+        let eve = Credentials::new(1002, 200);
+
+        //Only root may create entries in the root directory
+        assert!(denied(
+            my_fs.with_credentials(alice.clone(), |fs| fs.mkdir("/home"))
+        ));
+        my_fs.mkdir("/home").unwrap();
+        my_fs.chown("/home", 1000, 100).unwrap();
+        let ext = my_fs
+            .fs
+            .i_ext_get(my_fs.resolve_path("/home").unwrap().inum)
+            .unwrap();
+        assert_eq!((ext.uid, ext.gid, ext.mode), (1000, 100, 0o755));
+
+        //New inodes belong to their creator
+        my_fs.set_credentials(alice.clone());
+        let mut dir = my_fs.mkdir("/home/alice").unwrap();
+        let ext = my_fs.fs.i_ext_get(dir.inum).unwrap();
+        assert_eq!((ext.uid, ext.gid, ext.mode), (1000, 100, 0o755));
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        my_fs.dirlink(&mut dir, "notes", inum).unwrap();
+        let mut file = my_fs.resolve_path("/home/alice/notes").unwrap();
+        let data = Buffer::new(vec![7; 10].into_boxed_slice());
+        my_fs.i_write(&mut file, &data, 0, 10).unwrap();
+        assert!(denied(my_fs.chown("/home/alice", 1001, 100)));
+        assert!(denied(my_fs.chown("/home/alice", 1000, 200)));
+        my_fs.chmod("/home/alice/notes", 0o640).unwrap();
+
+        //Group members may read, but not write; others may do neither
+        let mut buf = Buffer::new_zero(10);
+        my_fs.set_credentials(bob);
+        assert_eq!(my_fs.i_read(&file, &mut buf, 0, 10).unwrap(), 10);
+        assert!(denied(my_fs.i_write(&mut file, &data, 0, 10)));
+        assert!(denied(my_fs.unlink("/home/alice/notes")));
+        assert!(denied(my_fs.chmod("/home/alice/notes", 0o666)));
+        my_fs.set_credentials(eve);
+        assert!(denied(my_fs.i_read(&file, &mut buf, 0, 10)));
+
+        //Directories that cannot be searched cannot be traversed
+        my_fs.set_credentials(alice);
+        my_fs.chmod("/home/alice", 0o700).unwrap();
+        my_fs.set_credentials(Credentials::new(1001, 300));
+        assert!(my_fs.resolve_path("/home/alice").is_ok());
+        assert!(denied(my_fs.resolve_path("/home/alice/notes")));
+        my_fs.set_cwd("/home/alice").unwrap();
+        assert!(denied(my_fs.resolve_path("./notes")));
+
+        //Root may do anything
+        my_fs.set_credentials(Credentials::root());
+        my_fs.unlink("/home/alice/notes").unwrap();
+        assert!(my_fs.resolve_path("./notes").is_err());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
@@ -0,0 +1,181 @@
+//! Sequential read-ahead
+//!
+//! A `ReadAhead` watches the logical blocks that are read from every inode. When a read starts
+//! right where the previous read of the same inode ended (or at the start of the file, for the
+//! first read), the access is considered sequential and the next blocks of the file are
+//! prefetched into the buffer cache, so the following reads hit.
+//! The window starts at `INITIAL_WINDOW` blocks, doubles with every sequential read, and is capped
+//! at the maximum given by the mount options. A read anywhere else resets the window.
+//! Every block is only prefetched once per sequential stream.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Number of blocks that are prefetched after the first sequential read
+pub const INITIAL_WINDOW: u64 = 2;
+
+/// Maximum read-ahead window when the mount options do not say otherwise
+pub const DEFAULT_READAHEAD_BLOCKS: u64 = 16;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Read-ahead state of a single inode
+struct Stream {
+    /// Logical block right after the last block that was read
+    next: u64,
+    /// Current window, 0 while the access pattern does not look sequential
+    window: u64,
+    /// Logical block right after the last block that was prefetched
+    ahead_until: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Read-ahead state of all inodes of a file system
+pub struct ReadAhead {
+    /// Largest window, 0 turns read-ahead off
+    max_window: u64,
+    /// Streams by inode number
+    streams: HashMap<u64, Stream>,
+}
+
+impl ReadAhead {
+    /// Creates a read-ahead tracker whose window grows to at most `max_window` blocks
+    pub fn new(max_window: u64) -> ReadAhead {
+        ReadAhead {
+            max_window,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Registers a read of the logical blocks `[first, last]` of inode `inum`, and returns the
+    /// logical blocks that should be prefetched now
+    pub fn on_read(&mut self, inum: u64, first: u64, last: u64) -> Range<u64> {
+        if self.max_window == 0 {
+            return 0..0;
+        }
+        let stream = self.streams.entry(inum).or_default();
+        // Reads that continue in the block where the previous read ended count as sequential too
+        let sequential = first == stream.next || first + 1 == stream.next;
+        if sequential && last + 1 == stream.next {
+            return 0..0;
+        }
+        if sequential {
+            let initial = INITIAL_WINDOW.min(self.max_window);
+            stream.window = (stream.window * 2).max(initial).min(self.max_window);
+        } else {
+            stream.window = 0;
+            stream.ahead_until = 0;
+        }
+        stream.next = last + 1;
+
+        let end = stream.next + stream.window;
+        let start = stream.next.max(stream.ahead_until);
+        stream.ahead_until = stream.ahead_until.max(end);
+        start..end.max(start)
+    }
+
+    /// Forgets the stream of inode `inum`, e.g. because it was freed
+    pub fn forget(&mut self, inum: u64) {
+        self.streams.remove(&inum);
+    }
+}
+
+#[cfg(test)]
+mod readahead_tests {
+    use super::ReadAhead;
+
+    #[test]
+    fn adaptive_window() {
+        let mut ra = ReadAhead::new(8);
+        assert_eq!(ra.on_read(1, 0, 0), 1..3);
+        //More reads in the same block change nothing
+        assert_eq!(ra.on_read(1, 0, 0), 0..0);
+        //The window doubles, and blocks are only prefetched once
+        assert_eq!(ra.on_read(1, 1, 1), 3..6);
+        assert_eq!(ra.on_read(1, 2, 3), 6..12);
+        assert_eq!(ra.on_read(1, 4, 4), 12..13);
+        //A jump resets the window
+        assert_eq!(ra.on_read(1, 20, 20), 21..21);
+        assert_eq!(ra.on_read(1, 21, 21), 22..24);
+        //Inodes are tracked separately
+        assert_eq!(ra.on_read(2, 0, 1), 2..4);
+        ra.forget(1);
+        assert_eq!(ra.on_read(1, 5, 5), 6..6);
+
+        let mut off = ReadAhead::new(0);
+        assert_eq!(off.on_read(1, 0, 0), 0..0);
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::c_dirs_support::FileSystemC;
+    use crate::options::MountOptions;
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-readahead-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn streaming_read() {
+        let path = disk_prep_path("streaming_read");
+        let mut my_fs = FileSystemC::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let data = Buffer::new(vec![7; 6 * BLOCK_SIZE as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &data, 0, data.len()).unwrap();
+
+        //Start with an empty cache
+        let dev = my_fs.unmountfs();
+        let options = MountOptions {
+            cache_blocks: 16,
+            readahead_blocks: 8,
+            ..MountOptions::default()
+        };
+        let my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+
+        let mut buf = Buffer::new_zero(BLOCK_SIZE);
+        assert_eq!(
+            my_fs.i_read(&ino, &mut buf, 0, BLOCK_SIZE).unwrap(),
+            BLOCK_SIZE
+        );
+        let stats = my_fs.cache_stats();
+        assert_eq!(stats.prefetches, 2);
+        for i in 1..6 {
+            let off = i * BLOCK_SIZE;
+            assert_eq!(
+                my_fs.i_read(&ino, &mut buf, off, BLOCK_SIZE).unwrap(),
+                BLOCK_SIZE
+            );
+            assert_eq!(
+                buf.contents_as_ref(),
+                &data.contents_as_ref()[..BLOCK_SIZE as usize]
+            );
+        }
+        //All other blocks were prefetched in time
+        assert_eq!(my_fs.cache_stats().misses, stats.misses);
+        assert_eq!(my_fs.cache_stats().prefetches, 5);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
@@ -0,0 +1,302 @@
+//! Copy-on-write clones of files (reflinks)
+//!
+//! On a file system created with `MkfsOptions::reflink` (`FEATURE_REFLINK`), `mkfs` puts a
+//! reference count table behind the inode extension table (or the data region or the log, if
+//! there is none). Entry `i` counts the files that use data block `i` besides the first one, so a
+//! zeroed table matches a file system in which nothing is shared, and allocating a block does not
+//! have to touch the table. The allocation bitmap still tells which blocks are in use.
+//!
+//! `clone_file` makes a file share all data blocks of another one, at the cost of the indirect
+//! blocks or extent tree nodes of the clone only. `b_free` drops one reference to a shared block
+//! instead of freeing it, so the block is only released when its last file lets go of it.
+//! Writes to a shared block first copy it to a block of the writing file (`unshare`), so the other
+//! files keep seeing the old contents.
+//!
+//! Like the inode extension table, the table is read and written with `b_get` and `b_put`, so its
+//! updates go through the buffer cache and the log.
+
+use cplfs_api::controller::Device;
+use cplfs_api::fs::{BlockSupport, InodeSupport};
+use cplfs_api::types::{Block, SuperBlock};
+
+use crate::b_inode_support::FileSystem;
+use crate::f_indirect_inodes::{FileSystemF, IndirectInode};
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::write_block;
+use crate::superblock_ext::{SuperBlockExt, FEATURE_INODE_EXT, FEATURE_LOG, FEATURE_REFLINK};
+
+/// Size of an entry of the reference count table on disk, in bytes
+const REFCOUNT_SIZE: u64 = 4;
+
+/// Number of entries in a block of the table
+fn refcounts_per_block(sb: &SuperBlock) -> u64 {
+    sb.block_size / REFCOUNT_SIZE
+}
+
+/// Number of blocks the reference count table needs
+pub fn refcount_blocks(sb: &SuperBlock) -> u64 {
+    let per_block = refcounts_per_block(sb);
+    (sb.ndatablocks + per_block - 1) / per_block
+}
+
+/// Checks whether the table described by the extension fits on the disk, behind the data region,
+/// the log and the inode extension table
+pub fn refcounts_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    let mut first_free = sb.datastart + sb.ndatablocks;
+    if ext.has_feature(FEATURE_LOG) {
+        first_free = first_free.max(ext.logstart + ext.nlog);
+    }
+    if ext.has_feature(FEATURE_INODE_EXT) {
+        first_free = first_free.max(ext.iextstart + ext.niextblocks);
+    }
+    ext.refstart >= first_free
+        && ext.nrefblocks >= refcount_blocks(sb)
+        && ext.refstart + ext.nrefblocks <= sb.nblocks
+}
+
+/// Block and byte offset in that block of the entry of data block `i`
+fn refcount_location(sb: &SuperBlock, ext: &SuperBlockExt, i: u64) -> (u64, u64) {
+    let per_block = refcounts_per_block(sb);
+    (ext.refstart + i / per_block, i % per_block * REFCOUNT_SIZE)
+}
+
+/// Zeroes the reference count table, so no block starts out shared
+pub fn format_refcounts(
+    dev: &mut Device,
+    sb: &SuperBlock,
+    ext: &SuperBlockExt,
+) -> Result<(), FileSystemError> {
+    for i in ext.refstart..ext.refstart + ext.nrefblocks {
+        write_block(dev, &Block::new_zero(i, sb.block_size))?;
+    }
+    Ok(())
+}
+
+impl FileSystem {
+    /// Whether this file system was created with a reference count table
+    pub fn has_reflink(&self) -> bool {
+        self.sb_ext.has_feature(FEATURE_REFLINK)
+    }
+
+    /// Number of files that share data block `i` besides the first one
+    fn extra_refs(&self, i: u64) -> Result<u32, FileSystemError> {
+        if !self.has_reflink() {
+            return Ok(0);
+        }
+        let (block_no, offset) = refcount_location(&self.superblock, &self.sb_ext, i);
+        Ok(self.b_get(block_no)?.deserialize_from::<u32>(offset)?)
+    }
+
+    /// Stores the number of files that share data block `i` besides the first one
+    fn set_extra_refs(&mut self, i: u64, extra: u32) -> Result<(), FileSystemError> {
+        let (block_no, offset) = refcount_location(&self.superblock, &self.sb_ext, i);
+        let mut block = self.b_get(block_no)?;
+        block.serialize_into(&extra, offset)?;
+        self.b_put(&block)
+    }
+
+    /// Number of files that use data block `i`, 0 if it is free
+    pub fn b_refs(&self, i: u64) -> Result<u64, FileSystemError> {
+        if i >= self.superblock.ndatablocks {
+            return Err(FileSystemError::IndexOutOfBounds());
+        }
+        let allocated = self
+            .alloc_bitmap
+            .as_ref()
+            .ok_or_else(FileSystemError::DeviceNotSet)?
+            .get(i);
+        if !allocated {
+            return Ok(0);
+        }
+        Ok(1 + self.extra_refs(i)? as u64)
+    }
+
+    /// Adds a reference to the allocated data block `i`, which then takes one more `b_free` to
+    /// be released
+    pub fn b_share(&mut self, i: u64) -> Result<(), FileSystemError> {
+        if !self.has_reflink() {
+            return Err(FileSystemError::FeatureNotSupported());
+        }
+        if self.b_refs(i)? == 0 {
+            return Err(FileSystemError::AllreadyFreeError());
+        }
+        let extra = self
+            .extra_refs(i)?
+            .checked_add(1)
+            .ok_or_else(FileSystemError::AllocationError)?;
+        self.in_op(|fs| fs.set_extra_refs(i, extra))
+    }
+
+    /// Drops a reference to data block `i` if it is shared, and tells whether it was
+    /// `b_free` only releases blocks for which this returns false.
+    pub(crate) fn drop_ref(&mut self, i: u64) -> Result<bool, FileSystemError> {
+        let extra = self.extra_refs(i)?;
+        if extra == 0 {
+            return Ok(false);
+        }
+        self.in_op(|fs| fs.set_extra_refs(i, extra - 1))?;
+        Ok(true)
+    }
+}
+
+impl FileSystemF {
+    /// Adds a reference to the data block with block number `block_no`
+    pub(crate) fn share_block(&mut self, block_no: u64) -> Result<(), FileSystemError> {
+        self.fs.b_share(block_no - self.fs.superblock.datastart)
+    }
+
+    /// Makes `dst` a copy of `src` that shares all data blocks with it, dropping the old contents
+    /// of `dst`
+    pub fn clone_file(
+        &mut self,
+        src: &IndirectInode,
+        dst: &mut IndirectInode,
+    ) -> Result<(), FileSystemError> {
+        if !self.fs.has_reflink() {
+            return Err(FileSystemError::FeatureNotSupported());
+        }
+        if src.inum == dst.inum {
+            return Ok(());
+        }
+        self.in_op(|fs| {
+            fs.free_blocks(dst)?;
+            if fs.has_extents() {
+                fs.share_extents(src, dst)?;
+            } else {
+                fs.share_pointers(src, dst)?;
+            }
+            dst.disk_node.size = src.disk_node.size;
+            fs.i_put(dst)
+        })
+    }
+
+    /// Makes sure logical block `lbn` of `inode`, currently block `block_no`, is not shared with
+    /// other files before it is written, and returns the block number to write to
+    /// A shared block is copied to a new block of `inode`; blocks that are not allocated (0) or
+    /// only used by `inode` are returned as they are.
+    /// Only changes `inode` in memory; the caller has to write it back.
+    pub(crate) fn unshare(
+        &mut self,
+        inode: &mut IndirectInode,
+        lbn: u64,
+        block_no: u64,
+    ) -> Result<u64, FileSystemError> {
+        let datastart = self.fs.superblock.datastart;
+        if block_no == 0 || self.fs.b_refs(block_no - datastart)? <= 1 {
+            return Ok(block_no);
+        }
+        let old = self.b_get(block_no)?;
+        self.free_range(inode, lbn, lbn + 1)?;
+        let copy_no = self.bmap_alloc(inode, lbn)?;
+        let mut copy = self.b_get(copy_no)?;
+        copy.write_data(old.contents_as_ref(), 0)?;
+        self.fs.data_put(&copy)?;
+        Ok(copy_no)
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::f_indirect_inodes::FileSystemF;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::MkfsOptions;
+    use crate::test_support::{contents, format_options, mkfs_f, BLOCK_SIZE, SUPERBLOCK_F};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, DIRECT_POINTERS};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-reflink-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn needs_refcounts() {
+        let path = disk_prep_path("needs_refcounts");
+        let mut my_fs = FileSystemF::mkfs(&path, &SUPERBLOCK_F).unwrap();
+        let src_inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let dst_inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let src = my_fs.i_get(src_inum).unwrap();
+        let mut dst = my_fs.i_get(dst_inum).unwrap();
+        assert!(matches!(
+            my_fs.clone_file(&src, &mut dst),
+            Err(FileSystemError::FeatureNotSupported())
+        ));
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    /// Clones a file that needs an indirect block, and writes to and frees both copies
+    fn clones(extents: bool) {
+        let path = disk_prep_path(&format!("clones-{}", extents));
+        let options = MkfsOptions {
+            reflink: true,
+            ..format_options(extents)
+        };
+        let mut my_fs = mkfs_f(&path, &options);
+        let src_inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let dst_inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut src = my_fs.i_get(src_inum).unwrap();
+        let mut dst = my_fs.i_get(dst_inum).unwrap();
+        let size = (DIRECT_POINTERS + 8) * BLOCK_SIZE;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        my_fs.i_write(&mut src, &buf, 0, size).unwrap();
+        let meta = if extents { 0 } else { 1 };
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 20 - meta);
+
+        //The clone only takes its own indirect block
+        my_fs.clone_file(&src, &mut dst).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 20 - 2 * meta);
+        assert_eq!(contents(&my_fs, &dst), data);
+        let block_no = my_fs.bmap(&src, 15).unwrap();
+        assert_eq!(my_fs.bmap(&dst, 15).unwrap(), block_no);
+        let datastart = SUPERBLOCK_F.datastart;
+        assert_eq!(my_fs.fs.b_refs(block_no - datastart).unwrap(), 2);
+
+        //Writing to a shared block copies it first
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemF::mountfs(dev).unwrap();
+        let mut dst = my_fs.i_get(dst_inum).unwrap();
+        let patch = Buffer::new(vec![0xee; 50].into_boxed_slice());
+        my_fs
+            .i_write(&mut dst, &patch, 15 * BLOCK_SIZE + 100, 50)
+            .unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 21 - 2 * meta);
+        assert_ne!(my_fs.bmap(&dst, 15).unwrap(), block_no);
+        assert_eq!(my_fs.fs.b_refs(block_no - datastart).unwrap(), 1);
+        let src = my_fs.i_get(src_inum).unwrap();
+        assert_eq!(contents(&my_fs, &src), data);
+        let mut expected = data.clone();
+        for byte in &mut expected[15 * BLOCK_SIZE as usize + 100..][..50] {
+            *byte = 0xee;
+        }
+        assert_eq!(contents(&my_fs, &dst), expected);
+
+        //Blocks are only released with their last reference
+        let mut src = my_fs.i_get(src_inum).unwrap();
+        my_fs.i_trunc(&mut src).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 20 - meta);
+        assert_eq!(contents(&my_fs, &dst), expected);
+        my_fs.i_truncate_to(&mut dst, 10).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 1);
+        my_fs.i_trunc(&mut dst).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn clones_with_pointers() {
+        clones(false);
+    }
+
+    #[test]
+    fn clones_with_extents() {
+        clones(true);
+    }
+}
@@ -0,0 +1,206 @@
+//! Secure delete
+//!
+//! Freeing a data block only clears its bit in the allocation bitmap, so without further measures
+//! the contents of deleted files stay readable in the image. When a file system is mounted with
+//! `MountOptions::secure_delete` set, freed blocks are scrubbed:
+//! - `SecureDelete::Overwrite` overwrites every freed block with a fixed byte, as part of `b_free`
+//! - `SecureDelete::Lazy` only queues freed blocks in a `ScrubQueue`. The queue is zeroed in
+//!   batches by `scrub_freed`, and completely by `sync` and when unmounting. Blocks that are
+//!   allocated again before their turn are dropped from the queue, since allocation zeroes them.
+//!
+//! The policy holds for the whole mount: the inode layout has no room for a per-inode flag.
+
+use std::collections::BTreeSet;
+
+use crate::options::SecureDelete;
+
+#[derive(Debug, Clone, Default)]
+/// Freed data blocks that still have to be scrubbed
+pub struct ScrubQueue {
+    /// What happens to freed blocks
+    policy: SecureDelete,
+    /// Indices of the freed data blocks that have not been zeroed yet
+    pending: BTreeSet<u64>,
+}
+
+impl ScrubQueue {
+    /// Creates an empty queue for the given policy
+    pub fn new(policy: SecureDelete) -> ScrubQueue {
+        ScrubQueue {
+            policy,
+            pending: BTreeSet::new(),
+        }
+    }
+
+    /// The policy of this queue
+    pub fn policy(&self) -> SecureDelete {
+        self.policy
+    }
+
+    /// Registers that data block `index` was freed
+    /// Returns the byte the block has to be overwritten with right away, if any
+    pub fn freed(&mut self, index: u64) -> Option<u8> {
+        match self.policy {
+            SecureDelete::Off => None,
+            SecureDelete::Overwrite(byte) => Some(byte),
+            SecureDelete::Lazy => {
+                self.pending.insert(index);
+                None
+            }
+        }
+    }
+
+    /// Registers that data block `index` was zeroed, e.g. because it was allocated again
+    pub fn zeroed(&mut self, index: u64) {
+        self.pending.remove(&index);
+    }
+
+    /// Takes at most `max` queued blocks out of the queue, lowest indices first
+    pub fn take_batch(&mut self, max: usize) -> Vec<u64> {
+        let batch: Vec<u64> = self.pending.iter().take(max).copied().collect();
+        for index in &batch {
+            self.pending.remove(index);
+        }
+        batch
+    }
+
+    /// Number of queued blocks
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Whether no blocks are queued
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::b_inode_support::FileSystem;
+    use crate::options::{MountOptions, SecureDelete};
+    use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeSupport};
+    use cplfs_api::types::{FType, Inode, InodeLike, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-secure-delete-".to_string() + name), "img")
+    }
+
+    fn mount_options(secure_delete: SecureDelete) -> MountOptions {
+        //Without a buffer cache, the device shows what was scrubbed right away
+        MountOptions {
+            secure_delete,
+            cache_blocks: 0,
+            ..MountOptions::default()
+        }
+    }
+
+    /// Contents of data block `i`, as they are stored on the device
+    fn data_on_disk(fs: &FileSystem, i: u64) -> Vec<u8> {
+        let dev = fs.device.as_ref().unwrap();
+        let block = dev.read_block(SUPERBLOCK_GOOD.datastart + i).unwrap();
+        block.contents_as_ref().to_vec()
+    }
+
+    #[test]
+    fn overwrite() {
+        let path = disk_prep_path("overwrite");
+        let dev = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD)
+            .unwrap()
+            .unmountfs();
+        let options = mount_options(SecureDelete::Overwrite(0xaa));
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        my_fs
+            .b_put(&utils::n_block(SUPERBLOCK_GOOD.datastart, BLOCK_SIZE, 7))
+            .unwrap();
+        my_fs.b_free(0).unwrap();
+        assert_eq!(data_on_disk(&my_fs, 0), vec![0xaa; BLOCK_SIZE as usize]);
+        assert!(my_fs.scrub.is_empty());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn lazy() {
+        let path = disk_prep_path("lazy");
+        let dev = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD)
+            .unwrap()
+            .unmountfs();
+        let options = mount_options(SecureDelete::Lazy);
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+
+        //A file with 3 blocks of data
+        for i in 0..3 {
+            assert_eq!(my_fs.b_alloc().unwrap(), i);
+            let b = utils::n_block(SUPERBLOCK_GOOD.datastart + i, BLOCK_SIZE, 5);
+            my_fs.b_put(&b).unwrap();
+        }
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let size = 3 * BLOCK_SIZE;
+        let blocks = [4, 5, 6];
+        let ino = <Inode as InodeLike>::new(inum, &FType::TFile, 0, size, &blocks).unwrap();
+        my_fs.i_put(&ino).unwrap();
+
+        //Freeing the inode queues its blocks, but does not touch them yet
+        my_fs.i_free(inum).unwrap();
+        assert_eq!(my_fs.scrub.len(), 3);
+        assert_eq!(data_on_disk(&my_fs, 0), vec![5; BLOCK_SIZE as usize]);
+
+        assert_eq!(my_fs.scrub_freed(1).unwrap(), 1);
+        assert_eq!(data_on_disk(&my_fs, 0), vec![0; BLOCK_SIZE as usize]);
+        //Allocating a queued block zeroes it, so it leaves the queue
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+        assert_eq!(my_fs.scrub.len(), 1);
+
+        //Unmounting scrubs the rest
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(data_on_disk(&my_fs, 2), vec![0; BLOCK_SIZE as usize]);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn unmount_errors() {
+        let path = disk_prep_path("unmount-errors");
+        let dev = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD)
+            .unwrap()
+            .unmountfs();
+        let options = mount_options(SecureDelete::Lazy);
+
+        //A queued block past the end of the device cannot be scrubbed
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+        my_fs.scrub.freed(NBLOCKS);
+        let (_, dev) = my_fs.unmountfs_checked().unwrap_err();
+
+        //Unmounting still hands back the device instead of panicking
+        let mut my_fs = FileSystem::mountfs_with_options(dev, &options).unwrap();
+        my_fs.scrub.freed(NBLOCKS);
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystem::mountfs(dev).unwrap();
+        let dev = my_fs.unmountfs_checked().unwrap();
+        utils::disk_destruct(dev);
+    }
+}
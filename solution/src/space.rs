@@ -0,0 +1,139 @@
+//! Free space accounting
+//!
+//! A file system can be created with a number of reserved data blocks (see
+//! `MkfsOptions::reserved_percent`). Ordinary allocations fail with `AllocationError()` once only
+//! the reserved blocks are left, so that recovery tools still find some room to repair things.
+//! Allocations that are made in the `AllocContext::Privileged` context may use the reserved
+//! blocks as well. `statfs` reports how much space is left, for ordinary and privileged users.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// On whose behalf data blocks are allocated
+pub enum AllocContext {
+    /// Ordinary allocations, that cannot use the reserved blocks
+    User,
+    /// Allocations by administrators and recovery tools, that can use all free blocks
+    Privileged,
+}
+
+impl Default for AllocContext {
+    fn default() -> Self {
+        AllocContext::User
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Summary of the space that is used and left in a file system
+pub struct StatFs {
+    /// Size of a block, in bytes
+    pub block_size: u64,
+    /// Number of blocks in the data region
+    pub data_blocks: u64,
+    /// Number of free data blocks, including the reserved ones
+    pub free_blocks: u64,
+    /// Number of data blocks that are reserved for privileged allocations
+    pub reserved_blocks: u64,
+    /// Number of free data blocks that ordinary allocations can still use
+    pub available_blocks: u64,
+    /// Number of inodes
+    pub inodes: u64,
+    /// Number of free inodes
+    pub free_inodes: u64,
+}
+
+/// Number of reserved data blocks for a data region of `ndatablocks` blocks, if `percent` is valid
+pub fn reserved_blocks(ndatablocks: u64, percent: u64) -> Option<u64> {
+    if percent > 100 {
+        return None;
+    }
+    Some(ndatablocks * percent / 100)
+}
+
+/// Number of free blocks that can be used in context `ctx`
+pub fn available_blocks(free: u64, reserved: u64, ctx: AllocContext) -> u64 {
+    match ctx {
+        AllocContext::User => free.saturating_sub(reserved),
+        AllocContext::Privileged => free,
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::b_inode_support::FileSystem;
+    use crate::options::MkfsOptions;
+    use cplfs_api::fs::{BlockSupport, FileSysSupport, InodeSupport};
+    use cplfs_api::types::{FType, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 40;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn reserved() -> MkfsOptions {
+        MkfsOptions {
+            reserved_percent: 25,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-space-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn reserved_blocks() {
+        let path = disk_prep_path("reserved_blocks");
+        let invalid = MkfsOptions {
+            reserved_percent: 101,
+            ..reserved()
+        };
+        assert!(FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &invalid).is_err());
+
+        let mut my_fs =
+            FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &reserved()).unwrap();
+        let stat = my_fs.statfs().unwrap();
+        assert_eq!(stat.free_blocks, 32);
+        assert_eq!(stat.reserved_blocks, 8);
+        assert_eq!(stat.available_blocks, 24);
+        assert_eq!(stat.free_inodes, 15);
+
+        for i in 0..24 {
+            assert_eq!(my_fs.b_alloc().unwrap(), i);
+        }
+        assert!(my_fs.b_alloc().is_err());
+        assert!(my_fs.b_alloc_run(1).is_err());
+        my_fs.i_alloc(FType::TFile).unwrap();
+        let stat = my_fs.statfs().unwrap();
+        assert_eq!(stat.free_blocks, 8);
+        assert_eq!(stat.available_blocks, 0);
+        assert_eq!(stat.free_inodes, 14);
+
+        //Privileged allocations can use the reserved blocks
+        assert_eq!(my_fs.as_privileged(|fs| fs.b_alloc_run(2)).unwrap(), 24);
+        for i in 28..32 {
+            assert_eq!(my_fs.as_privileged(|fs| fs.b_alloc()).unwrap(), i);
+        }
+        assert!(my_fs.as_privileged(|fs| fs.b_alloc()).is_err());
+
+        //The reservation is stored in the image
+        my_fs.b_free(0).unwrap();
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().reserved_blocks, 8);
+        assert!(my_fs.b_alloc().is_err());
+        assert_eq!(my_fs.as_privileged(|fs| fs.b_alloc()).unwrap(), 0);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
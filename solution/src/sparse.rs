@@ -0,0 +1,169 @@
+//! Sparse files for the file system of assignment f
+//!
+//! `i_write` on a `FileSystemF` accepts offsets past the end of a file. The logical blocks between
+//! the old end of the file and the offset are not allocated: their block pointer stays 0, or no
+//! extent maps them, and `i_read` returns zeros for them. Such unallocated ranges are called holes.
+//! Only `FileSystemF` supports holes. The `i_write` of assignment e, on `FileSystemC`, keeps
+//! refusing offsets past the end of a file, as the base project asks.
+//!
+//! `seek_data` and `seek_hole` find the allocated ranges and the holes of a file, like the
+//! `SEEK_DATA` and `SEEK_HOLE` modes of `lseek`, with the granularity of a block. `stat` reports
+//! how many blocks a file owns next to its logical size, which is what a hole saves.
+
+use cplfs_api::types::InodeLike;
+
+use crate::f_indirect_inodes::{FileSystemF, IndirectInode};
+use crate::filesystem_errors::FileSystemError;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Size and space usage of a file
+pub struct FileStat {
+    /// Logical size of the file in bytes, holes included
+    pub size: u64,
+    /// Number of blocks the file owns, its indirect blocks or extent tree nodes included
+    pub blocks: u64,
+}
+
+impl FileSystemF {
+    /// Size and space usage of `inode`
+    pub fn stat(&self, inode: &IndirectInode) -> Result<FileStat, FileSystemError> {
+        Ok(FileStat {
+            size: inode.get_size(),
+            blocks: self.allocated_blocks(inode)?,
+        })
+    }
+
+    /// Smallest offset of at least `off` that lies in an allocated block of `inode`
+    /// Returns `None` if there is no data at or after `off` before the end of the file.
+    pub fn seek_data(
+        &self,
+        inode: &IndirectInode,
+        off: u64,
+    ) -> Result<Option<u64>, FileSystemError> {
+        self.seek(inode, off, true)
+    }
+
+    /// Smallest offset of at least `off` that lies in a hole of `inode`, where the end of the file
+    /// counts as a hole
+    /// Returns `None` if `off` is not before the end of the file.
+    pub fn seek_hole(
+        &self,
+        inode: &IndirectInode,
+        off: u64,
+    ) -> Result<Option<u64>, FileSystemError> {
+        if off >= inode.get_size() {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.seek(inode, off, false)?.unwrap_or(inode.get_size()),
+        ))
+    }
+
+    /// Smallest offset of at least `off`, before the end of `inode`, whose block is allocated if
+    /// `data` is set, or a hole otherwise
+    fn seek(
+        &self,
+        inode: &IndirectInode,
+        off: u64,
+        data: bool,
+    ) -> Result<Option<u64>, FileSystemError> {
+        let size = inode.get_size();
+        if off >= size {
+            return Ok(None);
+        }
+        let block_size = self.fs.superblock.block_size;
+        let first = off / block_size;
+        let blocks = self.map_range(inode, first, (size - 1) / block_size)?;
+        Ok(blocks
+            .iter()
+            .position(|&block_no| (block_no != 0) == data)
+            .map(|k| off.max((first + k as u64) * block_size)))
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::FileStat;
+    use crate::f_indirect_inodes::FileSystemF;
+    use crate::test_support::{format_options, mkfs_f, BLOCK_SIZE};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-sparse-".to_string() + name), "img")
+    }
+
+    /// Writes a file with a hole in the middle, and checks reads, seeks and the block count
+    fn holes(extents: bool) {
+        let path = disk_prep_path(&format!("holes-{}", extents));
+        let mut my_fs = mkfs_f(&path, &format_options(extents));
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //Data in block 0, a hole from block 1 up to and including block 19, data in block 20
+        let buf = Buffer::new(vec![9; 400].into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, 100).unwrap();
+        let far = 20 * BLOCK_SIZE + 50;
+        my_fs.i_write(&mut ino, &buf, far, 200).unwrap();
+        assert_eq!(ino.get_size(), far + 200);
+        //Offsets that would overflow the file size are refused like any other too big offset
+        assert!(my_fs.i_write(&mut ino, &buf, u64::MAX - 50, 100).is_err());
+        assert_eq!(ino.get_size(), far + 200);
+        assert_eq!(
+            my_fs.stat(&ino).unwrap(),
+            FileStat {
+                size: far + 200,
+                //Block 20 needs the indirect block in the pointer format
+                blocks: if extents { 2 } else { 3 },
+            }
+        );
+        assert_eq!(
+            my_fs.statfs().unwrap().free_blocks,
+            70 - my_fs.stat(&ino).unwrap().blocks
+        );
+
+        //The hole, including the bytes after the first write, reads as zeros
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemF::mountfs(dev).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+        let mut all = Buffer::new_zero(far + 200);
+        assert_eq!(
+            my_fs.i_read(&ino, &mut all, 0, far + 200).unwrap(),
+            far + 200
+        );
+        let all = all.contents_as_ref();
+        assert!(all[..100].iter().all(|&b| b == 9));
+        assert!(all[100..far as usize].iter().all(|&b| b == 0));
+        assert!(all[far as usize..].iter().all(|&b| b == 9));
+
+        assert_eq!(my_fs.seek_data(&ino, 150).unwrap(), Some(150));
+        assert_eq!(my_fs.seek_data(&ino, 350).unwrap(), Some(20 * BLOCK_SIZE));
+        assert_eq!(my_fs.seek_data(&ino, far + 10).unwrap(), Some(far + 10));
+        assert_eq!(my_fs.seek_data(&ino, far + 200).unwrap(), None);
+        assert_eq!(my_fs.seek_hole(&ino, 10).unwrap(), Some(BLOCK_SIZE));
+        assert_eq!(
+            my_fs.seek_hole(&ino, 5 * BLOCK_SIZE).unwrap(),
+            Some(5 * BLOCK_SIZE)
+        );
+        assert_eq!(my_fs.seek_hole(&ino, far).unwrap(), Some(far + 200));
+        assert_eq!(my_fs.seek_hole(&ino, far + 200).unwrap(), None);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn holes_with_pointers() {
+        holes(false);
+    }
+
+    #[test]
+    fn holes_with_extents() {
+        holes(true);
+    }
+}
@@ -0,0 +1,205 @@
+//! Extension of the superblock with the metadata the API `SuperBlock` has no room for
+//!
+//! The `SuperBlock` type of the API crate cannot be changed, so everything extra we want to
+//! remember about the layout of the file system is stored in a `SuperBlockExt`, serialized into
+//! block 0 right behind the regular superblock.
+//! `mkfs` only writes an extension when one of its options needs it, so the plain flat layout
+//! keeps working with blocks that are too small to hold one.
+//! Images that were created without an extension (e.g. by an older version of this code) simply
+//! contain zeroes at that position, which deserializes into an extension without a valid magic
+//! number, and hence into the default flat layout.
+
+use cplfs_api::types::{Block, SuperBlock, SUPERBLOCK_SIZE};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::filesystem_errors::FileSystemError;
+
+/// Magic number marking a block 0 that contains a superblock extension
+pub const SB_EXT_MAGIC: u64 = 0x4350_4c46_5345_5854;
+
+/// Feature bit: the data and inode regions are split up into block groups
+pub const FEATURE_BLOCK_GROUPS: u64 = 1;
+
+/// Feature bit: there is a write-ahead log region behind the data region
+pub const FEATURE_LOG: u64 = 2;
+
+/// Feature bit: there is an inode extension table behind the data region and the log
+pub const FEATURE_INODE_EXT: u64 = 4;
+
+/// Feature bit: inodes map their blocks with extents instead of block pointers
+pub const FEATURE_EXTENTS: u64 = 8;
+
+/// Feature bit: small files and directories keep their contents inside their inode
+pub const FEATURE_INLINE_DATA: u64 = 16;
+
+/// Feature bit: there is a reference count table behind the inode extension table
+pub const FEATURE_REFLINK: u64 = 32;
+
+/// Feature bit: there is a checksum table behind the other regions, with a checksum of every inode
+/// and metadata block
+pub const FEATURE_CHECKSUMS: u64 = 64;
+
+/// Feature bit: the checksum table covers the blocks holding the contents of files as well
+pub const FEATURE_DATA_CHECKSUMS: u64 = 128;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Extra file system metadata, stored in block 0 after the `SuperBlock`
+pub struct SuperBlockExt {
+    /// Equal to `SB_EXT_MAGIC` if this extension was actually written by `mkfs`
+    pub magic: u64,
+    /// Bit set of the optional features this file system was created with
+    pub features: u64,
+    /// Number of data blocks in each block group, only meaningful with `FEATURE_BLOCK_GROUPS`
+    pub blocks_per_group: u64,
+    /// Number of inodes in each block group, only meaningful with `FEATURE_BLOCK_GROUPS`
+    pub inodes_per_group: u64,
+    /// Number of block groups, only meaningful with `FEATURE_BLOCK_GROUPS`
+    pub ngroups: u64,
+    /// Number of data blocks that only privileged allocations may use
+    pub reserved_blocks: u64,
+    /// First block of the log region, only meaningful with `FEATURE_LOG`
+    pub logstart: u64,
+    /// Number of blocks in the log region, header included, only meaningful with `FEATURE_LOG`
+    pub nlog: u64,
+    /// First block of the inode extension table, only meaningful with `FEATURE_INODE_EXT`
+    pub iextstart: u64,
+    /// Number of blocks in the inode extension table, only meaningful with `FEATURE_INODE_EXT`
+    pub niextblocks: u64,
+    /// First block of the reference count table, only meaningful with `FEATURE_REFLINK`
+    pub refstart: u64,
+    /// Number of blocks in the reference count table, only meaningful with `FEATURE_REFLINK`
+    pub nrefblocks: u64,
+    /// First block of the checksum table, only meaningful with `FEATURE_CHECKSUMS`
+    pub csumstart: u64,
+    /// Number of blocks in the checksum table, only meaningful with `FEATURE_CHECKSUMS`
+    pub ncsumblocks: u64,
+}
+
+lazy_static! {
+    /// Size of the superblock extension on disk, in bytes.
+    pub static ref SB_EXT_SIZE : u64 = bincode::serialize(&SuperBlockExt::default()).unwrap().len() as u64;
+}
+
+impl SuperBlockExt {
+    /// Creates an empty extension with the magic number set and no features enabled
+    pub fn new() -> SuperBlockExt {
+        SuperBlockExt {
+            magic: SB_EXT_MAGIC,
+            ..SuperBlockExt::default()
+        }
+    }
+
+    /// Whether this extension holds anything besides the defaults, so it has to be written
+    pub fn is_used(&self) -> bool {
+        self.features != 0 || self.reserved_blocks != 0
+    }
+
+    /// Checks whether the given feature bit is set in this extension
+    pub fn has_feature(&self, feature: u64) -> bool {
+        self.magic == SB_EXT_MAGIC && self.features & feature != 0
+    }
+}
+
+/// Offset of the superblock extension in block 0
+pub fn sb_ext_offset() -> u64 {
+    *SUPERBLOCK_SIZE
+}
+
+/// Reads the extension from block 0, falling back to the default extension if none was written
+pub fn read_sb_ext(block: &Block) -> Result<SuperBlockExt, FileSystemError> {
+    if sb_ext_offset() + *SB_EXT_SIZE > block.len() {
+        return Ok(SuperBlockExt::default());
+    }
+    let ext = block.deserialize_from::<SuperBlockExt>(sb_ext_offset())?;
+    if ext.magic == SB_EXT_MAGIC {
+        Ok(ext)
+    } else {
+        Ok(SuperBlockExt::default())
+    }
+}
+
+/// Writes the extension into (a copy of) block 0
+pub fn write_sb_ext(block: &mut Block, ext: &SuperBlockExt) -> Result<(), FileSystemError> {
+    block.serialize_into(ext, sb_ext_offset())?;
+    Ok(())
+}
+
+/// Checks whether the extension is consistent with the given superblock
+pub fn sb_ext_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    if ext.magic != SB_EXT_MAGIC {
+        // No extension; the flat layout is always fine, whatever the block size
+        return true;
+    }
+    if *SUPERBLOCK_SIZE + *SB_EXT_SIZE > sb.block_size {
+        return false;
+    }
+    if ext.has_feature(FEATURE_BLOCK_GROUPS) && !crate::block_groups::groups_valid(sb, ext) {
+        return false;
+    }
+    if ext.reserved_blocks > sb.ndatablocks {
+        return false;
+    }
+    if ext.has_feature(FEATURE_LOG) && !crate::wal::log_valid(sb, ext) {
+        return false;
+    }
+    if ext.has_feature(FEATURE_INODE_EXT) && !crate::inode_ext::inode_ext_valid(sb, ext) {
+        return false;
+    }
+    if ext.has_feature(FEATURE_REFLINK) && !crate::reflink::refcounts_valid(sb, ext) {
+        return false;
+    }
+    if ext.has_feature(FEATURE_CHECKSUMS) && !crate::checksum::checksums_valid(sb, ext) {
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::SB_EXT_SIZE;
+    use crate::b_inode_support::FileSystem;
+    use crate::options::MkfsOptions;
+    use cplfs_api::fs::{FileSysSupport, InodeSupport};
+    use cplfs_api::types::{FType, InodeLike, SuperBlock, SUPERBLOCK_SIZE};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static SUPERBLOCK_SMALL: SuperBlock = SuperBlock {
+        block_size: 150,
+        nblocks: 14,
+        ninodes: 2,
+        inodestart: 1,
+        ndatablocks: 10,
+        bmapstart: 3,
+        datastart: 4,
+    };
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-superblock-ext-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn small_blocks() {
+        let path = disk_prep_path("small_blocks");
+        assert!(*SUPERBLOCK_SIZE + *SB_EXT_SIZE > SUPERBLOCK_SMALL.block_size);
+
+        //Only the options that need an extension need room for it in block 0
+        let reserved = MkfsOptions {
+            reserved_percent: 10,
+            ..MkfsOptions::default()
+        };
+        assert!(FileSystem::mkfs_with_options(&path, &SUPERBLOCK_SMALL, &reserved).is_err());
+        let mut my_fs = FileSystem::mkfs(&path, &SUPERBLOCK_SMALL).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(my_fs.i_get(inum).unwrap().get_ft(), FType::TFile);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
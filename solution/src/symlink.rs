@@ -0,0 +1,238 @@
+//! Symbolic links
+//!
+//! The `FType` of the API crate has no room for another file type, so a symbolic link is a
+//! `TFile` inode with `INODE_FLAG_SYMLINK` set in its extension record (see `crate::inode_ext`);
+//! symbolic links therefore need a file system with an inode extension table. The contents of
+//! the inode are the target path:
+//! - targets of at most `inline_capacity()` bytes are stored inline, in the `direct_blocks` of the
+//!   inode, with `INODE_FLAG_INLINE` set,
+//! - longer targets, up to a block, are written to a data block like the contents of any file.
+//!
+//! `resolve_path` follows symbolic links in every name of a path, `resolve_nofollow` in all but
+//! the last one. Relative targets are resolved from the directory that holds the link, and they
+//! may start with a plain name, unlike relative paths (see `PathSupport::valid_path`).
+
+use cplfs_api::fs::{DirectorySupport, InodeRWSupport, InodeSupport};
+use cplfs_api::types::{Buffer, FType, Inode, InodeLike};
+
+use crate::d_path_support::FileSystemD;
+use crate::filesystem_errors::FileSystemError;
+use crate::inode_ext::{
+    inline_capacity, pack_inline, unpack_inline, INODE_FLAG_INLINE, INODE_FLAG_SYMLINK,
+};
+
+/// Maximum number of symbolic links that are followed while resolving a single path
+pub const MAX_SYMLINK_HOPS: u32 = 8;
+
+/// Mode of symbolic links; their own permission bits are never checked
+pub const SYMLINK_MODE: u32 = 0o777;
+
+impl FileSystemD {
+    /// Checks whether `target` can be stored as the target of a symbolic link
+    pub fn valid_target(target: &str) -> bool {
+        if target == "/" {
+            return true;
+        }
+        let names = target.strip_prefix('/').unwrap_or(target);
+        !names.is_empty()
+            && names
+                .split('/')
+                .all(|name| FileSystemD::new_de(0, name).is_some())
+    }
+
+    /// Whether inode `inum` is a symbolic link
+    pub fn is_symlink(&self, inum: u64) -> Result<bool, FileSystemError> {
+        Ok(self.fs.i_ext_get(inum)?.flags & INODE_FLAG_SYMLINK != 0)
+    }
+
+    /// Creates a symbolic link at `linkpath`, that points to `target`, and returns its inode
+    /// The link itself is not followed, so `linkpath` may not exist yet.
+    pub fn symlink(&mut self, target: &str, linkpath: &str) -> Result<Inode, FileSystemError> {
+        if !self.fs.fs.has_inode_ext() {
+            return Err(FileSystemError::FeatureNotSupported());
+        }
+        let len = target.len() as u64;
+        if !FileSystemD::valid_target(target) || len > self.fs.fs.superblock.block_size {
+            return Err(FileSystemError::InvalidPath());
+        }
+        let (parent, name) = self.resolve_parent(linkpath)?;
+        if self.dirlookup(&parent, name).is_ok() {
+            return Err(FileSystemError::EntryExists());
+        }
+        let inum = self.fs.in_op(|fs| {
+            let inum = fs.fs.i_alloc_near(FType::TFile, parent.inum)?;
+            let mut ino = fs.i_get(inum)?;
+            let mut ext = fs.i_ext_get(inum)?;
+            ext.flags |= INODE_FLAG_SYMLINK;
+            ext.mode = SYMLINK_MODE;
+            // The flags go first, since writing the target may change them
+            if len <= inline_capacity() {
+                ext.flags |= INODE_FLAG_INLINE;
+            }
+            fs.fs.i_ext_put(inum, &ext)?;
+            if len <= inline_capacity() {
+                ino.disk_node.direct_blocks = pack_inline(target.as_bytes());
+                ino.disk_node.size = len;
+                fs.i_put(&ino)?;
+            } else {
+                let buf = Buffer::new(target.as_bytes().to_vec().into_boxed_slice());
+                fs.i_write(&mut ino, &buf, 0, len)?;
+            }
+            fs.dirlink(&mut fs.i_get(parent.inum)?, name, inum)?;
+            Ok(inum)
+        })?;
+        self.i_get(inum)
+    }
+
+    /// Returns the target of the symbolic link at `path`
+    pub fn readlink(&self, path: &str) -> Result<String, FileSystemError> {
+        let link = self.resolve_nofollow(path)?;
+        self.link_target(&link)
+    }
+
+    /// Returns the target of symbolic link `link`
+    pub fn link_target(&self, link: &Inode) -> Result<String, FileSystemError> {
+        let ext = self.fs.i_ext_get(link.inum)?;
+        if ext.flags & INODE_FLAG_SYMLINK == 0 {
+            return Err(FileSystemError::NotASymlink());
+        }
+        let len = link.get_size();
+        let bytes = if ext.flags & INODE_FLAG_INLINE != 0 {
+            unpack_inline(&link.disk_node.direct_blocks, len)
+        } else {
+            let mut buf = Buffer::new_zero(len);
+            self.fs.i_read(link, &mut buf, 0, len)?;
+            buf.contents_as_ref().to_vec()
+        };
+        String::from_utf8(bytes).map_err(|_| FileSystemError::ReadError())
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::d_path_support::FileSystemD;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::MkfsOptions;
+    use cplfs_api::fs::{DirectorySupport, FileSysSupport, InodeSupport, PathSupport};
+    use cplfs_api::types::{FType, InodeLike, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 41;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn extended() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-symlink-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn no_inode_ext() {
+        let path = disk_prep_path("no_inode_ext");
+        let mut my_fs = FileSystemD::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        assert!(matches!(
+            my_fs.symlink("/", "/root"),
+            Err(FileSystemError::FeatureNotSupported())
+        ));
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn symlinks() {
+        let path = disk_prep_path("symlinks");
+        let mut my_fs =
+            FileSystemD::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &extended()).unwrap();
+        let usr = my_fs.mkdir("/usr").unwrap();
+        let lib = my_fs.mkdir("/usr/lib").unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        my_fs
+            .dirlink(&mut my_fs.i_get(lib.inum).unwrap(), "libc", inum)
+            .unwrap();
+
+        //Short targets are stored inline, without a data block
+        let free = my_fs.fs.fs.statfs().unwrap().free_blocks;
+        let link = my_fs.symlink("/usr/lib", "/lib").unwrap();
+        assert_eq!(link.get_ft(), FType::TFile);
+        assert_eq!(link.get_size(), 8);
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+        assert_eq!(my_fs.readlink("/lib").unwrap(), "/usr/lib");
+        assert_eq!(my_fs.resolve_path("/lib").unwrap().inum, lib.inum);
+        assert_eq!(my_fs.resolve_path("/lib/libc").unwrap().inum, inum);
+        assert_eq!(my_fs.resolve_nofollow("/lib").unwrap().inum, link.inum);
+        assert!(matches!(
+            my_fs.readlink("/lib/libc"),
+            Err(FileSystemError::NotASymlink())
+        ));
+        assert!(matches!(
+            my_fs.symlink("/usr", "/lib"),
+            Err(FileSystemError::EntryExists())
+        ));
+
+        //Relative targets start in the directory of the link, long ones take a data block
+        my_fs.symlink("lib/libc", "/usr/libc").unwrap();
+        assert_eq!(my_fs.resolve_path("/usr/libc").unwrap().inum, inum);
+        my_fs.symlink("../../usr/./lib", "/usr/lib/self").unwrap();
+        assert_eq!(
+            my_fs.resolve_path("/lib/self/self/libc").unwrap().inum,
+            inum
+        );
+        let long = format!("{}/usr", "/usr/..".repeat(30));
+        let long_link = my_fs.symlink(&long, "/long").unwrap();
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free - 1);
+        assert_eq!(my_fs.readlink("/long").unwrap(), long);
+        assert_eq!(my_fs.resolve_path("/long/lib").unwrap().inum, lib.inum);
+        my_fs.set_cwd("/lib").unwrap();
+        assert_eq!(my_fs.resolve_path("./libc").unwrap().inum, inum);
+        my_fs.set_cwd("/").unwrap();
+
+        //Loops and long chains end at the hop limit
+        my_fs.symlink("/loop2", "/loop1").unwrap();
+        my_fs.symlink("./loop1", "/loop2").unwrap();
+        assert!(matches!(
+            my_fs.resolve_path("/loop1"),
+            Err(FileSystemError::TooManyLinks())
+        ));
+        assert!(matches!(
+            my_fs.resolve_path("/lib/self/self/self/self/self/self/self/self/self"),
+            Err(FileSystemError::TooManyLinks())
+        ));
+        assert!(my_fs
+            .resolve_path("/lib/self/self/self/self/self/self/self")
+            .is_ok());
+
+        //Unlinking removes the link, not its target, and frees its data
+        my_fs.unlink("/lib").unwrap();
+        my_fs.unlink("/long").unwrap();
+        assert_eq!(my_fs.fs.fs.statfs().unwrap().free_blocks, free);
+        assert_eq!(my_fs.i_get(long_link.inum).unwrap().get_ft(), FType::TFree);
+        assert!(my_fs.resolve_path("/lib").is_err());
+        assert_eq!(my_fs.resolve_path("/usr/lib/libc").unwrap().inum, inum);
+        assert_eq!(my_fs.i_get(usr.inum).unwrap().get_ft(), FType::TDir);
+
+        //Links survive a remount
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemD::mountfs(dev).unwrap();
+        assert_eq!(my_fs.readlink("/usr/libc").unwrap(), "lib/libc");
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
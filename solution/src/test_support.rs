@@ -0,0 +1,46 @@
+//! Fixtures shared by the tests of the features that build on the file system of assignment f
+//!
+//! Most of those features work with both inode formats, so their tests take whether to use
+//! extents or block pointers, and `format_options` turns that into the options for `mkfs_f`.
+
+use std::path::Path;
+
+use cplfs_api::fs::InodeRWSupport;
+use cplfs_api::types::{Buffer, InodeLike, SuperBlock};
+
+use crate::f_indirect_inodes::FileSystemF;
+use crate::options::MkfsOptions;
+
+/// Block size of `SUPERBLOCK_F`
+pub static BLOCK_SIZE: u64 = 300;
+
+/// Small enough blocks that files soon need indirect blocks: 37 block numbers fit in one
+pub static SUPERBLOCK_F: SuperBlock = SuperBlock {
+    block_size: BLOCK_SIZE,
+    nblocks: 80,
+    ninodes: 6,
+    inodestart: 1,
+    ndatablocks: 70,
+    bmapstart: 4,
+    datastart: 5,
+};
+
+/// Options for a file system whose inodes map their blocks with extents, or with block pointers
+pub fn format_options(extents: bool) -> MkfsOptions {
+    MkfsOptions {
+        extents,
+        ..MkfsOptions::default()
+    }
+}
+
+/// Creates a `FileSystemF` with `SUPERBLOCK_F` at `path`
+pub fn mkfs_f(path: &Path, options: &MkfsOptions) -> FileSystemF {
+    FileSystemF::mkfs_with_options(path, &SUPERBLOCK_F, options).unwrap()
+}
+
+/// Contents of `ino`, read in one go
+pub fn contents<FS: InodeRWSupport>(my_fs: &FS, ino: &FS::Inode) -> Vec<u8> {
+    let mut buf = Buffer::new_zero(ino.get_size());
+    my_fs.i_read(ino, &mut buf, 0, ino.get_size()).unwrap();
+    buf.contents_as_ref().to_vec()
+}
@@ -0,0 +1,281 @@
+//! Transactions that group several file system calls into one atomic unit
+//!
+//! `FileSystemC::begin` starts a `Transaction`, a guard that dereferences to the file system, so
+//! all the usual `BlockSupport`, `InodeSupport`, `DirectorySupport` and `InodeRWSupport` calls can
+//! be made through it. They all join a single operation of the write-ahead log (see `crate::wal`),
+//! so nothing reaches the disk until the transaction is committed:
+//! - `commit` writes all blocks through the log at once. If one of the calls did not fit in the
+//!   log anymore, nothing is written and `LogFull()` is returned instead.
+//! - `abort`, or dropping the guard without committing, throws all changes away, on disk as well
+//!   as in the in-memory state of the file system (allocation bitmap, group descriptors, access
+//!   times, ...).
+//!
+//! Only file systems that were created with a log and are mounted in `JournalMode::Journal`
+//! support transactions. In the other modes the contents of files bypass the log, so an abort
+//! could not undo them.
+
+use std::ops::{Deref, DerefMut};
+
+use crate::b_inode_support::Snapshot;
+use crate::c_dirs_support::FileSystemC;
+use crate::filesystem_errors::FileSystemError;
+use crate::options::JournalMode;
+
+#[derive(Debug)]
+/// A running transaction on a file system, see the module documentation
+pub struct Transaction<'a> {
+    /// The file system the transaction runs on
+    fs: &'a mut FileSystemC,
+    /// State to go back to on abort, `None` once the transaction has ended
+    snapshot: Option<Snapshot>,
+}
+
+impl FileSystemC {
+    /// Starts a transaction; the file system can only be used through it until it ends
+    /// Errors if the file system has no log, is not mounted in `JournalMode::Journal`, or if an
+    /// operation is running already
+    pub fn begin(&mut self) -> Result<Transaction<'_>, FileSystemError> {
+        match self.fs.log.as_ref() {
+            Some(log) if !log.is_active() && log.mode() == JournalMode::Journal => (),
+            _ => return Err(FileSystemError::TransactionError()),
+        }
+        let snapshot = self.fs.snapshot();
+        self.begin_op();
+        Ok(Transaction {
+            fs: self,
+            snapshot: Some(snapshot),
+        })
+    }
+}
+
+impl Transaction<'_> {
+    /// Commits all changes made in this transaction, or none of them if they did not fit in the log
+    /// or could not be committed
+    pub fn commit(mut self) -> Result<(), FileSystemError> {
+        // The snapshot is only given up once the transaction is committed, a failing commit still
+        // rolls back
+        let snapshot = self.snapshot.take();
+        self.fs.fs.finish_op(snapshot, Ok(()))
+    }
+
+    /// Throws all changes made in this transaction away
+    pub fn abort(mut self) {
+        self.rollback();
+    }
+
+    /// Discards the running transaction of the log and restores the in-memory state
+    fn rollback(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.fs.fs.abort_op(snapshot);
+        }
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = FileSystemC;
+
+    fn deref(&self) -> &FileSystemC {
+        self.fs
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut FileSystemC {
+        self.fs
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.rollback();
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::c_dirs_support::FileSystemC;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::{BitmapWritePolicy, JournalMode, MkfsOptions, MountOptions};
+    use cplfs_api::fs::{
+        BlockSupport, DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport,
+    };
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock, ROOT_INUM};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 44;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn with_log() -> MkfsOptions {
+        MkfsOptions {
+            log_blocks: 8,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-transaction-".to_string() + name), "img")
+    }
+
+    /// Creates a file called `name` in the root directory, holding `len` bytes
+    fn create_file(fs: &mut FileSystemC, name: &str, len: u64) -> Result<u64, FileSystemError> {
+        let inum = fs.i_alloc(FType::TFile)?;
+        let mut ino = fs.i_get(inum)?;
+        let data = Buffer::new(vec![3; len as usize].into_boxed_slice());
+        fs.i_write(&mut ino, &data, 0, len)?;
+        let mut root = fs.i_get(ROOT_INUM)?;
+        fs.dirlink(&mut root, name, inum)?;
+        fs.i_put(&root)?;
+        Ok(inum)
+    }
+
+    #[test]
+    fn no_log() {
+        let path = disk_prep_path("no_log");
+        let mut my_fs = FileSystemC::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        assert!(matches!(
+            my_fs.begin(),
+            Err(FileSystemError::TransactionError())
+        ));
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn commit_and_abort() {
+        let path = disk_prep_path("commit_and_abort");
+        let mut my_fs =
+            FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log()).unwrap();
+        let mut tx = my_fs.begin().unwrap();
+        let inum = create_file(&mut tx, "kept", 2 * BLOCK_SIZE).unwrap();
+        tx.commit().unwrap();
+        let stat = my_fs.statfs().unwrap();
+
+        //Aborted and dropped transactions leave no trace
+        let tx = my_fs.begin().unwrap();
+        tx.abort();
+        let mut tx = my_fs.begin().unwrap();
+        create_file(&mut tx, "aborted", BLOCK_SIZE).unwrap();
+        tx.abort();
+        {
+            let mut tx = my_fs.begin().unwrap();
+            create_file(&mut tx, "dropped", BLOCK_SIZE).unwrap();
+        }
+
+        //A transaction that does not fit in the log is not committed at all
+        let mut tx = my_fs.begin().unwrap();
+        assert!(create_file(&mut tx, "big", 4 * BLOCK_SIZE).is_ok());
+        assert!(matches!(
+            create_file(&mut tx, "bigger", 4 * BLOCK_SIZE),
+            Err(FileSystemError::LogFull())
+        ));
+        assert!(matches!(tx.commit(), Err(FileSystemError::LogFull())));
+
+        assert_eq!(my_fs.statfs().unwrap(), stat);
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemC::mountfs(dev).unwrap();
+        let root = my_fs.i_get(ROOT_INUM).unwrap();
+        assert_eq!(my_fs.dirlookup(&root, "kept").unwrap().0.inum, inum);
+        for name in &["aborted", "dropped", "big", "bigger"] {
+            assert!(my_fs.dirlookup(&root, name).is_err());
+        }
+        assert_eq!(my_fs.i_get(inum).unwrap().get_size(), 2 * BLOCK_SIZE);
+        assert_eq!(my_fs.statfs().unwrap(), stat);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn commit_overflow() {
+        let path = disk_prep_path("commit_overflow");
+        let dev = FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log())
+            .unwrap()
+            .unmountfs();
+        let options = MountOptions {
+            bitmap_policy: BitmapWritePolicy::WriteBack,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        let stat = my_fs.statfs().unwrap();
+
+        //The zeroed blocks fill the log, so the held back bitmap block no longer fits at commit
+        let mut tx = my_fs.begin().unwrap();
+        for i in 0..7 {
+            assert_eq!(tx.b_alloc().unwrap(), i);
+        }
+        assert!(matches!(tx.commit(), Err(FileSystemError::LogFull())));
+
+        //Nothing of the transaction is left behind, in memory or in the log
+        assert_eq!(my_fs.statfs().unwrap(), stat);
+        assert_eq!(my_fs.b_alloc().unwrap(), 0);
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        assert_eq!(my_fs.b_alloc().unwrap(), 1);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    /// Aborts a transaction on a file system mounted in `mode`, which has to leave no trace
+    fn abort_in_mode(mode: JournalMode) {
+        let path = disk_prep_path(&format!("abort-{:?}", mode));
+        let dev = FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log())
+            .unwrap()
+            .unmountfs();
+        let options = MountOptions {
+            journal_mode: mode,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        let stat = my_fs.statfs().unwrap();
+
+        //File contents bypass the log in the other modes, so they cannot be undone
+        if mode != JournalMode::Journal {
+            assert!(matches!(
+                my_fs.begin(),
+                Err(FileSystemError::TransactionError())
+            ));
+        } else {
+            let mut tx = my_fs.begin().unwrap();
+            create_file(&mut tx, "aborted", 2 * BLOCK_SIZE).unwrap();
+            tx.abort();
+        }
+
+        assert_eq!(my_fs.statfs().unwrap(), stat);
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        let root = my_fs.i_get(ROOT_INUM).unwrap();
+        assert!(my_fs.dirlookup(&root, "aborted").is_err());
+        assert_eq!(my_fs.statfs().unwrap(), stat);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn abort_writeback() {
+        abort_in_mode(JournalMode::Writeback);
+    }
+
+    #[test]
+    fn abort_ordered() {
+        abort_in_mode(JournalMode::Ordered);
+    }
+
+    #[test]
+    fn abort_journal() {
+        abort_in_mode(JournalMode::Journal);
+    }
+}
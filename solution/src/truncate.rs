@@ -0,0 +1,239 @@
+//! Truncation to an arbitrary length
+//!
+//! `InodeSupport::i_trunc` can only cut a file to zero; `i_truncate_to` sets the size of a file
+//! to any length:
+//! - shrinking frees the blocks past the new end, together with the indirect blocks or extent
+//!   tree nodes that become empty, and zeroes the rest of the last block, so that a later grow
+//!   reads zeros there,
+//! - growing leaves a hole on a `FileSystemF` (see `crate::sparse`). The inodes of the API cannot
+//!   have holes, so a `FileSystemC` writes zeros instead, allocating zeroed blocks. It cannot
+//!   grow past its `DIRECT_POINTERS` blocks.
+//!
+//! Inline files (see `crate::inline_data`) stay inline as long as they fit, and shrinking
+//! moves contents back into the inode if the file system is mounted with `reinline`.
+
+use cplfs_api::fs::{BlockSupport, InodeRWSupport, InodeSupport};
+use cplfs_api::types::{Buffer, Inode, InodeLike, DIRECT_POINTERS};
+
+use crate::c_dirs_support::FileSystemC;
+use crate::f_indirect_inodes::{max_file_size, FileSystemF, IndirectInode};
+use crate::filesystem_errors::FileSystemError;
+use crate::inode_ext::{pack_inline, unpack_inline};
+
+impl FileSystemC {
+    /// Sets the size of `inode` to `new_len`, see the module documentation
+    pub fn i_truncate_to(
+        &mut self,
+        inode: &mut Inode,
+        new_len: u64,
+    ) -> Result<(), FileSystemError> {
+        let size = inode.get_size();
+        let block_size = self.fs.superblock.block_size;
+        if new_len > DIRECT_POINTERS * block_size {
+            return Err(FileSystemError::AllocationError());
+        }
+        if new_len >= size {
+            let zeros = Buffer::new_zero(block_size);
+            return self.in_op(|fs| {
+                let mut pos = size;
+                while pos < new_len {
+                    let len = (block_size - pos % block_size).min(new_len - pos);
+                    fs.i_write(inode, &zeros, pos, len)?;
+                    pos += len;
+                }
+                Ok(())
+            });
+        }
+        self.in_op(|fs| {
+            if fs.fs.is_inline(inode.inum)? {
+                let data = unpack_inline(&inode.disk_node.direct_blocks, new_len);
+                inode.disk_node.direct_blocks = pack_inline(&data);
+            } else {
+                let keep = ((new_len + block_size - 1) / block_size) as usize;
+                for j in keep..DIRECT_POINTERS as usize {
+                    let block_no = inode.disk_node.direct_blocks[j];
+                    if block_no != 0 {
+                        fs.fs.b_free(block_no - fs.fs.superblock.datastart)?;
+                        inode.disk_node.direct_blocks[j] = 0;
+                    }
+                }
+                if new_len % block_size != 0 {
+                    let block_no = inode.disk_node.direct_blocks[keep - 1];
+                    fs.fs
+                        .zero_data(block_no, new_len % block_size, block_size)?;
+                }
+            }
+            inode.disk_node.size = new_len;
+            fs.fs.reinline(inode)?;
+            fs.i_put(inode)?;
+            fs.fs.touch_modified(inode.inum)
+        })
+    }
+}
+
+impl FileSystemF {
+    /// Sets the size of `inode` to `new_len`, see the module documentation
+    pub fn i_truncate_to(
+        &mut self,
+        inode: &mut IndirectInode,
+        new_len: u64,
+    ) -> Result<(), FileSystemError> {
+        if new_len > max_file_size(&self.fs.superblock) {
+            return Err(FileSystemError::AllocationError());
+        }
+        self.in_op(|fs| {
+            if new_len < inode.get_size() {
+                let block_size = fs.fs.superblock.block_size;
+                fs.free_blocks_from(inode, (new_len + block_size - 1) / block_size)?;
+                if new_len % block_size != 0 {
+                    let lbn = new_len / block_size;
+                    let block_no = fs.bmap(inode, lbn)?;
+                    let block_no = fs.unshare(inode, lbn, block_no)?;
+                    if block_no != 0 {
+                        fs.fs
+                            .zero_data(block_no, new_len % block_size, block_size)?;
+                    }
+                }
+            }
+            inode.disk_node.size = new_len;
+            fs.i_put(inode)
+        })
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::c_dirs_support::FileSystemC;
+    use crate::options::{MkfsOptions, MountOptions};
+    use crate::test_support::{contents, format_options, mkfs_f, BLOCK_SIZE};
+    use cplfs_api::fs::{FileSysSupport, InodeRWSupport, InodeSupport};
+    use cplfs_api::types::{Buffer, FType, InodeLike, SuperBlock, DIRECT_POINTERS};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static SUPERBLOCK_C: SuperBlock = SuperBlock {
+        block_size: 1000,
+        nblocks: 41,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn inline() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            inline_data: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-truncate-".to_string() + name), "img")
+    }
+
+    /// Shrinks and grows a file that reaches into the double-indirect tree
+    fn truncate_f(extents: bool) {
+        let path = disk_prep_path(&format!("f-{}", extents));
+        let mut my_fs = mkfs_f(&path, &format_options(extents));
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+
+        //12 direct blocks, 37 through the indirect block, and 2 in the double-indirect tree
+        let size = (DIRECT_POINTERS + 37 + 2) * BLOCK_SIZE;
+        let data: Vec<u8> = (0..size).map(|i| (i % 251 + 1) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, size).unwrap();
+        let meta = if extents { 0 } else { 3 };
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 51 - meta);
+
+        //Cutting into the indirect range frees the double-indirect tree
+        let len = (DIRECT_POINTERS + 5) * BLOCK_SIZE + 120;
+        my_fs.i_truncate_to(&mut ino, len).unwrap();
+        let meta = if extents { 0 } else { 1 };
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 18 - meta);
+        assert_eq!(ino.disk_node.double_indirect_block, 0);
+        assert_eq!(contents(&my_fs, &ino), &data[..len as usize]);
+
+        //Growing leaves a hole, and the old tail reads as zeros
+        my_fs.i_truncate_to(&mut ino, size).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 18 - meta);
+        let read = contents(&my_fs, &ino);
+        assert_eq!(&read[..len as usize], &data[..len as usize]);
+        assert!(read[len as usize..].iter().all(|&b| b == 0));
+
+        //Cutting into the direct blocks frees the indirect block as well
+        my_fs.i_truncate_to(&mut ino, 700).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70 - 3);
+        assert_eq!(ino.disk_node.indirect_block, 0);
+        let mut ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(contents(&my_fs, &ino), &data[..700]);
+        my_fs.i_truncate_to(&mut ino, 0).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 70);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn truncate_pointers() {
+        truncate_f(false);
+    }
+
+    #[test]
+    fn truncate_extents() {
+        truncate_f(true);
+    }
+
+    #[test]
+    fn truncate_c() {
+        let path = disk_prep_path("c");
+        let my_fs = FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_C, &inline()).unwrap();
+        let reinline = MountOptions {
+            reinline: true,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemC::mountfs_with_options(my_fs.unmountfs(), &reinline).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let data: Vec<u8> = (0..2500).map(|i| (i % 251 + 1) as u8).collect();
+        let buf = Buffer::new(data.clone().into_boxed_slice());
+        my_fs.i_write(&mut ino, &buf, 0, 2500).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 32 - 3);
+
+        //Shrinking frees the blocks past the end and zeroes the tail of the last one
+        my_fs.i_truncate_to(&mut ino, 1200).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 32 - 2);
+        my_fs.i_truncate_to(&mut ino, 1500).unwrap();
+        let read = contents(&my_fs, &ino);
+        assert_eq!(&read[..1200], &data[..1200]);
+        assert!(read[1200..].iter().all(|&b| b == 0));
+
+        //Growing allocates zeroed blocks, as the inodes of the API cannot have holes
+        my_fs.i_truncate_to(&mut ino, 3500).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 32 - 4);
+        assert!(contents(&my_fs, &ino)[1200..].iter().all(|&b| b == 0));
+        assert!(my_fs.i_truncate_to(&mut ino, u64::MAX).is_err());
+        assert!(my_fs
+            .i_truncate_to(&mut ino, DIRECT_POINTERS * 1000 + 1)
+            .is_err());
+        assert_eq!(ino.get_size(), 3500);
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 32 - 4);
+
+        //Shrinking far enough moves the contents back into the inode
+        my_fs.i_truncate_to(&mut ino, 50).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, 32);
+        assert!(my_fs.fs.is_inline(inum).unwrap());
+        my_fs.i_truncate_to(&mut ino, 80).unwrap();
+        my_fs.i_truncate_to(&mut ino, 20).unwrap();
+        assert!(my_fs.fs.is_inline(inum).unwrap());
+        let ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(contents(&my_fs, &ino), &data[..20]);
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}
@@ -0,0 +1,541 @@
+//! Write-ahead log, in the style of xv6
+//!
+//! A file system can be created with a log region (see `MkfsOptions::log_blocks`), which lies
+//! right behind the data region. Its first block is the log header, the others hold copies of
+//! the blocks that are being written.
+//!
+//! File system operations that write several blocks (e.g. `dirlink`, `i_write`) are wrapped in
+//! `begin_op`/`end_op` on the file system. Inside an operation, `b_put` only stores the block in
+//! the current `Log` transaction, and `b_get` sees those pending blocks. When the outermost
+//! operation ends, the transaction is committed:
+//! 1. all pending blocks are written to the log region
+//! 2. the header, listing the home locations of the blocks, is written; this is the commit point
+//! 3. the blocks are installed at their home locations
+//! 4. the header is cleared
+//!
+//! Unlike in xv6, an operation that fails (e.g. because it ran out of space or wrote more blocks
+//! than fit in the log) is not committed: `in_op` throws its transaction away and restores the
+//! in-memory allocation state, so nothing of it reaches the disk.
+//!
+//! When mounting, `recover` installs the blocks of a committed transaction whose header is still
+//! there. A crash before step 2 leaves an empty header, so the incomplete transaction is lost as a
+//! whole. All log writes go straight to the device, bypassing the buffer cache, so they happen
+//! in the order above.
+//!
+//! Whether the contents of files go through the log as well depends on the `JournalMode` of the
+//! mount. In `Ordered` mode they are written to their home location right before step 1.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use cplfs_api::controller::Device;
+use cplfs_api::types::{Block, SuperBlock};
+use serde::{Deserialize, Serialize};
+
+use crate::filesystem_errors::FileSystemError;
+use crate::helpers::{read_block, write_block};
+use crate::options::JournalMode;
+use crate::superblock_ext::{SuperBlockExt, FEATURE_LOG};
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+/// Contents of the first block of the log region
+pub struct LogHeader {
+    /// Home locations of the blocks in the log, in the order in which they are stored.
+    /// Empty if there is no committed transaction.
+    pub blocks: Vec<u64>,
+}
+
+/// Number of blocks a single transaction can hold, for a log region of `nlog` blocks
+pub fn log_capacity(sb: &SuperBlock, nlog: u64) -> u64 {
+    // The header holds the length of the list and one block number per block
+    let fits_in_header = (sb.block_size / 8).saturating_sub(1);
+    nlog.saturating_sub(1).min(fits_in_header)
+}
+
+/// Checks whether the log region described by the extension fits on the disk
+pub fn log_valid(sb: &SuperBlock, ext: &SuperBlockExt) -> bool {
+    ext.logstart >= sb.datastart + sb.ndatablocks
+        && ext.logstart + ext.nlog <= sb.nblocks
+        && log_capacity(sb, ext.nlog) > 0
+}
+
+/// Installs the committed transaction that is still in the log, if any, and clears the log
+/// Returns the number of installed blocks
+pub fn recover(dev: &mut Device, ext: &SuperBlockExt) -> Result<u64, FileSystemError> {
+    if !ext.has_feature(FEATURE_LOG) {
+        return Ok(0);
+    }
+    let header = read_block(dev, ext.logstart)?.deserialize_from::<LogHeader>(0)?;
+    for (k, &home) in header.blocks.iter().enumerate() {
+        let logged = read_block(dev, ext.logstart + 1 + k as u64)?;
+        write_block(dev, &Block::new(home, logged.contents_as_ref().into()))?;
+    }
+    clear_log(dev, ext)?;
+    Ok(header.blocks.len() as u64)
+}
+
+/// Writes an empty log header, so the log holds no transaction
+pub fn clear_log(dev: &mut Device, ext: &SuperBlockExt) -> Result<(), FileSystemError> {
+    write_header(dev, ext.logstart, &LogHeader::default())
+}
+
+/// Writes the log header
+fn write_header(
+    dev: &mut Device,
+    logstart: u64,
+    header: &LogHeader,
+) -> Result<(), FileSystemError> {
+    write_block(dev, &header_block(logstart, dev.block_size, header)?)
+}
+
+/// The log header block holding `header`
+fn header_block(
+    logstart: u64,
+    block_size: u64,
+    header: &LogHeader,
+) -> Result<Block, FileSystemError> {
+    let mut block = Block::new_zero(logstart, block_size);
+    block.serialize_into(header, 0)?;
+    Ok(block)
+}
+
+#[derive(Debug, Clone, Default)]
+/// The log region of a mounted file system, together with the running transaction
+pub struct Log {
+    /// First block of the log region, the header
+    start: u64,
+    /// Maximum number of blocks in a transaction
+    capacity: u64,
+    /// Which blocks go through the log
+    mode: JournalMode,
+    /// Number of operations that have begun but not ended yet
+    outstanding: usize,
+    /// Blocks written by the running transaction, by home location
+    pending: BTreeMap<u64, Box<[u8]>>,
+    /// File contents written by the running transaction outside of the log, that have to reach
+    /// the disk before it commits (`JournalMode::Ordered` only)
+    ordered: BTreeSet<u64>,
+    /// Whether a write of the running transaction was refused because the log was full
+    overflowed: bool,
+    /// Number of device writes left before a simulated crash, see `crash_after`
+    crash_budget: Option<u64>,
+    /// Whether the simulated crash dropped a device write
+    crashed: bool,
+}
+
+impl Log {
+    /// Creates the log described by the superblock extension
+    pub fn new(sb: &SuperBlock, ext: &SuperBlockExt, mode: JournalMode) -> Log {
+        Log {
+            start: ext.logstart,
+            capacity: log_capacity(sb, ext.nlog),
+            mode,
+            outstanding: 0,
+            pending: BTreeMap::new(),
+            ordered: BTreeSet::new(),
+            overflowed: false,
+            crash_budget: None,
+            crashed: false,
+        }
+    }
+
+    /// Maximum number of blocks in a transaction
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Which blocks go through the log
+    pub fn mode(&self) -> JournalMode {
+        self.mode
+    }
+
+    /// Whether an operation is running
+    pub fn is_active(&self) -> bool {
+        self.outstanding > 0
+    }
+
+    /// Whether exactly one operation is running, so ending it commits the transaction
+    pub fn is_outermost(&self) -> bool {
+        self.outstanding == 1
+    }
+
+    /// Starts an operation, which joins the running transaction if there is one
+    pub fn begin_op(&mut self) {
+        self.outstanding += 1;
+    }
+
+    /// Ends an operation; returns true if it was the outermost one, so the transaction has to be
+    /// committed now
+    pub fn end_op(&mut self) -> bool {
+        debug_assert!(self.outstanding > 0);
+        self.outstanding = self.outstanding.saturating_sub(1);
+        self.outstanding == 0
+    }
+
+    /// The pending version of block `i`, if the running transaction wrote it
+    pub fn read(&self, i: u64) -> Option<Block> {
+        self.pending.get(&i).map(|data| Block::new(i, data.clone()))
+    }
+
+    /// Adds block `b` to the running transaction
+    /// Fails if the transaction would no longer fit in the log
+    pub fn write(&mut self, b: &Block) -> Result<(), FileSystemError> {
+        if !self.pending.contains_key(&b.block_no) && self.pending.len() as u64 >= self.capacity {
+            self.overflowed = true;
+            return Err(FileSystemError::LogFull());
+        }
+        self.pending.insert(b.block_no, b.contents_as_ref().into());
+        Ok(())
+    }
+
+    /// Registers that the running transaction wrote file contents to block `i` outside of the log
+    /// An earlier version of the block in the transaction (e.g. the zeroes written when it was
+    /// allocated) is dropped, so it does not overwrite the contents when installed.
+    /// In `JournalMode::Ordered`, the block is written to disk before the transaction commits.
+    pub fn write_unlogged(&mut self, i: u64) {
+        self.pending.remove(&i);
+        if self.mode == JournalMode::Ordered {
+            self.ordered.insert(i);
+        }
+    }
+
+    /// Takes the blocks that have to be written to disk before the running transaction commits
+    pub fn take_ordered(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.ordered).into_iter().collect()
+    }
+
+    /// Whether the running transaction lost a write because the log was full
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// Ends all running operations and throws their transaction away
+    pub fn abort(&mut self) {
+        self.outstanding = 0;
+        self.pending.clear();
+        self.ordered.clear();
+        self.overflowed = false;
+    }
+
+    /// Simulates a crash for testing: after `writes` more device writes by `commit`, all further
+    /// writes are silently dropped, as if the machine lost power. `None` turns this off again.
+    pub fn crash_after(&mut self, writes: Option<u64>) {
+        self.crash_budget = writes;
+        self.crashed = false;
+    }
+
+    /// Whether a simulated crash dropped device writes, see `crash_after`
+    pub fn crashed(&self) -> bool {
+        self.crashed
+    }
+
+    /// Commits the running transaction to `dev` and returns the blocks that were installed
+    /// The blocks in `ordered` are written to their home location first.
+    pub fn commit(
+        &mut self,
+        dev: &mut Device,
+        ordered: &[Block],
+    ) -> Result<Vec<Block>, FileSystemError> {
+        self.overflowed = false;
+        for b in ordered {
+            self.device_write(dev, b)?;
+        }
+        let pending = std::mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(vec![]);
+        }
+        let blocks: Vec<Block> = pending
+            .into_iter()
+            .map(|(i, data)| Block::new(i, data))
+            .collect();
+
+        for (k, b) in blocks.iter().enumerate() {
+            let logged = Block::new(self.start + 1 + k as u64, b.contents_as_ref().into());
+            self.device_write(dev, &logged)?;
+        }
+        let header = LogHeader {
+            blocks: blocks.iter().map(|b| b.block_no).collect(),
+        };
+        let block_size = dev.block_size;
+        self.device_write(dev, &header_block(self.start, block_size, &header)?)?;
+        for b in &blocks {
+            self.device_write(dev, b)?;
+        }
+        let empty = header_block(self.start, block_size, &LogHeader::default())?;
+        self.device_write(dev, &empty)?;
+        Ok(blocks)
+    }
+
+    /// Writes `b` to `dev`, unless a simulated crash happened
+    fn device_write(&mut self, dev: &mut Device, b: &Block) -> Result<(), FileSystemError> {
+        match self.crash_budget.as_mut() {
+            Some(0) => {
+                self.crashed = true;
+                Ok(())
+            }
+            Some(budget) => {
+                *budget -= 1;
+                write_block(dev, b)
+            }
+            None => write_block(dev, b),
+        }
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use super::LogHeader;
+    use crate::c_dirs_support::FileSystemC;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::{JournalMode, MkfsOptions, MountOptions};
+    use cplfs_api::controller::Device;
+    use cplfs_api::fs::{
+        BlockSupport, DirectorySupport, FileSysSupport, InodeRWSupport, InodeSupport,
+    };
+    use cplfs_api::types::{Block, Buffer, FType, InodeLike, SuperBlock, ROOT_INUM};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 44;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    static LOGSTART: u64 = 36;
+    fn with_log() -> MkfsOptions {
+        MkfsOptions {
+            log_blocks: 8,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-wal-".to_string() + name), "img")
+    }
+
+    fn header(blocks: Vec<u64>) -> Block {
+        let mut block = Block::new_zero(LOGSTART, BLOCK_SIZE);
+        block.serialize_into(&LogHeader { blocks }, 0).unwrap();
+        block
+    }
+
+    #[test]
+    fn commit() {
+        let path = disk_prep_path("commit");
+        let too_long = MkfsOptions {
+            log_blocks: 9,
+            extended_inodes: false,
+            extents: false,
+            inline_data: false,
+            ..with_log()
+        };
+        assert!(FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &too_long).is_err());
+
+        let mut my_fs =
+            FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log()).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut root = my_fs.i_get(ROOT_INUM).unwrap();
+        //Nested operations are committed together, when the outer one ends
+        my_fs
+            .in_op(|fs| {
+                fs.dirlink(&mut root, "file", inum)?;
+                fs.i_put(&root)
+            })
+            .unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let data = Buffer::new(vec![5; 2 * BLOCK_SIZE as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &data, 0, data.len()).unwrap();
+
+        my_fs.begin_op();
+        let other = my_fs.i_alloc(FType::TFile).unwrap();
+        assert_eq!(my_fs.i_get(other).unwrap().get_ft(), FType::TFile);
+        my_fs.end_op().unwrap();
+
+        //A transaction that does not fit in the log is refused
+        let mut other = my_fs.i_get(other).unwrap();
+        let big = Buffer::new(vec![6; 8 * BLOCK_SIZE as usize].into_boxed_slice());
+        assert!(matches!(
+            my_fs.i_write(&mut other, &big, 0, big.len()),
+            Err(FileSystemError::LogFull())
+        ));
+
+        let dev = my_fs.unmountfs();
+        assert_eq!(dev.read_block(LOGSTART).unwrap(), header(vec![]));
+        let my_fs = FileSystemC::mountfs(dev).unwrap();
+        let root = my_fs.i_get(ROOT_INUM).unwrap();
+        assert_eq!(my_fs.dirlookup(&root, "file").unwrap().0.inum, inum);
+        let mut buf = Buffer::new_zero(BLOCK_SIZE);
+        let ino = my_fs.i_get(inum).unwrap();
+        my_fs
+            .i_read(&ino, &mut buf, BLOCK_SIZE, BLOCK_SIZE)
+            .unwrap();
+        assert_eq!(
+            buf.contents_as_ref(),
+            &data.contents_as_ref()[..BLOCK_SIZE as usize]
+        );
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn log_full_rolls_back() {
+        let path = disk_prep_path("log-full");
+        let mut my_fs =
+            FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log()).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let data = Buffer::new(vec![5; BLOCK_SIZE as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &data, 0, data.len()).unwrap();
+        let free_blocks = my_fs.statfs().unwrap().free_blocks;
+
+        //A single write that fills up the log leaves nothing behind, in memory or on disk
+        let big = Buffer::new(vec![6; 8 * BLOCK_SIZE as usize].into_boxed_slice());
+        assert!(matches!(
+            my_fs.i_write(&mut ino, &big, 0, big.len()),
+            Err(FileSystemError::LogFull())
+        ));
+        assert_eq!(ino.get_size(), BLOCK_SIZE);
+        assert_eq!(ino.get_block(1), 0);
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free_blocks);
+
+        //So does a run of blocks that is too long to zero in one operation
+        assert!(matches!(
+            my_fs.b_alloc_run(3),
+            Err(FileSystemError::LogFull())
+        ));
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free_blocks);
+
+        let dev = my_fs.unmountfs();
+        let my_fs = FileSystemC::mountfs(dev).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+        assert_eq!(ino.get_size(), BLOCK_SIZE);
+        assert_eq!(ino.get_block(1), 0);
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free_blocks);
+        let mut buf = Buffer::new_zero(BLOCK_SIZE);
+        my_fs.i_read(&ino, &mut buf, 0, BLOCK_SIZE).unwrap();
+        assert_eq!(buf.contents_as_ref(), data.contents_as_ref());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn recovery() {
+        let path = disk_prep_path("recovery");
+        let my_fs = FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log()).unwrap();
+        let mut dev = my_fs.unmountfs();
+        let home = SUPERBLOCK_GOOD.datastart + 5;
+
+        //Crash before the commit point: the logged blocks are ignored
+        dev.write_block(&utils::n_block(LOGSTART + 1, BLOCK_SIZE, 7))
+            .unwrap();
+        let my_fs = FileSystemC::mountfs(dev).unwrap();
+        assert_eq!(
+            my_fs.b_get(home).unwrap(),
+            utils::zero_block(home, BLOCK_SIZE)
+        );
+        let mut dev = my_fs.unmountfs();
+
+        //Crash after the commit point: the logged blocks are installed when mounting
+        dev.write_block(&utils::n_block(LOGSTART + 1, BLOCK_SIZE, 7))
+            .unwrap();
+        dev.write_block(&utils::n_block(LOGSTART + 2, BLOCK_SIZE, 8))
+            .unwrap();
+        dev.write_block(&header(vec![home, home + 1])).unwrap();
+        let my_fs = FileSystemC::mountfs(dev).unwrap();
+        assert_eq!(
+            my_fs.b_get(home).unwrap(),
+            utils::n_block(home, BLOCK_SIZE, 7)
+        );
+        assert_eq!(
+            my_fs.b_get(home + 1).unwrap(),
+            utils::n_block(home + 1, BLOCK_SIZE, 8)
+        );
+
+        let dev = my_fs.unmountfs();
+        assert_eq!(dev.read_block(LOGSTART).unwrap(), header(vec![]));
+        utils::disk_destruct(dev);
+    }
+
+    /// Stops using `fs` without writing anything that is still pending, like a power loss would
+    fn crash(mut fs: FileSystemC) -> Device {
+        fs.fs.device.take().unwrap()
+    }
+
+    /// What is left of a 2-block `i_write` to an empty file, in the given mode, when the machine
+    /// crashes after `writes` device writes of the commit
+    /// Returns the size and the first byte of the file after recovery, and whether the crash
+    /// actually interrupted the commit
+    fn write_and_crash(mode: JournalMode, writes: u64) -> (u64, u8, bool) {
+        let path = disk_prep_path(&format!("crash-{:?}", mode));
+        let dev = FileSystemC::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &with_log())
+            .unwrap()
+            .unmountfs();
+        let options = MountOptions {
+            journal_mode: mode,
+            ..MountOptions::default()
+        };
+        let mut my_fs = FileSystemC::mountfs_with_options(dev, &options).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let free = my_fs.statfs().unwrap().free_blocks;
+
+        my_fs.fs.log.as_mut().unwrap().crash_after(Some(writes));
+        let mut ino = my_fs.i_get(inum).unwrap();
+        let data = Buffer::new(vec![9; 2 * BLOCK_SIZE as usize].into_boxed_slice());
+        my_fs.i_write(&mut ino, &data, 0, data.len()).unwrap();
+        let crashed = my_fs.fs.log.as_ref().unwrap().crashed();
+
+        let my_fs = FileSystemC::mountfs(crash(my_fs)).unwrap();
+        let ino = my_fs.i_get(inum).unwrap();
+        let size = ino.get_size();
+        //The metadata is updated atomically
+        let expected_free = if size == 0 { free } else { free - 2 };
+        assert!(
+            size == 0 || size == 2 * BLOCK_SIZE,
+            "torn write: size {}",
+            size
+        );
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, expected_free);
+        let block = my_fs.b_get(ino.disk_node.direct_blocks[1]).unwrap();
+        let first = block.contents_as_ref()[0];
+        utils::disk_destruct(my_fs.unmountfs());
+        (size, first, crashed)
+    }
+
+    #[test]
+    fn journal_modes() {
+        for &mode in &[
+            JournalMode::Writeback,
+            JournalMode::Ordered,
+            JournalMode::Journal,
+        ] {
+            let mut outcomes = vec![];
+            for writes in 0.. {
+                let (size, first, crashed) = write_and_crash(mode, writes);
+                if size > 0 {
+                    //Writeback mode may expose blocks whose contents never made it to disk
+                    match mode {
+                        JournalMode::Writeback => assert_eq!(first, 0),
+                        _ => assert_eq!(first, 9),
+                    }
+                }
+                outcomes.push(size);
+                if !crashed {
+                    break;
+                }
+            }
+            //Early crashes lose the write, late ones keep it
+            assert_eq!(outcomes.first(), Some(&0));
+            assert_eq!(outcomes.last(), Some(&(2 * BLOCK_SIZE)));
+        }
+    }
+}
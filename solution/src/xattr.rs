@@ -0,0 +1,308 @@
+//! Extended attributes
+//!
+//! Every inode can carry a set of name/value pairs besides its contents. The attributes of an
+//! inode are stored together in a single data block, the attribute block, that is referenced
+//! from the inode extension record (see `crate::inode_ext`). The block is allocated when the first
+//! attribute is set, freed when the last one is removed, and freed along with the inode by
+//! `i_free`; truncating the contents leaves the attributes alone.
+//!
+//! Attribute names consist of a namespace prefix and a non-empty name:
+//! - `user.` attributes can be read by users that may read the inode, and changed by users that
+//!   may write it,
+//! - `system.` attributes can be read by everyone, but only changed by the root user.
+//!
+//! Names are at most `XATTR_NAME_MAX` bytes long, values at most `XATTR_SIZE_MAX` bytes, and all
+//! attributes of an inode together have to fit in one block.
+
+use cplfs_api::fs::BlockSupport;
+use cplfs_api::types::Block;
+use serde::{Deserialize, Serialize};
+
+use crate::b_inode_support::FileSystem;
+use crate::filesystem_errors::FileSystemError;
+use crate::permissions::{MAY_READ, MAY_WRITE};
+
+/// Namespace of attributes that belong to the users of the file system
+pub const XATTR_USER_PREFIX: &str = "user.";
+
+/// Namespace of attributes that only the root user may change
+pub const XATTR_SYSTEM_PREFIX: &str = "system.";
+
+/// Maximum length of an attribute name, prefix included, in bytes
+pub const XATTR_NAME_MAX: usize = 255;
+
+/// Maximum length of an attribute value, in bytes
+pub const XATTR_SIZE_MAX: usize = 512;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+/// Contents of an attribute block: the attributes of one inode, sorted by name
+struct XattrBlock {
+    /// Name/value pairs
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+/// Whether `name` is in the `system.` namespace, or an error if it is not a valid name at all
+fn is_system_name(name: &str) -> Result<bool, FileSystemError> {
+    let (system, rest) = if let Some(rest) = name.strip_prefix(XATTR_SYSTEM_PREFIX) {
+        (true, rest)
+    } else if let Some(rest) = name.strip_prefix(XATTR_USER_PREFIX) {
+        (false, rest)
+    } else {
+        return Err(FileSystemError::InvalidXattrName());
+    };
+    if rest.is_empty() || name.len() > XATTR_NAME_MAX {
+        return Err(FileSystemError::InvalidXattrName());
+    }
+    Ok(system)
+}
+
+impl FileSystem {
+    /// Checks whether the current user may read (`write == false`) or change attribute `name` of
+    /// inode `inum`
+    fn check_xattr_access(
+        &self,
+        inum: u64,
+        name: &str,
+        write: bool,
+    ) -> Result<(), FileSystemError> {
+        if is_system_name(name)? {
+            if write && !self.creds.is_root() {
+                return Err(FileSystemError::PermissionDenied());
+            }
+            return Ok(());
+        }
+        self.check_access(inum, if write { MAY_WRITE } else { MAY_READ })
+    }
+
+    /// Reads the attributes of inode `inum`, and the number of their block (0 if there is none)
+    fn xattr_block_get(&self, inum: u64) -> Result<(XattrBlock, u64), FileSystemError> {
+        if !self.has_inode_ext() {
+            return Err(FileSystemError::FeatureNotSupported());
+        }
+        let block_no = self.i_ext_get(inum)?.xattr_block;
+        if block_no == 0 {
+            return Ok((XattrBlock::default(), 0));
+        }
+        let attrs = self.b_get(block_no)?.deserialize_from::<XattrBlock>(0)?;
+        Ok((attrs, block_no))
+    }
+
+    /// Writes the attributes of inode `inum`, which were stored in block `block_no` (or 0), into
+    /// an attribute block, allocating or freeing it as needed
+    fn xattr_block_put(
+        &mut self,
+        inum: u64,
+        attrs: &XattrBlock,
+        block_no: u64,
+    ) -> Result<(), FileSystemError> {
+        let size = bincode::serialized_size(attrs).map_err(|_| FileSystemError::XattrNoSpace())?;
+        if size > self.superblock.block_size {
+            return Err(FileSystemError::XattrNoSpace());
+        }
+        self.in_op(|fs| {
+            let mut ext = fs.i_ext_get(inum)?;
+            if attrs.entries.is_empty() {
+                fs.b_free(block_no - fs.superblock.datastart)?;
+                ext.xattr_block = 0;
+            } else {
+                if block_no == 0 {
+                    ext.xattr_block = fs.b_alloc_near(inum)? + fs.superblock.datastart;
+                }
+                let mut block = Block::new_zero(ext.xattr_block, fs.superblock.block_size);
+                block.serialize_into(attrs, 0)?;
+                fs.b_put(&block)?;
+            }
+            fs.i_ext_put(inum, &ext)?;
+            fs.touch_changed(inum)
+        })
+    }
+
+    /// Sets attribute `name` of inode `inum` to `value`, creating it if it does not exist yet
+    pub fn setxattr(&mut self, inum: u64, name: &str, value: &[u8]) -> Result<(), FileSystemError> {
+        self.check_xattr_access(inum, name, true)?;
+        if value.len() > XATTR_SIZE_MAX {
+            return Err(FileSystemError::XattrNoSpace());
+        }
+        let (mut attrs, block_no) = self.xattr_block_get(inum)?;
+        match attrs
+            .entries
+            .binary_search_by(|(n, _)| n.as_str().cmp(name))
+        {
+            Ok(i) => attrs.entries[i].1 = value.to_vec(),
+            Err(i) => attrs.entries.insert(i, (name.to_string(), value.to_vec())),
+        }
+        self.xattr_block_put(inum, &attrs, block_no)
+    }
+
+    /// Returns the value of attribute `name` of inode `inum`
+    pub fn getxattr(&self, inum: u64, name: &str) -> Result<Vec<u8>, FileSystemError> {
+        self.check_xattr_access(inum, name, false)?;
+        let (attrs, _) = self.xattr_block_get(inum)?;
+        attrs
+            .entries
+            .into_iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value)
+            .ok_or_else(FileSystemError::XattrNotFound)
+    }
+
+    /// Returns the names of all attributes of inode `inum`, sorted
+    pub fn listxattr(&self, inum: u64) -> Result<Vec<String>, FileSystemError> {
+        let (attrs, _) = self.xattr_block_get(inum)?;
+        Ok(attrs.entries.into_iter().map(|(name, _)| name).collect())
+    }
+
+    /// Removes attribute `name` of inode `inum`
+    pub fn removexattr(&mut self, inum: u64, name: &str) -> Result<(), FileSystemError> {
+        self.check_xattr_access(inum, name, true)?;
+        let (mut attrs, block_no) = self.xattr_block_get(inum)?;
+        let i = attrs
+            .entries
+            .iter()
+            .position(|(n, _)| n == name)
+            .ok_or_else(FileSystemError::XattrNotFound)?;
+        attrs.entries.remove(i);
+        self.xattr_block_put(inum, &attrs, block_no)
+    }
+
+    /// Frees the attribute block of inode `inum`, if it has one
+    /// Called when the inode itself is freed.
+    pub fn free_xattrs(&mut self, inum: u64) -> Result<(), FileSystemError> {
+        let mut ext = self.i_ext_get(inum)?;
+        if ext.xattr_block == 0 {
+            return Ok(());
+        }
+        self.b_free(ext.xattr_block - self.superblock.datastart)?;
+        ext.xattr_block = 0;
+        self.i_ext_put(inum, &ext)
+    }
+}
+
+#[cfg(test)]
+#[path = "../../api/fs-tests"]
+mod test_with_utils {
+    use crate::b_inode_support::FileSystem;
+    use crate::filesystem_errors::FileSystemError;
+    use crate::options::MkfsOptions;
+    use crate::permissions::Credentials;
+    use cplfs_api::fs::{FileSysSupport, InodeSupport};
+    use cplfs_api::types::{FType, SuperBlock};
+    use std::path::PathBuf;
+
+    #[path = "utils.rs"]
+    mod utils;
+
+    static BLOCK_SIZE: u64 = 1000;
+    static NBLOCKS: u64 = 41;
+    static SUPERBLOCK_GOOD: SuperBlock = SuperBlock {
+        block_size: BLOCK_SIZE,
+        nblocks: NBLOCKS,
+        ninodes: 16,
+        inodestart: 1,
+        ndatablocks: 32,
+        bmapstart: 3,
+        datastart: 4,
+    };
+    fn extended() -> MkfsOptions {
+        MkfsOptions {
+            extended_inodes: true,
+            ..MkfsOptions::default()
+        }
+    }
+
+    fn disk_prep_path(name: &str) -> PathBuf {
+        utils::disk_prep_path(&("fs-images-xattr-".to_string() + name), "img")
+    }
+
+    #[test]
+    fn no_inode_ext() {
+        let path = disk_prep_path("no_inode_ext");
+        let mut my_fs = FileSystem::mkfs(&path, &SUPERBLOCK_GOOD).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        assert!(matches!(
+            my_fs.setxattr(inum, "user.origin", b"web"),
+            Err(FileSystemError::FeatureNotSupported())
+        ));
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+
+    #[test]
+    fn xattrs() {
+        let path = disk_prep_path("xattrs");
+        let mut my_fs =
+            FileSystem::mkfs_with_options(&path, &SUPERBLOCK_GOOD, &extended()).unwrap();
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        let free = my_fs.statfs().unwrap().free_blocks;
+        assert!(my_fs.listxattr(inum).unwrap().is_empty());
+
+        //The first attribute allocates the attribute block
+        my_fs.setxattr(inum, "user.origin", b"web").unwrap();
+        my_fs
+            .setxattr(inum, "user.mime_type", b"text/plain")
+            .unwrap();
+        my_fs.setxattr(inum, "system.checksum", &[1, 2, 3]).unwrap();
+        my_fs.setxattr(inum, "user.origin", b"mail").unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free - 1);
+        assert_eq!(
+            my_fs.listxattr(inum).unwrap(),
+            vec!["system.checksum", "user.mime_type", "user.origin"]
+        );
+        assert_eq!(my_fs.getxattr(inum, "user.origin").unwrap(), b"mail");
+        assert!(matches!(
+            my_fs.getxattr(inum, "user.missing"),
+            Err(FileSystemError::XattrNotFound())
+        ));
+
+        //Names need a namespace, and names, values and blocks are limited in size
+        for name in &["origin", "user.", "trusted.origin", &"user.x".repeat(50)] {
+            assert!(matches!(
+                my_fs.setxattr(inum, name, b""),
+                Err(FileSystemError::InvalidXattrName())
+            ));
+        }
+        assert!(matches!(
+            my_fs.setxattr(inum, "user.big", &[0; 513]),
+            Err(FileSystemError::XattrNoSpace())
+        ));
+        my_fs.setxattr(inum, "user.big", &[0; 512]).unwrap();
+        assert!(matches!(
+            my_fs.setxattr(inum, "user.bigger", &[0; 512]),
+            Err(FileSystemError::XattrNoSpace())
+        ));
+
+        //Only root may change system attributes
+        my_fs.creds = Credentials::new(1000, 100);
+        assert!(matches!(
+            my_fs.removexattr(inum, "system.checksum"),
+            Err(FileSystemError::PermissionDenied())
+        ));
+        assert!(matches!(
+            my_fs.removexattr(inum, "user.origin"),
+            Err(FileSystemError::PermissionDenied())
+        ));
+        assert_eq!(my_fs.getxattr(inum, "system.checksum").unwrap(), [1, 2, 3]);
+        my_fs.creds = Credentials::root();
+
+        //Truncation keeps the attributes, removing the last one frees the block
+        let mut ino = my_fs.i_get(inum).unwrap();
+        my_fs.i_trunc(&mut ino).unwrap();
+        let dev = my_fs.unmountfs();
+        let mut my_fs = FileSystem::mountfs(dev).unwrap();
+        assert_eq!(my_fs.listxattr(inum).unwrap().len(), 4);
+        for name in my_fs.listxattr(inum).unwrap() {
+            my_fs.removexattr(inum, &name).unwrap();
+        }
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free);
+
+        //Freeing the inode frees its attribute block
+        my_fs.setxattr(inum, "user.origin", b"web").unwrap();
+        my_fs.i_free(inum).unwrap();
+        assert_eq!(my_fs.statfs().unwrap().free_blocks, free);
+        let inum = my_fs.i_alloc(FType::TFile).unwrap();
+        assert!(my_fs.listxattr(inum).unwrap().is_empty());
+
+        let dev = my_fs.unmountfs();
+        utils::disk_destruct(dev);
+    }
+}